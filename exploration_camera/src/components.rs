@@ -8,6 +8,9 @@ use super::*;
 pub struct ExplorationControlTag {
     pub pitch: f32,
     pub yaw: f32,
+    /// Only rotate the camera, e.g. when another system moves it
+    #[serde(default)]
+    pub fixed_position: bool,
 }
 
 impl Component for ExplorationControlTag {
@@ -117,6 +117,9 @@ where
 
         if let Some(global_horizontal) = Unit::try_new(Vector3::new(x, 0.0, z), 1.0e-6) {
             for (transform, tag) in (&mut transform, &tag).join() {
+                if tag.fixed_position {
+                    continue;
+                }
                 let a = tag.yaw;
                 let local_horizontal = Matrix3::new(
                     a.cos(),
@@ -137,7 +140,10 @@ where
             }
         }
         if let Some(vertical_direction) = Unit::try_new(Vector3::new(0.0, y, 0.0), 1.0e-6) {
-            for (transform, _) in (&mut transform, &tag).join() {
+            for (transform, tag) in (&mut transform, &tag).join() {
+                if tag.fixed_position {
+                    continue;
+                }
                 transform.move_along_global(vertical_direction, time.delta_seconds() * self.speed);
             }
         }
@@ -0,0 +1,263 @@
+//! Texture atlas containing the textures of every block.
+//!
+//! All block textures are packed into a single texture at startup, so that every chunk can be
+//! drawn with the same texture. Textures must be square and all have the same size. Every tile
+//! is surrounded by `PADDING` pixels copied from its border, otherwise the smaller mipmap levels
+//! would blend the borders of neighbouring tiles together.
+use image::RgbaImage;
+use std::{collections::HashMap, error::Error, fmt, path::Path};
+
+use crate::{
+    registry::Registry,
+    world::{Block, BlockId},
+};
+
+/// Number of pixels copied around every tile.
+pub const PADDING: u32 = 8;
+
+/// Error while building the texture atlas
+#[derive(Debug)]
+pub enum AtlasError {
+    /// A texture couldn't be loaded
+    Image {
+        name: String,
+        error: image::ImageError,
+    },
+    /// A texture isn't square or doesn't have the size of the other textures
+    Size {
+        name: String,
+        expected: u32,
+        found: (u32, u32),
+    },
+}
+
+impl fmt::Display for AtlasError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AtlasError::Image { name, error } => {
+                write!(f, "Failed to load texture {}: {}", name, error)
+            }
+            AtlasError::Size {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Texture {} is {}x{}, expected {}x{}",
+                name, found.0, found.1, expected, expected
+            ),
+        }
+    }
+}
+
+impl Error for AtlasError {}
+
+/// The pixels of the atlas, in RGBA8. Rows are stored from bottom to top, so that the texture
+/// coordinate v = 0 is the bottom of the images.
+pub struct AtlasImage {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+/// The location of every block texture in the atlas
+#[derive(Clone)]
+pub struct TextureAtlas {
+    /// Layout of the tiles, see `tile_grid`
+    grid: [f32; 4],
+    /// Tile of every face of every block, indexed by block id
+    block_tiles: Vec<[u16; 6]>,
+}
+
+impl TextureAtlas {
+    /// Build the atlas from the `<name>.png` files in `directory`
+    pub fn load(
+        block_registry: &Registry<Block>,
+        directory: &Path,
+    ) -> Result<(Self, AtlasImage), AtlasError> {
+        Self::build(block_registry, |name| {
+            image::open(directory.join(format!("{}.png", name)))
+                .map(|image| image.to_rgba())
+                .map_err(|error| AtlasError::Image {
+                    name: name.to_owned(),
+                    error,
+                })
+        })
+    }
+
+    /// Build the atlas, loading every texture used by the blocks of the registry with
+    /// `load_texture`
+    pub fn build<F>(
+        block_registry: &Registry<Block>,
+        mut load_texture: F,
+    ) -> Result<(Self, AtlasImage), AtlasError>
+    where
+        F: FnMut(&str) -> Result<RgbaImage, AtlasError>,
+    {
+        let mut tile_ids: HashMap<&str, u16> = HashMap::new();
+        let mut textures = Vec::new();
+        let mut block_tiles = Vec::new();
+        for (_, block) in block_registry.iter() {
+            let mut faces = [0; 6];
+            if let Some(block_textures) = &block.textures {
+                for (face, name) in faces.iter_mut().zip(block_textures.0.iter()) {
+                    *face = match tile_ids.get(name.as_str()) {
+                        Some(tile) => *tile,
+                        None => {
+                            let tile = textures.len() as u16;
+                            textures.push((name.as_str(), load_texture(name)?));
+                            tile_ids.insert(name, tile);
+                            tile
+                        }
+                    };
+                }
+            }
+            block_tiles.push(faces);
+        }
+
+        let tile_size = textures
+            .first()
+            .map(|(_, texture)| texture.width())
+            .unwrap_or(1);
+        for (name, texture) in textures.iter() {
+            if texture.dimensions() != (tile_size, tile_size) {
+                return Err(AtlasError::Size {
+                    name: (*name).to_owned(),
+                    expected: tile_size,
+                    found: texture.dimensions(),
+                });
+            }
+        }
+
+        // Tiles are laid out in a square grid, and the size of the atlas is a power of two
+        let cell_size = tile_size + 2 * PADDING;
+        let columns = (textures.len() as f32).sqrt().ceil().max(1.0) as u32;
+        let size = (columns * cell_size).next_power_of_two();
+        let mut atlas = AtlasImage {
+            width: size,
+            height: size,
+            pixels: vec![0; (size * size * 4) as usize],
+        };
+        for (i, (_, texture)) in textures.iter().enumerate() {
+            let x = (i as u32 % columns) * cell_size;
+            let y = (i as u32 / columns) * cell_size;
+            atlas.copy_padded(texture, x, y);
+        }
+        let pixel = 1.0 / size as f32;
+        let grid = [
+            columns as f32,
+            cell_size as f32 * pixel,
+            PADDING as f32 * pixel,
+            tile_size as f32 * pixel,
+        ];
+
+        Ok((Self { grid, block_tiles }, atlas))
+    }
+
+    /// Get the tile of a face of a block. `face` uses the order of `mesh::cube::NORMALS`.
+    pub fn block_tile(&self, block_id: BlockId, face: usize) -> u16 {
+        self.block_tiles[block_id.index()][face]
+    }
+
+    /// The layout of the tiles, from which the chunk shaders find the texture coordinates of a
+    /// tile: the number of columns of the grid, the size of a cell, the offset of the tile in its
+    /// cell and the size of the tile, in texture coordinates. Tile `i` is in column
+    /// `i % columns` and row `i / columns`, counted from the bottom left.
+    pub fn tile_grid(&self) -> [f32; 4] {
+        self.grid
+    }
+}
+
+impl AtlasImage {
+    /// Copy `texture` in the cell whose bottom left corner is (x, y), flipping it vertically
+    /// and repeating its border pixels in the padding
+    fn copy_padded(&mut self, texture: &RgbaImage, x: u32, y: u32) {
+        let tile_size = texture.width() as i64;
+        let cell_size = tile_size as u32 + 2 * PADDING;
+        for i in 0..cell_size {
+            for j in 0..cell_size {
+                let clamp = |c: u32| (c as i64 - PADDING as i64).max(0).min(tile_size - 1) as u32;
+                let source_x = clamp(i);
+                // Image rows go from top to bottom
+                let source_y = tile_size as u32 - 1 - clamp(j);
+                let pixel = texture.get_pixel(source_x, source_y);
+                let index = (((y + j) * self.width + x + i) * 4) as usize;
+                self.pixels[index..index + 4].copy_from_slice(&pixel.data);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TextureAtlas, PADDING};
+    use crate::{
+        registry::Registry,
+        world::{Block, BlockTextures},
+    };
+    use image::{Rgba, RgbaImage};
+
+    /// The atlas coordinates of `tex_coord` in a tile, as computed by the chunk shaders
+    fn tex_coord(atlas: &TextureAtlas, tile: u16, tex_coord: [f32; 2]) -> [f32; 2] {
+        let [columns, cell_size, offset, tile_size] = atlas.tile_grid();
+        let column = (tile as f32 % columns).floor();
+        let row = (tile as f32 / columns).floor();
+        [
+            column * cell_size + offset + tex_coord[0].fract() * tile_size,
+            row * cell_size + offset + tex_coord[1].fract() * tile_size,
+        ]
+    }
+
+    #[test]
+    fn tiles_and_padding() {
+        let mut block_registry = Registry::new();
+        let air = Block {
+            transparent: true,
+            ..Block::default()
+        };
+        block_registry.register("default:air", air).unwrap();
+        let grass = Block {
+            textures: Some(BlockTextures::top_side_bottom("top", "side", "bottom")),
+            ..Block::default()
+        };
+        let grass_id = block_registry.register("default:grass", grass).unwrap();
+        // Every texture is a single color, except for its top left pixel
+        let colors = [("side", 20), ("top", 10), ("bottom", 30)];
+        let (atlas, image) = TextureAtlas::build(&block_registry, |name| {
+            let color = colors.iter().find(|(n, _)| *n == name).unwrap().1;
+            let mut texture = RgbaImage::from_pixel(4, 4, Rgba([color, 0, 0, 255]));
+            texture.put_pixel(0, 0, Rgba([color + 1, 0, 0, 255]));
+            Ok(texture)
+        })
+        .unwrap();
+
+        // Tiles are numbered in the order the textures are first used
+        let tiles: Vec<u16> = (0..6)
+            .map(|face| atlas.block_tile(grass_id, face))
+            .collect();
+        assert_eq!(tiles, vec![0, 0, 1, 2, 0, 0]);
+        let cell_size = 4 + 2 * PADDING;
+        assert_eq!(image.width, (2 * cell_size).next_power_of_two());
+
+        let pixel = |x: u32, y: u32| image.pixels[((y * image.width + x) * 4) as usize];
+        for (tile, (_, color)) in colors.iter().enumerate() {
+            let tile = tile as u16;
+            let corner = tex_coord(&atlas, tile, [0.0, 0.0]);
+            let size = image.width as f32;
+            let (x, y) = ((corner[0] * size) as u32, (corner[1] * size) as u32);
+            // Bottom left of the tile, and its padding
+            assert_eq!(pixel(x, y), *color);
+            assert_eq!(pixel(x - PADDING, y - PADDING), *color);
+            // The top left pixel of the image is at the top of the tile, just below v = 1
+            let top = tex_coord(&atlas, tile, [0.0, 0.999]);
+            let (x, y) = ((top[0] * size) as u32, (top[1] * size) as u32);
+            assert_eq!(pixel(x, y), color + 1);
+            assert_eq!(pixel(x - 1, y + PADDING), color + 1);
+            // The tile is repeated in the next block
+            assert_eq!(
+                tex_coord(&atlas, tile, [1.25, 2.5]),
+                tex_coord(&atlas, tile, [0.25, 0.5])
+            );
+        }
+    }
+}
@@ -0,0 +1,380 @@
+//! Block definitions, loaded from RON files.
+//!
+//! The blocks of the game are defined in `resources/blocks.ron`. Additional blocks can be added by
+//! putting more files in the `resources/content` directory, they are loaded in alphabetical order.
+//! Every file declares a namespace and a list of blocks:
+//!
+//! ```ron
+//! (
+//!     namespace: "default",
+//!     blocks: [
+//!         (name: "air", solid: false, transparent: true),
+//!         (name: "dirt", hardness: 0.5, textures: Some(All("dirt"))),
+//!     ],
+//! )
+//! ```
+//!
+//! Blocks are registered as `namespace:name`. Properties that are left out take the value of
+//! `Block::default()`.
+//!
+//! Files can also declare where the world generator places ore blocks, see `OreConfig`. Block
+//! names are written with their namespace, so ores can replace the blocks of other files:
+//!
+//! ```ron
+//! (
+//!     namespace: "default",
+//!     blocks: [(name: "coal_ore", hardness: 1.5, textures: Some(All("coal_ore")))],
+//!     ores: [
+//!         (
+//!             block: "default:coal_ore",
+//!             vein_size: 12,
+//!             attempts: 20,
+//!             min_height: -128,
+//!             max_height: 64,
+//!             replaceable: ["default:stone"],
+//!         ),
+//!     ],
+//! )
+//! ```
+use serde::Deserialize;
+use std::{
+    error::Error,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    registry::Registry,
+    world::{Block, BlockTextures, MAX_LIGHT_EMISSION},
+    worldgen::OreConfig,
+};
+
+/// Error while loading the block definitions
+#[derive(Debug)]
+pub enum BlockError {
+    /// A file couldn't be read
+    Io { path: PathBuf, error: io::Error },
+    /// A file is not valid RON or doesn't have the expected structure
+    Parse {
+        path: PathBuf,
+        error: ron::de::Error,
+    },
+    /// A block definition is invalid
+    Invalid {
+        path: PathBuf,
+        block: String,
+        reason: String,
+    },
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockError::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            BlockError::Parse { path, error } => write!(f, "{}: {}", path.display(), error),
+            BlockError::Invalid {
+                path,
+                block,
+                reason,
+            } => write!(f, "{}: block {}: {}", path.display(), block, reason),
+        }
+    }
+}
+
+impl Error for BlockError {}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BlockFile {
+    namespace: String,
+    blocks: Vec<BlockDefinition>,
+    #[serde(default)]
+    ores: Vec<OreConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct BlockDefinition {
+    name: String,
+    solid: bool,
+    transparent: bool,
+    light_emission: u8,
+    hardness: f32,
+    textures: Option<TexturesDefinition>,
+    tinted: bool,
+}
+
+impl Default for BlockDefinition {
+    fn default() -> Self {
+        let block = Block::default();
+        Self {
+            name: String::new(),
+            solid: block.solid,
+            transparent: block.transparent,
+            light_emission: block.light_emission,
+            hardness: block.hardness,
+            textures: None,
+            tinted: block.tinted,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+enum TexturesDefinition {
+    All(String),
+    TopSideBottom {
+        top: String,
+        side: String,
+        bottom: String,
+    },
+    /// One texture per face, in the order of `mesh::cube::NORMALS`
+    Faces([String; 6]),
+}
+
+impl From<TexturesDefinition> for BlockTextures {
+    fn from(textures: TexturesDefinition) -> Self {
+        match textures {
+            TexturesDefinition::All(name) => BlockTextures::all(name),
+            TexturesDefinition::TopSideBottom { top, side, bottom } => {
+                BlockTextures::top_side_bottom(top, side, bottom)
+            }
+            TexturesDefinition::Faces(faces) => BlockTextures(faces),
+        }
+    }
+}
+
+/// Load `blocks.ron` and the files of the `content` directory from the `resources` directory.
+/// Returns the blocks and the ore rules of every file.
+pub fn load_blocks(resources: &Path) -> Result<(Registry<Block>, Vec<OreConfig>), BlockError> {
+    let mut paths = vec![resources.join("blocks.ron")];
+    let content = resources.join("content");
+    if content.is_dir() {
+        let io_error = |error| BlockError::Io {
+            path: content.clone(),
+            error,
+        };
+        let mut content_paths = Vec::new();
+        for entry in fs::read_dir(&content).map_err(io_error)? {
+            let path = entry.map_err(io_error)?.path();
+            if path
+                .extension()
+                .map_or(false, |extension| extension == "ron")
+            {
+                content_paths.push(path);
+            }
+        }
+        content_paths.sort();
+        paths.extend(content_paths);
+    }
+
+    let mut block_registry = Registry::new();
+    let mut ores = Vec::new();
+    for path in paths {
+        let source = fs::read_to_string(&path).map_err(|error| BlockError::Io {
+            path: path.clone(),
+            error,
+        })?;
+        ores.extend(register_blocks(&mut block_registry, &source, &path)?);
+    }
+    Ok((block_registry, ores))
+}
+
+/// Register the blocks defined by the RON `source`, and return its ore rules. `path` is only
+/// used in error messages.
+pub fn register_blocks(
+    block_registry: &mut Registry<Block>,
+    source: &str,
+    path: &Path,
+) -> Result<Vec<OreConfig>, BlockError> {
+    let file: BlockFile = ron::de::from_str(source).map_err(|error| BlockError::Parse {
+        path: path.to_owned(),
+        error,
+    })?;
+    let invalid = |block: &str, reason: String| BlockError::Invalid {
+        path: path.to_owned(),
+        block: block.to_owned(),
+        reason,
+    };
+
+    if !is_valid_name(&file.namespace) {
+        return Err(invalid(
+            "",
+            format!(
+                "invalid namespace \"{}\", only lowercase letters, digits and underscores are allowed",
+                file.namespace
+            ),
+        ));
+    }
+    for definition in file.blocks {
+        let name = format!("{}:{}", file.namespace, definition.name);
+        if !is_valid_name(&definition.name) {
+            return Err(invalid(
+                &name,
+                "invalid name, only lowercase letters, digits and underscores are allowed".into(),
+            ));
+        }
+        if definition.light_emission > MAX_LIGHT_EMISSION {
+            return Err(invalid(
+                &name,
+                format!(
+                    "light_emission is {}, the maximum is {}",
+                    definition.light_emission, MAX_LIGHT_EMISSION
+                ),
+            ));
+        }
+        if !definition.hardness.is_finite() || definition.hardness < 0.0 {
+            return Err(invalid(
+                &name,
+                format!("hardness must not be negative, not {}", definition.hardness),
+            ));
+        }
+        // The faces next to an opaque block are hidden, so it would leave a hole in the world
+        if !definition.transparent && definition.textures.is_none() {
+            return Err(invalid(&name, "opaque blocks must have textures".into()));
+        }
+
+        let block = Block {
+            solid: definition.solid,
+            transparent: definition.transparent,
+            light_emission: definition.light_emission,
+            hardness: definition.hardness,
+            textures: definition.textures.map(BlockTextures::from),
+            tinted: definition.tinted,
+        };
+        block_registry
+            .register(name.as_str(), block)
+            .map_err(|_| invalid(&name, "defined more than once".into()))?;
+    }
+
+    // The ore blocks can be defined by files loaded later, they are checked by the generator
+    for ore in file.ores.iter() {
+        if ore.vein_size == 0 {
+            return Err(invalid(&ore.block, "ore veins can't be empty".into()));
+        }
+        if ore.min_height > ore.max_height {
+            return Err(invalid(
+                &ore.block,
+                format!(
+                    "ore min_height {} is above max_height {}",
+                    ore.min_height, ore.max_height
+                ),
+            ));
+        }
+    }
+    Ok(file.ores)
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{register_blocks, BlockError};
+    use crate::registry::Registry;
+    use std::path::Path;
+
+    #[test]
+    fn load_and_validate() {
+        let mut block_registry = Registry::new();
+        let source = r#"
+            (
+                namespace: "test",
+                blocks: [
+                    (name: "air", solid: false, transparent: true),
+                    (
+                        name: "grass",
+                        hardness: 0.6,
+                        textures: Some(TopSideBottom(top: "top", side: "side", bottom: "dirt")),
+                    ),
+                    (name: "lamp", light_emission: 15, textures: Some(All("lamp"))),
+                ],
+                ores: [
+                    (
+                        block: "test:lamp",
+                        vein_size: 4,
+                        attempts: 2,
+                        min_height: -10,
+                        max_height: 10,
+                        replaceable: ["test:grass"],
+                    ),
+                ],
+            )
+        "#;
+        let ores = register_blocks(&mut block_registry, source, Path::new("test.ron")).unwrap();
+        assert_eq!(ores.len(), 1);
+        assert_eq!(ores[0].block, "test:lamp");
+        assert_eq!(ores[0].replaceable, vec!["test:grass".to_owned()]);
+        let grass_id = block_registry.get_item_id("test:grass").unwrap();
+        let grass = &block_registry[grass_id];
+        assert!(grass.solid && !grass.transparent);
+        assert_eq!(grass.hardness, 0.6);
+        assert_eq!(grass.textures.as_ref().unwrap().0[2], "top");
+        let lamp_id = block_registry.get_item_id("test:lamp").unwrap();
+        assert_eq!(block_registry[lamp_id].light_emission, 15);
+
+        // Blocks can't be defined twice, even by another file
+        let duplicate = r#"(namespace: "test", blocks: [(name: "air", transparent: true)])"#;
+        match register_blocks(&mut block_registry, duplicate, Path::new("duplicate.ron")) {
+            Err(BlockError::Invalid { block, reason, .. }) => {
+                assert_eq!(block, "test:air");
+                assert_eq!(reason, "defined more than once");
+            }
+            _ => panic!("Blocks defined twice should be rejected"),
+        }
+
+        // Every definition is checked with an empty registry, so that it fails for its own reason
+        let invalid = [
+            (
+                r#"(namespace: "test", blocks: [(name: "glass")])"#,
+                "textures",
+            ),
+            (
+                r#"(namespace: "test", blocks: [(name: "Bad Name", transparent: true)])"#,
+                "invalid name",
+            ),
+            (
+                r#"(namespace: "Test", blocks: [(name: "air", transparent: true)])"#,
+                "invalid namespace",
+            ),
+            (
+                r#"(namespace: "test", blocks: [(name: "sun", light_emission: 16, transparent: true)])"#,
+                "light_emission",
+            ),
+            (
+                r#"(namespace: "test", blocks: [(name: "ice", hardness: -1.0, transparent: true)])"#,
+                "hardness",
+            ),
+            (
+                r#"(namespace: "test", blocks: [], ores: [(block: "test:lamp", vein_size: 0,
+                    attempts: 1, min_height: 0, max_height: 1, replaceable: [])])"#,
+                "empty",
+            ),
+            (
+                r#"(namespace: "test", blocks: [], ores: [(block: "test:lamp", vein_size: 1,
+                    attempts: 1, min_height: 2, max_height: 1, replaceable: [])])"#,
+                "min_height",
+            ),
+        ];
+        for (source, expected) in invalid.iter() {
+            let mut block_registry = Registry::new();
+            match register_blocks(&mut block_registry, source, Path::new("invalid.ron")) {
+                Err(BlockError::Invalid { reason, .. }) => {
+                    assert!(reason.contains(expected), "{}: {}", source, reason)
+                }
+                _ => panic!("{} should be invalid", source),
+            }
+        }
+        let unknown_field = r#"(namespace: "test", blocks: [(name: "x", colour: 3)])"#;
+        match register_blocks(&mut block_registry, unknown_field, Path::new("invalid.ron")) {
+            Err(error @ BlockError::Parse { .. }) => {
+                assert!(error.to_string().starts_with("invalid.ron: "))
+            }
+            _ => panic!("Unknown fields should be rejected"),
+        }
+    }
+}
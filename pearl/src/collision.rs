@@ -0,0 +1,347 @@
+//! Moving boxes through the world without going through the solid blocks.
+use amethyst::core::nalgebra::Vector3;
+
+use crate::world::BlockPos;
+
+/// Distance under which a box touches a block without overlapping it, to absorb rounding errors
+const EPSILON: f32 = 1e-3;
+
+/// An axis-aligned box, e.g. the body of the player
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Aabb {
+    pub min: Vector3<f32>,
+    pub max: Vector3<f32>,
+}
+
+impl Aabb {
+    pub fn new(min: Vector3<f32>, max: Vector3<f32>) -> Self {
+        Self { min, max }
+    }
+
+    pub fn translated(&self, offset: Vector3<f32>) -> Self {
+        Self::new(self.min + offset, self.max + offset)
+    }
+
+    /// Whether the box overlaps the block at `pos`, touching it is not enough
+    pub fn overlaps_block(&self, pos: &BlockPos) -> bool {
+        (0..3).all(|axis| {
+            let block_min = pos.0[axis] as f32;
+            self.min[axis] + EPSILON < block_min + 1.0 && self.max[axis] - EPSILON > block_min
+        })
+    }
+}
+
+/// Result of `move_body`
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Movement {
+    /// How far the box actually moved
+    pub offset: Vector3<f32>,
+    /// For every axis, whether the movement was stopped by a block
+    pub collided: [bool; 3],
+    /// The box was stopped by a block below it
+    pub on_ground: bool,
+}
+
+/// Move a box by `motion` until it touches solid blocks. The blocks for which `is_solid` returns
+/// true stop the box, except the ones it already overlaps so that it can get out of them.
+///
+/// The box moves along the vertical axis first, then along X and Z, so that it slides along the
+/// blocks that stop only one of the axes. When the box ends on the ground but is stopped
+/// horizontally, it also tries to climb on top of the blocks in its way, up to `step_height`
+/// blocks high.
+pub fn move_body<F>(
+    body: &Aabb,
+    motion: Vector3<f32>,
+    step_height: f32,
+    mut is_solid: F,
+) -> Movement
+where
+    F: FnMut(&BlockPos) -> bool,
+{
+    let movement = move_axes(body, motion, &mut is_solid);
+    let horizontal_collision = movement.collided[0] || movement.collided[2];
+    if step_height <= 0.0 || !movement.on_ground || !horizontal_collision {
+        return movement;
+    }
+
+    // Go up, forward, and back down on the blocks in the way
+    let up = clip_axis(body, 1, step_height, &mut is_solid).unwrap_or(step_height);
+    let raised = body.translated(Vector3::new(0.0, up, 0.0));
+    let horizontal_motion = Vector3::new(motion[0], 0.0, motion[2]);
+    let forward = move_axes(&raised, horizontal_motion, &mut is_solid);
+    let fall = motion[1] - up;
+    let down = clip_axis(&raised.translated(forward.offset), 1, fall, &mut is_solid);
+    let stepped = Movement {
+        offset: Vector3::new(
+            forward.offset[0],
+            up + down.unwrap_or(fall),
+            forward.offset[2],
+        ),
+        collided: [forward.collided[0], down.is_some(), forward.collided[2]],
+        on_ground: down.is_some(),
+    };
+    let horizontal_distance =
+        |movement: &Movement| movement.offset[0].powi(2) + movement.offset[2].powi(2);
+    if horizontal_distance(&stepped) > horizontal_distance(&movement) {
+        stepped
+    } else {
+        movement
+    }
+}
+
+/// Move along Y, then X, then Z
+fn move_axes<F>(body: &Aabb, motion: Vector3<f32>, is_solid: &mut F) -> Movement
+where
+    F: FnMut(&BlockPos) -> bool,
+{
+    let mut body = *body;
+    let mut offset = Vector3::zeros();
+    let mut collided = [false; 3];
+    for &axis in &[1, 0, 2] {
+        let clipped = clip_axis(&body, axis, motion[axis], is_solid);
+        collided[axis] = clipped.is_some();
+        offset[axis] = clipped.unwrap_or(motion[axis]);
+        body.min[axis] += offset[axis];
+        body.max[axis] += offset[axis];
+    }
+    Movement {
+        offset,
+        collided,
+        on_ground: collided[1] && motion[1] < 0.0,
+    }
+}
+
+/// Distance that the box can move along `axis` before it touches a solid block, or `None` if it
+/// can move all the way by `distance`. Negative distances go down the axis.
+fn clip_axis<F>(body: &Aabb, axis: usize, distance: f32, is_solid: &mut F) -> Option<f32>
+where
+    F: FnMut(&BlockPos) -> bool,
+{
+    let (a, b) = ((axis + 1) % 3, (axis + 2) % 3);
+    // Blocks overlapped by the box on the other axes. The ones it only touches are left out.
+    let range = |axis: usize| {
+        let min = (body.min[axis] + EPSILON).floor() as isize;
+        let max = (body.max[axis] - EPSILON).floor() as isize;
+        min..=max
+    };
+    let mut layer_is_solid = |layer: isize| {
+        for i in range(a) {
+            for j in range(b) {
+                let mut pos = BlockPos::new(0, 0, 0);
+                pos.0[axis] = layer;
+                pos.0[a] = i;
+                pos.0[b] = j;
+                if is_solid(&pos) {
+                    return true;
+                }
+            }
+        }
+        false
+    };
+
+    // Go through the layers of blocks in front of the box until one of them is solid
+    if distance > 0.0 {
+        let first = (body.max[axis] - EPSILON).floor() as isize + 1;
+        let last = (body.max[axis] + distance - EPSILON).floor() as isize;
+        (first..=last)
+            .find(|layer| layer_is_solid(*layer))
+            .map(|layer| layer as f32 - body.max[axis])
+    } else if distance < 0.0 {
+        let first = (body.min[axis] + EPSILON).floor() as isize - 1;
+        let last = (body.min[axis] + distance + EPSILON).floor() as isize;
+        (last..=first)
+            .rev()
+            .find(|layer| layer_is_solid(*layer))
+            .map(|layer| layer as f32 + 1.0 - body.min[axis])
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::{
+        test_blocks::{AIR, STONE},
+        BlockRegion, Chunk, ChunkPos, World,
+    };
+
+    /// Air chunks around the origin, with a stone floor at the height 0
+    fn world() -> World {
+        let mut world = World::new();
+        for x in -1..=0 {
+            for z in -1..=0 {
+                world.insert_chunk(ChunkPos(Vector3::new(x, 0, z)), Chunk::filled(AIR));
+            }
+        }
+        let floor = BlockRegion::new(BlockPos::new(-16, 0, -16), BlockPos::new(15, 0, 15));
+        world.fill(&floor, STONE);
+        world
+    }
+
+    /// The blocks of the chunks that are not loaded are solid
+    fn is_solid(world: &World) -> impl FnMut(&BlockPos) -> bool + '_ {
+        move |pos| world.get_block(pos).map_or(true, |block| block == STONE)
+    }
+
+    /// A body of 0.6 by 1.8 blocks, with its feet at `position`
+    fn body(x: f32, y: f32, z: f32) -> Aabb {
+        Aabb::new(
+            Vector3::new(x - 0.3, y, z - 0.3),
+            Vector3::new(x + 0.3, y + 1.8, z + 0.3),
+        )
+    }
+
+    fn assert_close(a: Vector3<f32>, b: Vector3<f32>) {
+        assert!((a - b).norm() < 1e-4, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn falls_and_lands() {
+        let world = world();
+        let movement = move_body(
+            &body(4.5, 3.0, 4.5),
+            Vector3::new(0.0, -5.0, 0.0),
+            0.6,
+            is_solid(&world),
+        );
+        assert_close(movement.offset, Vector3::new(0.0, -2.0, 0.0));
+        assert_eq!(movement.collided, [false, true, false]);
+        assert!(movement.on_ground);
+
+        // Standing on the ground
+        let movement = move_body(
+            &body(4.5, 1.0, 4.5),
+            Vector3::new(0.0, -0.1, 0.0),
+            0.6,
+            is_solid(&world),
+        );
+        assert_close(movement.offset, Vector3::zeros());
+        assert!(movement.on_ground);
+
+        // Jumping into a ceiling
+        let mut world = world;
+        world.set_block(&BlockPos::new(4, 4, 4), STONE);
+        let movement = move_body(
+            &body(4.5, 1.0, 4.5),
+            Vector3::new(0.0, 2.0, 0.0),
+            0.6,
+            is_solid(&world),
+        );
+        assert_close(movement.offset, Vector3::new(0.0, 1.2, 0.0));
+        assert_eq!(movement.collided, [false, true, false]);
+        assert!(!movement.on_ground);
+    }
+
+    #[test]
+    fn lands_on_chunk_edges() {
+        let mut world = world();
+        // A hole in the floor, on the x = 0 side of the border between two chunks
+        world.fill(
+            &BlockRegion::new(BlockPos::new(0, 0, 2), BlockPos::new(3, 0, 5)),
+            AIR,
+        );
+
+        // Straddling the border, above the blocks of one chunk only
+        let movement = move_body(
+            &body(0.0, 4.0, 3.0),
+            Vector3::new(0.0, -10.0, 0.0),
+            0.6,
+            is_solid(&world),
+        );
+        assert_close(movement.offset, Vector3::new(0.0, -3.0, 0.0));
+        assert!(movement.on_ground);
+
+        // Only touching the side of the blocks of the other chunk, so falling into the hole until
+        // the chunk below, which is not loaded
+        let movement = move_body(
+            &body(0.3, 4.0, 3.0),
+            Vector3::new(0.0, -10.0, 0.0),
+            0.6,
+            is_solid(&world),
+        );
+        assert_close(movement.offset, Vector3::new(0.0, -4.0, 0.0));
+        assert!(movement.on_ground);
+
+        // On the corner of four chunks
+        let movement = move_body(
+            &body(0.0, 4.0, 0.0),
+            Vector3::new(0.0, -10.0, 0.0),
+            0.6,
+            is_solid(&world),
+        );
+        assert_close(movement.offset, Vector3::new(0.0, -3.0, 0.0));
+    }
+
+    #[test]
+    fn slides_along_walls() {
+        let mut world = world();
+        let wall = BlockRegion::new(BlockPos::new(3, 1, -8), BlockPos::new(3, 2, 8));
+        world.fill(&wall, STONE);
+
+        // Diagonally into the wall, the body keeps moving along it
+        let movement = move_body(
+            &body(2.5, 1.0, 0.5),
+            Vector3::new(1.0, -0.1, 1.0),
+            0.6,
+            is_solid(&world),
+        );
+        assert_close(movement.offset, Vector3::new(0.2, 0.0, 1.0));
+        assert_eq!(movement.collided, [true, true, false]);
+        assert!(movement.on_ground);
+
+        // Along the wall while touching it
+        let movement = move_body(
+            &body(2.7, 1.0, 0.5),
+            Vector3::new(0.0, -0.1, -2.0),
+            0.6,
+            is_solid(&world),
+        );
+        assert_close(movement.offset, Vector3::new(0.0, 0.0, -2.0));
+        assert_eq!(movement.collided, [false, true, false]);
+
+        // Into the corner of the wall and another one
+        let corner = BlockRegion::new(BlockPos::new(-8, 1, 2), BlockPos::new(2, 2, 2));
+        world.fill(&corner, STONE);
+        let movement = move_body(
+            &body(2.5, 1.0, 1.5),
+            Vector3::new(1.0, -0.1, 1.0),
+            0.6,
+            is_solid(&world),
+        );
+        assert_close(movement.offset, Vector3::new(0.2, 0.0, 0.2));
+        assert_eq!(movement.collided, [true, true, true]);
+    }
+
+    #[test]
+    fn steps_on_blocks() {
+        let mut world = world();
+        world.set_block(&BlockPos::new(3, 1, 0), STONE);
+        let start = body(2.5, 1.0, 0.5);
+        let motion = Vector3::new(0.5, -0.1, 0.0);
+
+        // Too high
+        let movement = move_body(&start, motion, 0.6, is_solid(&world));
+        assert_close(movement.offset, Vector3::new(0.2, 0.0, 0.0));
+
+        let movement = move_body(&start, motion, 1.0, is_solid(&world));
+        assert_close(movement.offset, Vector3::new(0.5, 1.0, 0.0));
+        assert_eq!(movement.collided, [false, true, false]);
+        assert!(movement.on_ground);
+
+        // Not in the air
+        let movement = move_body(
+            &start.translated(Vector3::new(0.0, 0.5, 0.0)),
+            motion,
+            1.0,
+            is_solid(&world),
+        );
+        assert_close(movement.offset, Vector3::new(0.2, -0.1, 0.0));
+        assert!(!movement.on_ground);
+
+        // Not under a ceiling
+        world.set_block(&BlockPos::new(2, 3, 0), STONE);
+        let movement = move_body(&start, motion, 1.0, is_solid(&world));
+        assert_close(movement.offset, Vector3::new(0.2, 0.0, 0.0));
+    }
+}
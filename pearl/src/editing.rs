@@ -0,0 +1,148 @@
+//! Breaking and placing blocks with the mouse.
+use amethyst::{
+    core::{nalgebra::Vector3, Transform},
+    ecs::prelude::{Join, Read, ReadExpect, ReadStorage, System, WriteExpect},
+    input::InputHandler,
+};
+use exploration_camera::ExplorationControlTag;
+use log::info;
+use std::collections::HashSet;
+
+use crate::{
+    player::{MovementMode, Player},
+    raycast::raycast,
+    registry::Registry,
+    world::{floor_mod, Block, BlockId, World},
+};
+
+/// Input actions of the `BlockEditingSystem`, as named in `keybindings.ron`
+const BREAK_BLOCK: &str = "break_block";
+const PLACE_BLOCK: &str = "place_block";
+const SELECT_NEXT_BLOCK: &str = "select_next_block";
+const SELECT_PREVIOUS_BLOCK: &str = "select_previous_block";
+
+/// The system that breaks the block the camera looks at, or places the selected block against
+/// it. Every action is done once per key press.
+///
+/// The blocks that are drawn can be broken and selected. Broken blocks are replaced with
+/// `default:air`. The changes are picked up by the other systems from the `BlockChange` events
+/// of the `World`.
+pub struct BlockEditingSystem {
+    /// Maximum distance between the camera and the edited blocks
+    reach: f32,
+    /// The blocks that can be placed, filled on the first run once the registry exists
+    placeable: Vec<BlockId>,
+    /// Index of the selected block in `placeable`
+    selected: usize,
+    /// Actions that were down during the last run
+    down: HashSet<&'static str>,
+}
+
+impl BlockEditingSystem {
+    pub fn new(reach: f32) -> Self {
+        Self {
+            reach,
+            placeable: Vec::new(),
+            selected: 0,
+            down: HashSet::new(),
+        }
+    }
+
+    /// Cycle through the placeable blocks
+    fn select(&mut self, step: isize, block_registry: &Registry<Block>) {
+        let count = self.placeable.len() as isize;
+        if count == 0 {
+            return;
+        }
+        self.selected = floor_mod(self.selected as isize + step, count) as usize;
+        let name = block_registry
+            .get_item_name(self.placeable[self.selected])
+            .unwrap_or("?");
+        info!("Selected block: {}", name);
+    }
+}
+
+impl<'a> System<'a> for BlockEditingSystem {
+    type SystemData = (
+        ReadStorage<'a, ExplorationControlTag>,
+        ReadStorage<'a, Transform>,
+        ReadStorage<'a, Player>,
+        Read<'a, InputHandler<String, String>>,
+        WriteExpect<'a, World>,
+        ReadExpect<'a, Registry<Block>>,
+    );
+
+    fn run(
+        &mut self,
+        (tags, transforms, players, input, mut world, block_registry): Self::SystemData,
+    ) {
+        if self.placeable.is_empty() {
+            self.placeable = block_registry
+                .iter()
+                .filter(|(_, block)| block.textures.is_some())
+                .map(|(id, _)| id)
+                .collect();
+        }
+
+        // Only keep the actions that were just pressed
+        let mut pressed = Vec::new();
+        for action in &[
+            BREAK_BLOCK,
+            PLACE_BLOCK,
+            SELECT_NEXT_BLOCK,
+            SELECT_PREVIOUS_BLOCK,
+        ] {
+            if input.action_is_down(*action).unwrap_or(false) {
+                if self.down.insert(*action) {
+                    pressed.push(*action);
+                }
+            } else {
+                self.down.remove(action);
+            }
+        }
+
+        for action in pressed {
+            match action {
+                SELECT_NEXT_BLOCK => self.select(1, &block_registry),
+                SELECT_PREVIOUS_BLOCK => self.select(-1, &block_registry),
+                _ => {
+                    let (origin, direction) = match (&tags, &transforms).join().next() {
+                        // The camera looks towards -Z
+                        Some((_, transform)) => (
+                            *transform.translation(),
+                            transform.rotation() * -Vector3::z(),
+                        ),
+                        None => return,
+                    };
+                    let hit = raycast(&world, origin, direction, self.reach, |id| {
+                        block_registry[id].textures.is_some()
+                    });
+                    let hit = match hit {
+                        Some(hit) => hit,
+                        None => continue,
+                    };
+                    if action == BREAK_BLOCK {
+                        if let Ok(air) = block_registry.get_item_id("default:air") {
+                            world.set_block(&hit.pos, air);
+                        }
+                    } else if hit.adjacent != hit.pos {
+                        // Blocks are only placed against a face, not when the camera is inside
+                        // the hit block
+                        if let Some(&block_id) = self.placeable.get(self.selected) {
+                            // Solid blocks are not placed inside the walking player
+                            let in_player =
+                                (&players, &transforms).join().any(|(player, transform)| {
+                                    player.mode == MovementMode::Walking
+                                        && Player::body(transform.translation())
+                                            .overlaps_block(&hit.adjacent)
+                                });
+                            if !(block_registry[block_id].solid && in_player) {
+                                world.set_block(&hit.adjacent, block_id);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,343 @@
+//! Chunk generation and meshing on background worker threads.
+//!
+//! Jobs only work on data they own: the generator and the block registry are shared immutably,
+//! generation jobs take the chunks lent by the `GenerationPipeline` and give them back in their
+//! result, and meshing jobs receive copies of the chunk and of its neighbours. Finished jobs are
+//! collected on the main thread with `ChunkJobs::poll`.
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+use crate::{
+    atlas::TextureAtlas,
+    mesh::{
+        buffer::{ChunkMesh, VertexLayout},
+        chunk::{ChunkNeighbourhood, MeshingMode},
+    },
+    registry::Registry,
+    world::{Block, Chunk, ChunkPos},
+    worldgen::{BiomeMap, GenerationTask, WorldGenerator},
+};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// The kind of work done by a job
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
+pub enum JobKind {
+    Generate,
+    Mesh,
+}
+
+/// The output of a finished job
+pub enum JobResult {
+    /// The task, with the chunks it needs to give back to the pipeline
+    Generated(GenerationTask),
+    /// The mesh, and the version of the chunk it was built from
+    Meshed(ChunkMesh, u64),
+}
+
+struct FinishedJob {
+    id: u64,
+    pos: ChunkPos,
+    result: JobResult,
+}
+
+struct PendingJob {
+    id: u64,
+    cancelled: Arc<AtomicBool>,
+}
+
+/// A pool of worker threads running chunk jobs
+pub struct ChunkJobs {
+    job_sender: Option<Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+    result_sender: Sender<FinishedJob>,
+    result_receiver: Receiver<FinishedJob>,
+    pending: HashMap<(ChunkPos, JobKind), PendingJob>,
+    next_id: u64,
+    block_registry: Arc<Registry<Block>>,
+    texture_atlas: Arc<TextureAtlas>,
+    world_generator: Arc<dyn WorldGenerator>,
+    meshing_mode: MeshingMode,
+    vertex_layout: VertexLayout,
+}
+
+impl ChunkJobs {
+    pub fn new(
+        worker_count: usize,
+        block_registry: Arc<Registry<Block>>,
+        texture_atlas: Arc<TextureAtlas>,
+        world_generator: Arc<dyn WorldGenerator>,
+        meshing_mode: MeshingMode,
+        vertex_layout: VertexLayout,
+    ) -> Self {
+        let (job_sender, job_receiver) = channel::<Job>();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+        let workers = (0..worker_count.max(1))
+            .map(|i| {
+                let job_receiver = job_receiver.clone();
+                thread::Builder::new()
+                    .name(format!("chunk worker {}", i))
+                    .spawn(move || loop {
+                        // The lock is released before the job runs
+                        let job = job_receiver.lock().unwrap().recv();
+                        match job {
+                            Ok(job) => job(),
+                            // The pool was dropped
+                            Err(_) => break,
+                        }
+                    })
+                    .expect("Failed to spawn chunk worker thread")
+            })
+            .collect();
+        let (result_sender, result_receiver) = channel();
+        Self {
+            job_sender: Some(job_sender),
+            workers,
+            result_sender,
+            result_receiver,
+            pending: HashMap::new(),
+            next_id: 0,
+            block_registry,
+            texture_atlas,
+            world_generator,
+            meshing_mode,
+            vertex_layout,
+        }
+    }
+
+    /// Run a generation stage in the background
+    pub fn generate(&mut self, mut task: GenerationTask) {
+        let world_generator = self.world_generator.clone();
+        let block_registry = self.block_registry.clone();
+        let pos = *task.view.pos();
+        self.submit(pos, JobKind::Generate, move || {
+            task.run(&*world_generator, &block_registry);
+            JobResult::Generated(task)
+        });
+    }
+
+    /// Mesh a chunk in the background. `neighbours` must be in the order of `neighbour_offsets`,
+    /// and `biome_map` is the biome map of the column of the chunk if it has one.
+    pub fn mesh(
+        &mut self,
+        pos: ChunkPos,
+        chunk: Chunk,
+        neighbours: Vec<Chunk>,
+        biome_map: Option<Arc<BiomeMap>>,
+    ) {
+        let block_registry = self.block_registry.clone();
+        let texture_atlas = self.texture_atlas.clone();
+        let meshing_mode = self.meshing_mode;
+        let vertex_layout = self.vertex_layout;
+        let version = chunk.version();
+        self.submit(pos, JobKind::Mesh, move || {
+            let neighbours: Vec<&Chunk> = neighbours.iter().collect();
+            let mut neighbourhood = ChunkNeighbourhood::new(&chunk, &neighbours);
+            if let Some(biome_map) = &biome_map {
+                neighbourhood = neighbourhood.with_biome_map(biome_map);
+            }
+            let triangles = crate::mesh::chunk::generate_chunk(
+                &neighbourhood,
+                &block_registry,
+                &texture_atlas,
+                meshing_mode,
+            );
+            JobResult::Meshed(ChunkMesh::new(&triangles, vertex_layout), version)
+        });
+    }
+
+    /// Return true if a job of this kind is running or waiting for the chunk at `pos`
+    pub fn is_pending(&self, pos: &ChunkPos, kind: JobKind) -> bool {
+        self.pending.contains_key(&(*pos, kind))
+    }
+
+    /// Number of jobs running or waiting
+    pub fn pending_count(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Cancel the jobs matching `predicate`. Their results will never be returned by `poll`, and
+    /// the jobs that didn't start yet are skipped.
+    pub fn cancel_where<F>(&mut self, mut predicate: F)
+    where
+        F: FnMut(&ChunkPos, JobKind) -> bool,
+    {
+        self.pending.retain(|(pos, kind), job| {
+            let cancel = predicate(pos, *kind);
+            if cancel {
+                job.cancelled.store(true, Ordering::Relaxed);
+            }
+            !cancel
+        });
+    }
+
+    /// Collect the results of the jobs that finished since the last call
+    pub fn poll(&mut self) -> Vec<(ChunkPos, JobResult)> {
+        let mut results = Vec::new();
+        while let Ok(finished) = self.result_receiver.try_recv() {
+            let key = (finished.pos, finished.result.kind());
+            // Skip the results of cancelled jobs
+            if self.pending.get(&key).map(|job| job.id) == Some(finished.id) {
+                self.pending.remove(&key);
+                results.push((finished.pos, finished.result));
+            }
+        }
+        results
+    }
+
+    fn submit<F>(&mut self, pos: ChunkPos, kind: JobKind, work: F)
+    where
+        F: FnOnce() -> JobResult + Send + 'static,
+    {
+        let id = self.next_id;
+        self.next_id += 1;
+        let cancelled = Arc::new(AtomicBool::new(false));
+        if let Some(previous) = self.pending.insert(
+            (pos, kind),
+            PendingJob {
+                id,
+                cancelled: cancelled.clone(),
+            },
+        ) {
+            previous.cancelled.store(true, Ordering::Relaxed);
+        }
+
+        let result_sender = self.result_sender.clone();
+        let job: Job = Box::new(move || {
+            if cancelled.load(Ordering::Relaxed) {
+                return;
+            }
+            let result = work();
+            // The receiver is gone if the pool was dropped in the meantime
+            let _ = result_sender.send(FinishedJob { id, pos, result });
+        });
+        self.job_sender
+            .as_ref()
+            .unwrap()
+            .send(job)
+            .expect("Chunk worker threads stopped");
+    }
+}
+
+impl JobResult {
+    fn kind(&self) -> JobKind {
+        match self {
+            JobResult::Generated(_) => JobKind::Generate,
+            JobResult::Meshed(..) => JobKind::Mesh,
+        }
+    }
+}
+
+impl Drop for ChunkJobs {
+    fn drop(&mut self) {
+        self.cancel_where(|_, _| true);
+        // Closing the channel stops the workers once they are done with their current job
+        self.job_sender.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::{
+        neighbour_offsets,
+        test_blocks::{block_registry, AIR},
+    };
+    use amethyst::core::nalgebra::Vector3;
+    use image::{Rgba, RgbaImage};
+    use std::time::{Duration, Instant};
+
+    struct AirGenerator;
+
+    impl WorldGenerator for AirGenerator {
+        fn generate_chunk(&self, _pos: &ChunkPos) -> Chunk {
+            Chunk::filled(AIR)
+        }
+    }
+
+    /// A pool of a single worker, which runs the jobs in the order they are submitted
+    fn single_worker() -> ChunkJobs {
+        let block_registry = block_registry();
+        let (texture_atlas, _) = TextureAtlas::build(&block_registry, |_| {
+            Ok(RgbaImage::from_pixel(1, 1, Rgba([0, 0, 0, 255])))
+        })
+        .unwrap();
+        ChunkJobs::new(
+            1,
+            Arc::new(block_registry),
+            Arc::new(texture_atlas),
+            Arc::new(AirGenerator),
+            MeshingMode::Naive,
+            VertexLayout::Packed,
+        )
+    }
+
+    fn mesh(jobs: &mut ChunkJobs, pos: ChunkPos, chunk: Chunk) {
+        let neighbours = neighbour_offsets().map(|_| Chunk::filled(AIR)).collect();
+        jobs.mesh(pos, chunk, neighbours, None);
+    }
+
+    /// Poll the jobs until the one meshing `last` is returned, and return the positions and
+    /// versions of the meshed chunks
+    fn poll_until(jobs: &mut ChunkJobs, last: &ChunkPos) -> Vec<(ChunkPos, u64)> {
+        let start = Instant::now();
+        let mut meshed = Vec::new();
+        while !meshed.iter().any(|(pos, _)| pos == last) {
+            assert!(start.elapsed() < Duration::from_secs(10), "{:?}", meshed);
+            for (pos, result) in jobs.poll() {
+                if let JobResult::Meshed(_, version) = result {
+                    meshed.push((pos, version));
+                }
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        meshed
+    }
+
+    #[test]
+    fn cancelled_jobs_are_not_returned() {
+        let mut jobs = single_worker();
+        let chunk = Chunk::filled(AIR);
+        let positions: Vec<ChunkPos> = (0..4).map(|x| ChunkPos(Vector3::new(x, 0, 0))).collect();
+        for pos in &positions {
+            mesh(&mut jobs, *pos, chunk.clone());
+        }
+        let cancelled = |pos: &ChunkPos| pos.0[0] == 1 || pos.0[0] == 2;
+        jobs.cancel_where(|pos, kind| kind == JobKind::Mesh && cancelled(pos));
+        assert_eq!(jobs.pending_count(), 2);
+        assert!(!jobs.is_pending(&positions[1], JobKind::Mesh));
+
+        // The last job runs after the cancelled ones
+        let meshed = poll_until(&mut jobs, &positions[3]);
+        assert_eq!(meshed, vec![(positions[0], 0), (positions[3], 0)]);
+        assert_eq!(jobs.pending_count(), 0);
+        assert!(jobs.poll().is_empty());
+    }
+
+    #[test]
+    fn new_jobs_replace_pending_ones() {
+        let mut jobs = single_worker();
+        let pos = ChunkPos(Vector3::new(0, 0, 0));
+        let mut chunk = Chunk::filled(AIR);
+        mesh(&mut jobs, pos, chunk.clone());
+        chunk.mark_changed();
+        mesh(&mut jobs, pos, chunk);
+        assert_eq!(jobs.pending_count(), 1);
+
+        // Only the result of the last job is returned
+        let meshed = poll_until(&mut jobs, &pos);
+        assert_eq!(meshed, vec![(pos, 1)]);
+        thread::sleep(Duration::from_millis(50));
+        assert!(jobs.poll().is_empty());
+    }
+}
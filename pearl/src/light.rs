@@ -0,0 +1,706 @@
+//! Voxel lighting.
+//!
+//! Every block has two light levels from 0 to `MAX_LIGHT`, stored in the `ChunkLight` of its
+//! chunk. The sky light comes down from the sky without fading, and fades by one level per block
+//! in the other directions. The block light is emitted by blocks like lamps and fades by one
+//! level per block in every direction. Light only spreads through transparent blocks.
+//!
+//! Chunks are first lit on their own by `light_chunk`, at the end of their generation. The light
+//! then spreads across the borders of the chunks with `connect_chunk` once they are added to the
+//! `World`, and around the edited blocks with `update_block`. The sky is open above the chunks
+//! whose upper neighbour is not loaded.
+use amethyst::{
+    core::{nalgebra::Vector3, shrev::ReaderId},
+    ecs::prelude::{ReadExpect, System, WriteExpect},
+};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::{
+    registry::Registry,
+    world::{
+        Block, BlockChange, BlockPos, Chunk, ChunkPos, World, ADJACENCY, CHUNK_SIZE, CHUNK_VOLUME,
+    },
+};
+
+/// Highest light level, the one of the blocks under the open sky
+pub const MAX_LIGHT: u8 = 15;
+
+/// Index of the downward direction in `ADJACENCY`
+const DOWN: usize = 3;
+
+/// The two kinds of light
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
+pub enum LightChannel {
+    /// Light coming from the sky
+    Sky,
+    /// Light emitted by blocks
+    Block,
+}
+
+impl LightChannel {
+    pub const ALL: [LightChannel; 2] = [LightChannel::Sky, LightChannel::Block];
+
+    /// Position of the level in the byte holding both levels of a block
+    fn shift(self) -> u8 {
+        match self {
+            LightChannel::Sky => 4,
+            LightChannel::Block => 0,
+        }
+    }
+}
+
+/// The light levels of the blocks of a chunk
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChunkLight {
+    /// The sky light in the high 4 bits of every byte and the block light in the low 4 bits.
+    /// Empty if every block has the levels of `uniform`.
+    levels: Vec<u8>,
+    uniform: u8,
+}
+
+impl ChunkLight {
+    /// The same levels for every block
+    pub fn uniform(sky: u8, block: u8) -> Self {
+        debug_assert!(sky <= MAX_LIGHT && block <= MAX_LIGHT);
+        Self {
+            levels: Vec::new(),
+            uniform: sky << 4 | block,
+        }
+    }
+
+    /// No light at all
+    pub fn dark() -> Self {
+        Self::uniform(0, 0)
+    }
+
+    /// Get a light level at the given position inside the chunk
+    pub fn get(&self, channel: LightChannel, x: usize, y: usize, z: usize) -> u8 {
+        let levels = if self.levels.is_empty() {
+            self.uniform
+        } else {
+            self.levels[Self::offset(x, y, z)]
+        };
+        levels >> channel.shift() & 0xf
+    }
+
+    /// Set a light level at the given position inside the chunk
+    pub fn set(&mut self, channel: LightChannel, x: usize, y: usize, z: usize, level: u8) {
+        debug_assert!(level <= MAX_LIGHT);
+        if self.levels.is_empty() {
+            if self.get(channel, x, y, z) == level {
+                return;
+            }
+            self.levels = vec![self.uniform; CHUNK_VOLUME];
+        }
+        let shift = channel.shift();
+        let levels = &mut self.levels[Self::offset(x, y, z)];
+        *levels = *levels & !(0xf << shift) | level << shift;
+    }
+
+    fn offset(x: usize, y: usize, z: usize) -> usize {
+        debug_assert!(x < CHUNK_SIZE && y < CHUNK_SIZE && z < CHUNK_SIZE);
+        (x * CHUNK_SIZE + y) * CHUNK_SIZE + z
+    }
+}
+
+/// Light the blocks of a chunk on their own, as if the sky was open above the chunk and there was
+/// only darkness on the other sides
+pub fn light_chunk(chunk: &mut Chunk, block_registry: &Registry<Block>) {
+    if chunk.is_uniform() {
+        let block = &block_registry[chunk.get(0, 0, 0)];
+        let sky = if block.transparent { MAX_LIGHT } else { 0 };
+        *chunk.light_mut() = ChunkLight::uniform(sky, block.light_emission);
+        return;
+    }
+
+    let mut volume = ChunkVolume {
+        chunk,
+        block_registry,
+        light: ChunkLight::dark(),
+    };
+    let region = ChunkPos(Vector3::zeros()).region();
+    for &channel in LightChannel::ALL.iter() {
+        let mut queue = VecDeque::new();
+        for pos in region.iter() {
+            let source = volume.source(channel, &pos);
+            if source > 0 {
+                volume.set_light(channel, &pos, source);
+                queue.push_back(pos);
+            }
+        }
+        spread(&mut volume, channel, &mut queue);
+    }
+    let light = volume.light;
+    *chunk.light_mut() = light;
+}
+
+/// Spread the light across the borders of a chunk lit by `light_chunk` that was just added to
+/// the world, and return the chunks whose light changed.
+///
+/// The light of the chunk itself doesn't make the meshes of its neighbours outdated: they could
+/// only be meshed while an earlier copy of the chunk was loaded, which had the same light.
+pub fn connect_chunk(
+    world: &mut World,
+    block_registry: &Registry<Block>,
+    pos: &ChunkPos,
+) -> HashSet<ChunkPos> {
+    let mut volume = WorldVolume::new(world, block_registry);
+    let up = Vector3::new(0, 1, 0);
+    for &channel in LightChannel::ALL.iter() {
+        let mut relight = VecDeque::new();
+        if channel == LightChannel::Sky {
+            // The chunk and the one below it were lit under the open sky. Where it is covered by
+            // the chunk above them, the sky light that came through their top is removed.
+            for lower in [ChunkPos(pos.0 - up), *pos].iter() {
+                let mut removed = VecDeque::new();
+                let upper = ChunkPos(lower.0 + up);
+                if volume.world.get_chunk(lower).is_some()
+                    && volume.world.get_chunk(&upper).is_some()
+                {
+                    let top = lower.origin().offset(0, CHUNK_SIZE as isize - 1, 0);
+                    for x in 0..CHUNK_SIZE as isize {
+                        for z in 0..CHUNK_SIZE as isize {
+                            let block_pos = top.offset(x, 0, z);
+                            let above = block_pos.offset(0, 1, 0);
+                            if volume.light(channel, &block_pos) == Some(MAX_LIGHT)
+                                && volume.light(channel, &above) != Some(MAX_LIGHT)
+                            {
+                                volume.set_light(channel, &block_pos, 0);
+                                removed.push_back((block_pos, MAX_LIGHT));
+                            }
+                        }
+                    }
+                }
+                unspread(&mut volume, channel, &mut removed, &mut relight);
+            }
+        }
+        relight.extend(border_seeds(volume.world, block_registry, pos, channel));
+        spread(&mut volume, channel, &mut relight);
+    }
+    volume.finish(Some(pos))
+}
+
+/// Update the light around a block that was just changed, and return the chunks whose light
+/// changed
+pub fn update_block(
+    world: &mut World,
+    block_registry: &Registry<Block>,
+    pos: &BlockPos,
+) -> HashSet<ChunkPos> {
+    let mut volume = WorldVolume::new(world, block_registry);
+    for &channel in LightChannel::ALL.iter() {
+        let level = match volume.light(channel, pos) {
+            Some(level) => level,
+            None => break,
+        };
+        let mut removed = VecDeque::new();
+        let mut relight = VecDeque::new();
+        let source = volume.source(channel, pos);
+        volume.set_light(channel, pos, source);
+        if source > 0 {
+            relight.push_back(*pos);
+        }
+        removed.push_back((*pos, level));
+        unspread(&mut volume, channel, &mut removed, &mut relight);
+        // The light around the block spreads into it if it is transparent
+        relight.extend(
+            ADJACENCY
+                .iter()
+                .map(|[dx, dy, dz]| pos.offset(*dx, *dy, *dz)),
+        );
+        spread(&mut volume, channel, &mut relight);
+    }
+    volume.finish(None)
+}
+
+/// The system updating the light of the `World` after its blocks are changed
+#[derive(Default)]
+pub struct LightingSystem {
+    /// Registered on the first run, once the world exists
+    reader: Option<ReaderId<BlockChange>>,
+}
+
+impl<'a> System<'a> for LightingSystem {
+    type SystemData = (WriteExpect<'a, World>, ReadExpect<'a, Registry<Block>>);
+
+    fn run(&mut self, (mut world, block_registry): Self::SystemData) {
+        let reader = self
+            .reader
+            .get_or_insert_with(|| world.register_change_reader());
+        let changed: Vec<BlockPos> = world
+            .read_changes(reader)
+            .flat_map(|change| change.region.iter())
+            .collect();
+        for pos in changed {
+            update_block(&mut world, &block_registry, &pos);
+        }
+    }
+}
+
+/// Level given by a block with the light `level` to its neighbour on `side`, in the order of
+/// `ADJACENCY`
+fn spread_level(channel: LightChannel, level: u8, side: usize) -> u8 {
+    if channel == LightChannel::Sky && side == DOWN && level == MAX_LIGHT {
+        MAX_LIGHT
+    } else {
+        level.saturating_sub(1)
+    }
+}
+
+/// Blocks that the light spreads through
+trait LightVolume {
+    /// Get a block, or `None` if it is outside of the volume
+    fn block(&self, pos: &BlockPos) -> Option<&Block>;
+
+    /// Get a light level, or `None` if it is outside of the volume
+    fn light(&self, channel: LightChannel, pos: &BlockPos) -> Option<u8>;
+
+    /// Set a light level, does nothing outside of the volume
+    fn set_light(&mut self, channel: LightChannel, pos: &BlockPos, level: u8);
+
+    fn is_transparent(&self, pos: &BlockPos) -> bool {
+        self.block(pos).map_or(false, |block| block.transparent)
+    }
+
+    /// The light of a block that doesn't come from its neighbours: the light emitted by the
+    /// block, or the sky light of the transparent blocks at the top of the volume
+    fn source(&self, channel: LightChannel, pos: &BlockPos) -> u8 {
+        match channel {
+            LightChannel::Sky => {
+                let open_sky = self.block(&pos.offset(0, 1, 0)).is_none();
+                if open_sky && self.is_transparent(pos) {
+                    MAX_LIGHT
+                } else {
+                    0
+                }
+            }
+            LightChannel::Block => self.block(pos).map_or(0, |block| block.light_emission),
+        }
+    }
+}
+
+/// Spread the light of the queued blocks to their neighbours, and so on
+fn spread<V: LightVolume>(volume: &mut V, channel: LightChannel, queue: &mut VecDeque<BlockPos>) {
+    while let Some(pos) = queue.pop_front() {
+        let level = match volume.light(channel, &pos) {
+            Some(level) => level,
+            None => continue,
+        };
+        for (side, [dx, dy, dz]) in ADJACENCY.iter().enumerate() {
+            let next = pos.offset(*dx, *dy, *dz);
+            let next_level = spread_level(channel, level, side);
+            if volume
+                .light(channel, &next)
+                .map_or(false, |l| l < next_level)
+                && volume.is_transparent(&next)
+            {
+                volume.set_light(channel, &next, next_level);
+                queue.push_back(next);
+            }
+        }
+    }
+}
+
+/// Remove the light that spread from the queued blocks, given with the level they had. The
+/// blocks lit by something else around the darkened blocks are queued in `relight`, so that their
+/// light spreads back.
+fn unspread<V: LightVolume>(
+    volume: &mut V,
+    channel: LightChannel,
+    removed: &mut VecDeque<(BlockPos, u8)>,
+    relight: &mut VecDeque<BlockPos>,
+) {
+    while let Some((pos, level)) = removed.pop_front() {
+        for (side, [dx, dy, dz]) in ADJACENCY.iter().enumerate() {
+            let next = pos.offset(*dx, *dy, *dz);
+            let next_level = match volume.light(channel, &next) {
+                Some(next_level) if next_level > 0 => next_level,
+                _ => continue,
+            };
+            // The light of the neighbour may have come from the removed light
+            if next_level <= spread_level(channel, level, side) {
+                let source = volume.source(channel, &next);
+                volume.set_light(channel, &next, source);
+                if source > 0 {
+                    relight.push_back(next);
+                }
+                removed.push_back((next, next_level));
+            } else {
+                relight.push_back(next);
+            }
+        }
+    }
+}
+
+/// Blocks on both sides of the borders of a chunk that can light the block across the border
+fn border_seeds(
+    world: &World,
+    block_registry: &Registry<Block>,
+    pos: &ChunkPos,
+    channel: LightChannel,
+) -> Vec<BlockPos> {
+    let mut seeds = Vec::new();
+    let chunk = match world.get_chunk(pos) {
+        Some(chunk) => chunk,
+        None => return seeds,
+    };
+    let last = CHUNK_SIZE - 1;
+    for (side, offset) in ADJACENCY.iter().enumerate() {
+        let neighbour_pos = ChunkPos(pos.0 + Vector3::from(*offset));
+        let neighbour = match world.get_chunk(&neighbour_pos) {
+            Some(neighbour) => neighbour,
+            None => continue,
+        };
+        // The face is perpendicular to axis `d`, and spans axes `u` and `v`
+        let d = side / 2;
+        let u = (d + 1) % 3;
+        let v = (d + 2) % 3;
+        let (inner, outer) = if offset[d] > 0 { (last, 0) } else { (0, last) };
+        for a in 0..CHUNK_SIZE {
+            for b in 0..CHUNK_SIZE {
+                let mut local = [0; 3];
+                local[u] = a;
+                local[v] = b;
+                let mut across = local;
+                local[d] = inner;
+                across[d] = outer;
+                // Sides come in pairs of opposite directions in `ADJACENCY`
+                let pairs = [
+                    (chunk, pos, local, neighbour, across, side),
+                    (neighbour, &neighbour_pos, across, chunk, local, side ^ 1),
+                ];
+                for (from, from_pos, [x, y, z], to, [tx, ty, tz], direction) in pairs.iter() {
+                    let level = from.light().get(channel, *x, *y, *z);
+                    if spread_level(channel, level, *direction)
+                        > to.light().get(channel, *tx, *ty, *tz)
+                        && block_registry[to.get(*tx, *ty, *tz)].transparent
+                    {
+                        seeds.push(
+                            from_pos
+                                .origin()
+                                .offset(*x as isize, *y as isize, *z as isize),
+                        );
+                    }
+                }
+            }
+        }
+    }
+    seeds
+}
+
+/// The blocks of a single chunk, with positions relative to its origin
+struct ChunkVolume<'a> {
+    chunk: &'a Chunk,
+    block_registry: &'a Registry<Block>,
+    /// The light being computed
+    light: ChunkLight,
+}
+
+impl<'a> ChunkVolume<'a> {
+    fn local(pos: &BlockPos) -> Option<[usize; 3]> {
+        let size = CHUNK_SIZE as isize;
+        if pos.0.iter().all(|c| 0 <= *c && *c < size) {
+            Some([pos.0[0] as usize, pos.0[1] as usize, pos.0[2] as usize])
+        } else {
+            None
+        }
+    }
+}
+
+impl<'a> LightVolume for ChunkVolume<'a> {
+    fn block(&self, pos: &BlockPos) -> Option<&Block> {
+        let [x, y, z] = Self::local(pos)?;
+        Some(&self.block_registry[self.chunk.get(x, y, z)])
+    }
+
+    fn light(&self, channel: LightChannel, pos: &BlockPos) -> Option<u8> {
+        let [x, y, z] = Self::local(pos)?;
+        Some(self.light.get(channel, x, y, z))
+    }
+
+    fn set_light(&mut self, channel: LightChannel, pos: &BlockPos, level: u8) {
+        if let Some([x, y, z]) = Self::local(pos) {
+            self.light.set(channel, x, y, z, level);
+        }
+    }
+}
+
+/// The loaded blocks of the world, remembering the light levels it changes
+struct WorldVolume<'a> {
+    world: &'a mut World,
+    block_registry: &'a Registry<Block>,
+    /// The levels of the blocks whose light was set, from before it was first set
+    previous: HashMap<(LightChannel, BlockPos), u8>,
+}
+
+impl<'a> WorldVolume<'a> {
+    fn new(world: &'a mut World, block_registry: &'a Registry<Block>) -> Self {
+        Self {
+            world,
+            block_registry,
+            previous: HashMap::new(),
+        }
+    }
+
+    /// Mark the chunks whose mesh shows a block with a different light level than before as
+    /// changed, and return the chunks whose light changed. A block of `connected` only marks
+    /// its own chunk.
+    ///
+    /// Light is often removed then spread back to the same level, so the levels are compared
+    /// once every change is done.
+    fn finish(self, connected: Option<&ChunkPos>) -> HashSet<ChunkPos> {
+        let mut changed = HashSet::new();
+        for ((channel, pos), previous) in self.previous {
+            let chunk_pos = pos.chunk_pos();
+            let chunk = match self.world.get_chunk_mut(&chunk_pos) {
+                Some(chunk) => chunk,
+                None => continue,
+            };
+            let local = pos.local_pos().0;
+            if chunk.light().get(channel, local[0], local[1], local[2]) == previous {
+                continue;
+            }
+            if Some(&chunk_pos) == connected {
+                chunk.mark_changed();
+            } else {
+                // The mesh of the neighbours shows the light of the border blocks as well
+                self.world.mark_changed(&pos);
+            }
+            changed.insert(chunk_pos);
+        }
+        changed
+    }
+}
+
+impl<'a> LightVolume for WorldVolume<'a> {
+    fn block(&self, pos: &BlockPos) -> Option<&Block> {
+        let block_registry = self.block_registry;
+        self.world.get_block(pos).map(|id| &block_registry[id])
+    }
+
+    fn light(&self, channel: LightChannel, pos: &BlockPos) -> Option<u8> {
+        let local = pos.local_pos().0;
+        self.world
+            .get_chunk(&pos.chunk_pos())
+            .map(|chunk| chunk.light().get(channel, local[0], local[1], local[2]))
+    }
+
+    fn set_light(&mut self, channel: LightChannel, pos: &BlockPos, level: u8) {
+        let chunk_pos = pos.chunk_pos();
+        if let Some(chunk) = self.world.get_chunk_mut(&chunk_pos) {
+            let local = pos.local_pos().0;
+            let light = chunk.light_mut();
+            let previous = light.get(channel, local[0], local[1], local[2]);
+            self.previous.entry((channel, *pos)).or_insert(previous);
+            light.set(channel, local[0], local[1], local[2], level);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        noise::SplitMix64,
+        world::{
+            test_blocks::{block_registry, AIR, LAMP, STONE},
+            BlockRegion,
+        },
+    };
+
+    #[test]
+    fn chunk_under_the_sky() {
+        let block_registry = block_registry();
+        // A roof over half of the chunk, with a lamp below it
+        let mut chunk = Chunk::filled(AIR);
+        for x in 0..16 {
+            for z in 0..CHUNK_SIZE {
+                chunk.set(x, 20, z, STONE);
+            }
+        }
+        chunk.set(4, 10, 4, LAMP);
+        light_chunk(&mut chunk, &block_registry);
+
+        let light = chunk.light();
+        let sky = |x, y, z| light.get(LightChannel::Sky, x, y, z);
+        let block = |x, y, z| light.get(LightChannel::Block, x, y, z);
+        // The sky light goes all the way down, and fades below the roof
+        assert_eq!(sky(16, 0, 5), MAX_LIGHT);
+        assert_eq!(sky(16, 20, 5), MAX_LIGHT);
+        assert_eq!(sky(15, 20, 5), 0);
+        assert_eq!(sky(15, 19, 5), 14);
+        assert_eq!(sky(10, 5, 5), 9);
+        assert_eq!(sky(0, 19, 5), 0);
+        // The lamp lights itself and the air around it, but not the other side of the roof
+        assert_eq!(block(4, 10, 4), 12);
+        assert_eq!(block(4, 10, 7), 9);
+        assert_eq!(block(6, 12, 4), 8);
+        assert_eq!(block(4, 21, 4), 0);
+    }
+
+    /// Light levels of a region computed from scratch, by giving every transparent block the
+    /// highest level its neighbours can give it until nothing changes. The sky is open above
+    /// the region.
+    fn brute_force_light(
+        world: &World,
+        block_registry: &Registry<Block>,
+        region: &BlockRegion,
+        channel: LightChannel,
+    ) -> Vec<u8> {
+        // Blocks are indexed in the order of `BlockRegion::iter`
+        let blocks: Vec<&Block> = region
+            .iter()
+            .map(|pos| &block_registry[world.get_block(&pos).unwrap()])
+            .collect();
+        let size = region.max.0 - region.min.0 + Vector3::new(1, 1, 1);
+        let size = [size[0], size[1], size[2]];
+        let mut levels: Vec<u8> = region
+            .iter()
+            .zip(blocks.iter())
+            .map(|(pos, block)| match channel {
+                LightChannel::Sky if block.transparent && pos.0[1] == region.max.0[1] => MAX_LIGHT,
+                LightChannel::Sky => 0,
+                LightChannel::Block => block.light_emission,
+            })
+            .collect();
+
+        let mut backwards = false;
+        let mut changed = true;
+        while changed {
+            changed = false;
+            // Going back and forth, the light spreads across the region in a few passes
+            backwards = !backwards;
+            for i in 0..levels.len() {
+                let i = if backwards { levels.len() - 1 - i } else { i };
+                if !blocks[i].transparent {
+                    continue;
+                }
+                let coordinates = [
+                    i as isize / (size[1] * size[2]),
+                    i as isize / size[2] % size[1],
+                    i as isize % size[2],
+                ];
+                for (side, offset) in ADJACENCY.iter().enumerate() {
+                    let mut neighbour = coordinates;
+                    for (c, offset) in neighbour.iter_mut().zip(offset.iter()) {
+                        *c += offset;
+                    }
+                    if (0..3).any(|axis| neighbour[axis] < 0 || neighbour[axis] >= size[axis]) {
+                        continue;
+                    }
+                    let [x, y, z] = neighbour;
+                    let neighbour_level = levels[((x * size[1] + y) * size[2] + z) as usize];
+                    // The neighbour on `side` lights the block from the opposite side
+                    let level = spread_level(channel, neighbour_level, side ^ 1);
+                    if level > levels[i] {
+                        levels[i] = level;
+                        changed = true;
+                    }
+                }
+            }
+        }
+        levels
+    }
+
+    fn assert_light_matches(world: &World, block_registry: &Registry<Block>, region: &BlockRegion) {
+        for &channel in LightChannel::ALL.iter() {
+            let expected = brute_force_light(world, block_registry, region, channel);
+            for (pos, expected) in region.iter().zip(expected) {
+                let local = pos.local_pos().0;
+                let chunk = world.get_chunk(&pos.chunk_pos()).unwrap();
+                let level = chunk.light().get(channel, local[0], local[1], local[2]);
+                assert_eq!(level, expected, "{:?} light at {:?}", channel, pos);
+            }
+        }
+    }
+
+    #[test]
+    fn incremental_light_matches_brute_force() {
+        let block_registry = block_registry();
+        let mut rng = SplitMix64::new(7);
+        let random_block = |rng: &mut SplitMix64, stone_probability: f64| {
+            let value = rng.next_f64();
+            if value < 0.005 {
+                LAMP
+            } else if value < stone_probability {
+                STONE
+            } else {
+                AIR
+            }
+        };
+        // Caves below the height 40, and a few floating blocks above it
+        let positions = [
+            ChunkPos(Vector3::new(0, 0, 0)),
+            ChunkPos(Vector3::new(1, 1, 0)),
+            ChunkPos(Vector3::new(0, 1, 0)),
+            ChunkPos(Vector3::new(1, 0, 0)),
+        ];
+        let mut world = World::new();
+        for pos in positions.iter() {
+            let mut chunk = Chunk::filled(AIR);
+            for block_pos in pos.region().iter() {
+                let stone_probability = if block_pos.0[1] < 40 { 0.6 } else { 0.05 };
+                let local = block_pos.local_pos().0;
+                let block = random_block(&mut rng, stone_probability);
+                chunk.set(local[0], local[1], local[2], block);
+            }
+            light_chunk(&mut chunk, &block_registry);
+            world.insert_chunk(*pos, chunk);
+            // The lower chunks are added before and after the ones above them
+            connect_chunk(&mut world, &block_registry, pos);
+        }
+        let region = BlockRegion::new(BlockPos::new(0, 0, 0), BlockPos::new(63, 63, 31));
+        assert_light_matches(&world, &block_registry, &region);
+
+        for _ in 0..500 {
+            let offset =
+                |rng: &mut SplitMix64, size: isize| (rng.next_f64() * size as f64) as isize;
+            let pos = BlockPos::new(
+                offset(&mut rng, 64),
+                offset(&mut rng, 64),
+                offset(&mut rng, 32),
+            );
+            let block = random_block(&mut rng, 0.5);
+            world.set_block(&pos, block);
+            update_block(&mut world, &block_registry, &pos);
+        }
+        assert_light_matches(&world, &block_registry, &region);
+    }
+
+    #[test]
+    fn connecting_a_chunk_only_outdates_the_changed_meshes() {
+        let block_registry = block_registry();
+        let mut world = World::new();
+        let add_chunk = |world: &mut World, pos: ChunkPos, mut chunk: Chunk| {
+            light_chunk(&mut chunk, &block_registry);
+            world.insert_chunk(pos, chunk);
+            connect_chunk(world, &block_registry, &pos)
+        };
+        let far = ChunkPos(Vector3::new(-1, 0, 0));
+        let open = ChunkPos(Vector3::new(0, 0, 0));
+        add_chunk(&mut world, far, Chunk::filled(AIR));
+        add_chunk(&mut world, open, Chunk::filled(AIR));
+        let versions = |world: &World| -> Vec<u64> {
+            [far, open]
+                .iter()
+                .map(|pos| world.get_chunk(pos).unwrap().version())
+                .collect()
+        };
+        let before = versions(&world);
+
+        // A chunk with a roof, where the sky light comes in from the side of the open chunk
+        let covered = ChunkPos(Vector3::new(1, 0, 0));
+        let mut chunk = Chunk::filled(AIR);
+        for x in 0..CHUNK_SIZE {
+            for z in 0..CHUNK_SIZE {
+                chunk.set(x, CHUNK_SIZE - 1, z, STONE);
+            }
+        }
+        let changed = add_chunk(&mut world, covered, chunk);
+        assert_eq!(changed, [covered].iter().cloned().collect());
+        let light = world.get_chunk(&covered).unwrap().light();
+        assert_eq!(light.get(LightChannel::Sky, 0, 0, 0), MAX_LIGHT - 1);
+        assert_eq!(versions(&world), before);
+        assert!(world.get_chunk(&covered).unwrap().version() > 0);
+    }
+}
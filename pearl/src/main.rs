@@ -4,14 +4,41 @@ use amethyst::{
     core::transform::TransformBundle,
     input::InputBundle,
     prelude::*,
-    renderer::{DisplayConfig, DrawShaded, Pipeline, PosNormTex, RenderBundle, Stage},
+    renderer::{DisplayConfig, Pipeline, RenderBundle, Stage},
     utils::application_root_dir,
 };
 use exploration_camera::ExplorationCameraBundle;
+use log::error;
+use std::path::Path;
 
+use crate::{
+    atlas::TextureAtlas,
+    blocks::load_blocks,
+    editing::BlockEditingSystem,
+    light::LightingSystem,
+    mesh::buffer::PackedVertex,
+    player::PlayerMovementSystem,
+    render::{DrawChunks, FullVertex},
+    streaming::ChunkStreamingSystem,
+    worldgen::{create_generator, WorldConfig},
+};
+
+mod atlas;
+mod blocks;
+mod collision;
+mod editing;
+mod jobs;
+mod light;
 mod mesh;
+mod noise;
 mod pearl;
+mod player;
+mod raycast;
+mod region;
 mod registry;
+mod remesh;
+mod render;
+mod streaming;
 mod world;
 mod worldgen;
 
@@ -27,15 +54,45 @@ fn main() -> amethyst::Result<()> {
 
     let key_bindings_path = format!("{}/resources/keybindings.ron", app_root);
 
+    let resources_path = format!("{}/resources", app_root);
+    let (block_registry, ores) = match load_blocks(Path::new(&resources_path)) {
+        Ok(blocks) => blocks,
+        Err(e) => {
+            error!("Failed to load the block definitions: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let assets_path = format!("{}/assets", app_root);
+    let (texture_atlas, atlas_image) =
+        match TextureAtlas::load(&block_registry, Path::new(&assets_path)) {
+            Ok(atlas) => atlas,
+            Err(e) => {
+                error!("Failed to build the block texture atlas: {}", e);
+                std::process::exit(1);
+            }
+        };
+    let world_config_path = format!("{}/resources/world_config.ron", app_root);
+    let world_config = WorldConfig::load(&world_config_path);
+    let world_generator = match create_generator(&world_config, &block_registry, &ores) {
+        Ok(world_generator) => world_generator,
+        Err(e) => {
+            error!("Failed to create the world generator: {}", e);
+            std::process::exit(1);
+        }
+    };
+
     let path = format!("{}/resources/display_config.ron", app_root);
     let config = DisplayConfig::load(&path);
 
     let pipe = Pipeline::build().with_stage(
         Stage::with_backbuffer()
             .clear_target([0.00196, 0.23726, 0.21765, 1.0], 1.0)
-            .with_pass(DrawShaded::<PosNormTex>::new()),
+            .with_pass(DrawChunks::<PackedVertex>::default())
+            .with_pass(DrawChunks::<FullVertex>::default()),
     );
 
+    let chunk_streaming = ChunkStreamingSystem::new(4, 4, 3);
+    let chunk_remesh = chunk_streaming.remesh_system();
     let game_data = GameDataBuilder::default()
         .with_bundle(
             ExplorationCameraBundle::<String, String>::new(
@@ -49,9 +106,27 @@ fn main() -> amethyst::Result<()> {
         .with_bundle(
             InputBundle::<String, String>::new().with_bindings_from_file(&key_bindings_path)?,
         )?
-        .with_bundle(TransformBundle::new().with_dep(&["exploration_camera_movement"]))?
+        .with(
+            PlayerMovementSystem::default(),
+            "player_movement",
+            &["exploration_camera_movement"],
+        )
+        .with(
+            BlockEditingSystem::new(8.0),
+            "block_editing",
+            &["player_movement"],
+        )
+        .with(LightingSystem::default(), "lighting", &["block_editing"])
+        .with(chunk_streaming, "chunk_streaming", &["lighting"])
+        // The edited chunks are meshed again once their light is updated
+        .with(chunk_remesh, "chunk_remesh", &["chunk_streaming"])
+        .with_bundle(TransformBundle::new().with_dep(&["player_movement"]))?
         .with_bundle(RenderBundle::new(pipe, Some(config)))?;
-    let mut game = Application::new("./", pearl::Pearl::default(), game_data)?;
+    let mut game = Application::new(
+        "./",
+        pearl::Pearl::new(block_registry, world_generator, texture_atlas, atlas_image),
+        game_data,
+    )?;
 
     game.run();
 
@@ -1,6 +1,7 @@
 /// Cube meshing
 pub mod cube {
-    use amethyst::{core::nalgebra::Vector3, renderer::PosNormTex};
+    use super::buffer::ChunkVertex;
+    use amethyst::core::nalgebra::Vector3;
 
     /// Cube vertices. Bottom face then top face. Counterclockwise starting from (0.0, _, 0.0).
     const VERTICES: [[f32; 3]; 8] = [
@@ -43,7 +44,7 @@ pub mod cube {
     ];
 
     /// Normal vector for every face. Same order as the `FACES` variable.
-    const NORMALS: [[f32; 3]; 6] = [
+    pub const NORMALS: [[f32; 3]; 6] = [
         [1.0, 0.0, 0.0],
         [-1.0, 0.0, 0.0],
         [0.0, 1.0, 0.0],
@@ -52,96 +53,464 @@ pub mod cube {
         [0.0, 0.0, -1.0],
     ];
 
-    const TOTAL_VERTEX_COUNT: usize = 36;
+    /// For every face, the axes along which the two texture coordinates vary.
+    pub const TEXTURE_AXES: [[usize; 2]; 6] = [[2, 1], [2, 1], [2, 0], [2, 0], [0, 1], [0, 1]];
 
-    pub fn generate_cube(offset: Vector3<f32>, dest: &mut Vec<PosNormTex>) {
-        dest.reserve(TOTAL_VERTEX_COUNT);
-        for f in 0..6 {
-            for v in 0..6 {
-                dest.push(PosNormTex {
-                    position: Vector3::from(VERTICES[FACES[f][v]]) + &offset,
-                    normal: NORMALS[f].into(),
-                    tex_coord: TEXTURE_COORDINATES[v].into(),
-                });
-            }
+    /// Number of vertices generated for one face.
+    pub const FACE_VERTEX_COUNT: usize = 6;
+
+    /// Vertices of a face split along the other diagonal, as indices into the rows of `FACES`.
+    /// The winding order is unchanged.
+    const FLIPPED_FACE: [usize; 6] = [5, 1, 2, 2, 0, 5];
+
+    /// Ambient occlusion level of a vertex that isn't occluded at all.
+    pub const MAX_AO: u8 = 3;
+
+    /// Index of the corner of `face` where the vertex is, in the order used by `ao` arguments:
+    /// `a + 2 * b`, where a and b are 0 or 1 along the two `TEXTURE_AXES` of the face.
+    pub fn corner_index(face: usize, vertex: usize) -> usize {
+        let position = VERTICES[vertex];
+        let [a, b] = TEXTURE_AXES[face];
+        position[a] as usize + 2 * position[b] as usize
+    }
+
+    /// Generate the two triangles of one face of the cube. `face` uses the order of `FACES`.
+    pub fn generate_face(
+        offset: Vector3<f32>,
+        face: usize,
+        tile: u16,
+        ao: [u8; 4],
+        colors: [[f32; 3]; 4],
+        dest: &mut Vec<ChunkVertex>,
+    ) {
+        let size = Vector3::new(1.0, 1.0, 1.0);
+        generate_quad(offset, face, size, tile, ao, colors, dest);
+    }
+
+    /// Generate one face of a box of the given size, textured with an atlas tile. The texture
+    /// coordinates go from 0 to the size of the box, so that the tile is repeated once per block.
+    ///
+    /// `ao` holds the ambient occlusion level of every corner, see `corner_index`, and `colors`
+    /// the color of the light at every corner. The quad is split along the diagonal whose corners
+    /// are the least occluded, otherwise the interpolation of a single dark corner would spread
+    /// over the whole quad.
+    pub fn generate_quad(
+        offset: Vector3<f32>,
+        face: usize,
+        size: Vector3<f32>,
+        tile: u16,
+        ao: [u8; 4],
+        colors: [[f32; 3]; 4],
+        dest: &mut Vec<ChunkVertex>,
+    ) {
+        dest.reserve(FACE_VERTEX_COUNT);
+        let texture_scale = [size[TEXTURE_AXES[face][0]], size[TEXTURE_AXES[face][1]]];
+        let corner_ao = |v: usize| ao[corner_index(face, FACES[face][v])];
+        let corner_color = |v: usize| colors[corner_index(face, FACES[face][v])];
+        // Vertices 0 and 1 are the ends of the diagonal, 2 and 5 are the other corners
+        let flip = corner_ao(0) + corner_ao(1) < corner_ao(2) + corner_ao(5);
+        for (i, flipped) in FLIPPED_FACE.iter().enumerate() {
+            let v = if flip { *flipped } else { i };
+            dest.push(ChunkVertex {
+                position: Vector3::from(VERTICES[FACES[face][v]]).component_mul(&size) + &offset,
+                normal: NORMALS[face].into(),
+                tex_coord: [
+                    TEXTURE_COORDINATES[v][0] * texture_scale[0],
+                    TEXTURE_COORDINATES[v][1] * texture_scale[1],
+                ]
+                .into(),
+                tile,
+                ao: corner_ao(v) as f32 / MAX_AO as f32,
+                color: corner_color(v),
+            });
         }
     }
 }
 
 /// Chunk meshing
 pub mod chunk {
+    use super::{
+        buffer::ChunkVertex,
+        cube::{self, MAX_AO, TEXTURE_AXES},
+    };
     use crate::{
+        atlas::TextureAtlas,
+        light::{LightChannel, MAX_LIGHT},
         registry::Registry,
-        world::{Block, Chunk},
+        world::{floor_div, floor_mod, neighbour_offsets, Block, BlockId, Chunk, ADJACENCY},
+        worldgen::BiomeMap,
     };
-    use amethyst::{core::nalgebra::Vector3, renderer::PosNormTex};
-
-    pub const ADJACENCY: [[isize; 3]; 6] = [
-        [1, 0, 0],
-        [-1, 0, 0],
-        [0, 1, 0],
-        [0, -1, 0],
-        [0, 0, 1],
-        [0, 0, -1],
-    ];
+    use amethyst::core::nalgebra::Vector3;
 
     const CHUNK_SIZE: isize = crate::world::CHUNK_SIZE as isize;
 
+    /// A chunk and its 26 neighbours, used to read the blocks around the chunk while meshing
+    pub struct ChunkNeighbourhood<'a> {
+        /// Indexed by `9 * (x + 1) + 3 * (y + 1) + (z + 1)`, the chunk itself is in the middle
+        chunks: Vec<&'a Chunk>,
+        /// Biome map of the column of the chunk, giving the color of the tinted blocks
+        biome_map: Option<&'a BiomeMap>,
+    }
+
+    impl<'a> ChunkNeighbourhood<'a> {
+        /// `neighbours` must be in the order of `neighbour_offsets`
+        pub fn new(chunk: &'a Chunk, neighbours: &[&'a Chunk]) -> Self {
+            assert!(neighbours.len() == 26);
+            let mut chunks = neighbours.to_vec();
+            chunks.insert(13, chunk);
+            Self {
+                chunks,
+                biome_map: None,
+            }
+        }
+
+        /// Tint the blocks with the grass tints of `biome_map`. Without a biome map, the tinted
+        /// blocks keep the colors of their texture.
+        pub fn with_biome_map(mut self, biome_map: &'a BiomeMap) -> Self {
+            self.biome_map = Some(biome_map);
+            self
+        }
+
+        /// Get every neighbour from its offset
+        pub fn from_fn<F>(chunk: &'a Chunk, neighbour: F) -> Self
+        where
+            F: FnMut(Vector3<isize>) -> &'a Chunk,
+        {
+            let neighbours: Vec<&Chunk> = neighbour_offsets().map(neighbour).collect();
+            Self::new(chunk, &neighbours)
+        }
+
+        pub fn chunk(&self) -> &'a Chunk {
+            self.chunks[13]
+        }
+
+        /// Get the id of a block, relative to the origin of the middle chunk. Every coordinate
+        /// must be between `-CHUNK_SIZE` and `2 * CHUNK_SIZE - 1`.
+        pub fn get(&self, x: isize, y: isize, z: isize) -> BlockId {
+            let (chunk, [x, y, z]) = self.locate(x, y, z);
+            chunk.get(x, y, z)
+        }
+
+        /// Get a light level of a block, with the same coordinates as `get`
+        pub fn light(&self, channel: LightChannel, [x, y, z]: [isize; 3]) -> u8 {
+            let (chunk, [x, y, z]) = self.locate(x, y, z);
+            chunk.light().get(channel, x, y, z)
+        }
+
+        /// The chunk containing a block, and the position of the block inside the chunk
+        fn locate(&self, x: isize, y: isize, z: isize) -> (&'a Chunk, [usize; 3]) {
+            let index = |c: isize| floor_div(c, CHUNK_SIZE) + 1;
+            let local = |c: isize| floor_mod(c, CHUNK_SIZE) as usize;
+            let chunk = self.chunks[(9 * index(x) + 3 * index(y) + index(z)) as usize];
+            (chunk, [local(x), local(y), local(z)])
+        }
+
+        fn is_transparent(&self, block_registry: &Registry<Block>, [x, y, z]: [isize; 3]) -> bool {
+            block_registry[self.get(x, y, z)].transparent
+        }
+
+        /// Grass tint of a column of blocks of the middle chunk
+        fn grass_tint(&self, x: isize, z: isize) -> [f32; 3] {
+            self.biome_map.map_or([1.0; 3], |biome_map| {
+                biome_map.grass_tint(x as usize, z as usize)
+            })
+        }
+    }
+
+    /// The algorithm used to build chunk meshes
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum MeshingMode {
+        /// Two triangles for every visible block face
+        Naive,
+        /// Adjacent coplanar faces of the same block are merged into larger quads
+        Greedy,
+    }
+
+    /// Generate the mesh of a chunk. Only the faces next to a different transparent block are
+    /// generated.
     pub fn generate_chunk(
-        chunk: &Chunk,
-        adjacent_chunks: &[&Chunk],
+        neighbourhood: &ChunkNeighbourhood,
         block_registry: &Registry<Block>,
-    ) -> Vec<PosNormTex> {
-        assert!(adjacent_chunks.len() == 6);
+        atlas: &TextureAtlas,
+        mode: MeshingMode,
+    ) -> Vec<ChunkVertex> {
+        match mode {
+            MeshingMode::Naive => generate_chunk_naive(neighbourhood, block_registry, atlas),
+            MeshingMode::Greedy => generate_chunk_greedy(neighbourhood, block_registry, atlas),
+        }
+    }
 
+    fn generate_chunk_naive(
+        neighbourhood: &ChunkNeighbourhood,
+        block_registry: &Registry<Block>,
+        atlas: &TextureAtlas,
+    ) -> Vec<ChunkVertex> {
         let mut output = Vec::new();
         for x in 0..CHUNK_SIZE {
             for y in 0..CHUNK_SIZE {
                 for z in 0..CHUNK_SIZE {
-                    let mut should_render = false;
                     for side in 0..6 {
-                        let mut chunk = chunk;
-                        let mut nx = x + ADJACENCY[side][0];
-                        let mut ny = y + ADJACENCY[side][1];
-                        let mut nz = z + ADJACENCY[side][2];
-                        // TODO: Is there a cleaner/more efficient way to do this?
-                        // TODO: Maybe separating chunk sides and chunk interior could work?
-                        if nx == CHUNK_SIZE {
-                            nx -= CHUNK_SIZE;
-                            chunk = &adjacent_chunks[0];
-                        }
-                        if nx == -1 {
-                            nx += CHUNK_SIZE;
-                            chunk = &adjacent_chunks[1];
-                        }
-                        if ny == CHUNK_SIZE {
-                            ny -= CHUNK_SIZE;
-                            chunk = &adjacent_chunks[2];
+                        let coordinates = [x, y, z];
+                        if let Some(block_id) =
+                            visible_face(neighbourhood, block_registry, coordinates, side)
+                        {
+                            cube::generate_face(
+                                Vector3::new(x as f32, y as f32, z as f32),
+                                side,
+                                atlas.block_tile(block_id, side),
+                                face_ao(neighbourhood, block_registry, coordinates, side),
+                                face_colors(
+                                    neighbourhood,
+                                    block_registry,
+                                    block_id,
+                                    coordinates,
+                                    side,
+                                ),
+                                &mut output,
+                            );
                         }
-                        if ny == -1 {
-                            ny += CHUNK_SIZE;
-                            chunk = &adjacent_chunks[3];
+                    }
+                }
+            }
+        }
+        output
+    }
+
+    /// Id of the block at `coordinates` if its face on `side` is visible
+    fn visible_face(
+        neighbourhood: &ChunkNeighbourhood,
+        block_registry: &Registry<Block>,
+        coordinates: [isize; 3],
+        side: usize,
+    ) -> Option<BlockId> {
+        let [x, y, z] = coordinates;
+        let block_id = neighbourhood.get(x, y, z);
+        if block_registry[block_id].textures.is_none() {
+            return None;
+        }
+        let [dx, dy, dz] = ADJACENCY[side];
+        let adjacent_id = neighbourhood.get(x + dx, y + dy, z + dz);
+        // Faces between two blocks of the same transparent kind, like glass, are hidden
+        if adjacent_id != block_id && block_registry[adjacent_id].transparent {
+            Some(block_id)
+        } else {
+            None
+        }
+    }
+
+    /// Ambient occlusion level of the corners of a block face, in the order of
+    /// `cube::corner_index`. It depends on the blocks in front of the face that touch the corner:
+    /// the two sides and the corner between them.
+    fn face_ao(
+        neighbourhood: &ChunkNeighbourhood,
+        block_registry: &Registry<Block>,
+        coordinates: [isize; 3],
+        side: usize,
+    ) -> [u8; 4] {
+        let [a, b] = TEXTURE_AXES[side];
+        let mut front = coordinates;
+        for (c, offset) in front.iter_mut().zip(ADJACENCY[side].iter()) {
+            *c += offset;
+        }
+        let solid = |da: isize, db: isize| {
+            let mut pos = front;
+            pos[a] += da;
+            pos[b] += db;
+            !neighbourhood.is_transparent(block_registry, pos)
+        };
+
+        let mut ao = [0; 4];
+        for (corner, level) in ao.iter_mut().enumerate() {
+            let da = 2 * (corner % 2) as isize - 1;
+            let db = 2 * (corner / 2) as isize - 1;
+            let (side_a, side_b) = (solid(da, 0), solid(0, db));
+            *level = if side_a && side_b {
+                // The corner block can't make it any darker
+                0
+            } else {
+                MAX_AO - side_a as u8 - side_b as u8 - solid(da, db) as u8
+            };
+        }
+        ao
+    }
+
+    /// Color of the light at the corners of a block face, in the order of `cube::corner_index`.
+    /// The light levels are the average of the four blocks in front of the face that touch the
+    /// corner. Opaque blocks, which are dark, count as the block right in front of the face
+    /// instead, as does the corner block when it is hidden behind both sides.
+    fn face_light(
+        neighbourhood: &ChunkNeighbourhood,
+        block_registry: &Registry<Block>,
+        coordinates: [isize; 3],
+        side: usize,
+    ) -> [[f32; 3]; 4] {
+        let [a, b] = TEXTURE_AXES[side];
+        let mut front = coordinates;
+        for (c, offset) in front.iter_mut().zip(ADJACENCY[side].iter()) {
+            *c += offset;
+        }
+        let offset = |da: isize, db: isize| {
+            let mut pos = front;
+            pos[a] += da;
+            pos[b] += db;
+            pos
+        };
+
+        let mut colors = [[0.0; 3]; 4];
+        for (corner, color) in colors.iter_mut().enumerate() {
+            let da = 2 * (corner % 2) as isize - 1;
+            let db = 2 * (corner / 2) as isize - 1;
+            let (side_a, side_b) = (offset(da, 0), offset(0, db));
+            let open_a = neighbourhood.is_transparent(block_registry, side_a);
+            let open_b = neighbourhood.is_transparent(block_registry, side_b);
+            let diagonal = offset(da, db);
+            let samples = [
+                front,
+                if open_a { side_a } else { front },
+                if open_b { side_b } else { front },
+                if (open_a || open_b) && neighbourhood.is_transparent(block_registry, diagonal) {
+                    diagonal
+                } else {
+                    front
+                },
+            ];
+            let level = |channel| {
+                let sum: u8 = samples
+                    .iter()
+                    .map(|pos| neighbourhood.light(channel, *pos))
+                    .sum();
+                sum as f32 / samples.len() as f32
+            };
+            *color = light_color(level(LightChannel::Sky), level(LightChannel::Block));
+        }
+        colors
+    }
+
+    /// Colors of the corners of a block face: the color of the light, multiplied by the grass
+    /// tint on the top of the tinted blocks. The components are rounded to 8 bits, so that they
+    /// can be packed.
+    fn face_colors(
+        neighbourhood: &ChunkNeighbourhood,
+        block_registry: &Registry<Block>,
+        block_id: BlockId,
+        coordinates: [isize; 3],
+        side: usize,
+    ) -> [[f32; 3]; 4] {
+        let mut colors = face_light(neighbourhood, block_registry, coordinates, side);
+        // The top face, see `cube::NORMALS`
+        if side == 2 && block_registry[block_id].tinted {
+            let tint = neighbourhood.grass_tint(coordinates[0], coordinates[2]);
+            for color in colors.iter_mut() {
+                for (c, t) in color.iter_mut().zip(tint.iter()) {
+                    *c = (*c * t * 255.0).round() / 255.0;
+                }
+            }
+        }
+        colors
+    }
+
+    /// Brightness of the darkest light level, so that unlit blocks can still be seen
+    const MIN_BRIGHTNESS: f32 = 0.05;
+
+    /// Color of the block light, warmer than the sky light
+    const BLOCK_LIGHT_COLOR: [f32; 3] = [1.0, 0.85, 0.6];
+
+    /// Color of the light of the given sky and block light levels, as multipliers of the texture
+    /// colors. Every light level is 20% darker than the next one. The components are rounded to
+    /// 8 bits, so that they can be packed.
+    fn light_color(sky: f32, block: f32) -> [f32; 3] {
+        let brightness = |level: f32| {
+            MIN_BRIGHTNESS + (1.0 - MIN_BRIGHTNESS) * 0.8f32.powf(f32::from(MAX_LIGHT) - level)
+        };
+        let (sky, block) = (brightness(sky), brightness(block));
+        let mut color = [0.0; 3];
+        for (c, block_color) in color.iter_mut().zip(BLOCK_LIGHT_COLOR.iter()) {
+            *c = (sky.max(block * block_color) * 255.0).round() / 255.0;
+        }
+        color
+    }
+
+    fn generate_chunk_greedy(
+        neighbourhood: &ChunkNeighbourhood,
+        block_registry: &Registry<Block>,
+        atlas: &TextureAtlas,
+    ) -> Vec<ChunkVertex> {
+        const SIZE: usize = CHUNK_SIZE as usize;
+
+        let mut output = Vec::new();
+        let mut mask = [[None; SIZE]; SIZE];
+        for side in 0..6 {
+            // The face is perpendicular to axis `d`, and spans axes `u` and `v`
+            let d = side / 2;
+            let u = (d + 1) % 3;
+            let v = (d + 2) % 3;
+            for layer in 0..CHUNK_SIZE {
+                for a in 0..SIZE {
+                    for b in 0..SIZE {
+                        let mut coordinates = [0; 3];
+                        coordinates[d] = layer;
+                        coordinates[u] = a as isize;
+                        coordinates[v] = b as isize;
+                        mask[a][b] = visible_face(neighbourhood, block_registry, coordinates, side)
+                            .map(|block_id| {
+                                let ao = face_ao(neighbourhood, block_registry, coordinates, side);
+                                let colors = face_colors(
+                                    neighbourhood,
+                                    block_registry,
+                                    block_id,
+                                    coordinates,
+                                    side,
+                                );
+                                (block_id, ao, colors)
+                            });
+                    }
+                }
+
+                for a in 0..SIZE {
+                    let mut b = 0;
+                    while b < SIZE {
+                        let face = match mask[a][b] {
+                            Some(face) => face,
+                            None => {
+                                b += 1;
+                                continue;
+                            }
+                        };
+                        // Faces with an occlusion or light gradient are not merged, since the
+                        // gradient would be stretched over the whole quad
+                        let (block_id, ao, colors) = face;
+                        let mergeable = ao.iter().all(|level| *level == ao[0])
+                            && colors.iter().all(|color| *color == colors[0]);
+                        // Grow the quad along v, then along u as long as the whole row matches
+                        let mut height = 1;
+                        while mergeable && b + height < SIZE && mask[a][b + height] == Some(face) {
+                            height += 1;
                         }
-                        if nz == CHUNK_SIZE {
-                            nz -= CHUNK_SIZE;
-                            chunk = &adjacent_chunks[4];
+                        let mut width = 1;
+                        while mergeable
+                            && a + width < SIZE
+                            && mask[a + width][b..b + height]
+                                .iter()
+                                .all(|other| *other == Some(face))
+                        {
+                            width += 1;
                         }
-                        if nz == -1 {
-                            nz += CHUNK_SIZE;
-                            chunk = &adjacent_chunks[5];
+                        for row in mask[a..a + width].iter_mut() {
+                            for face in row[b..b + height].iter_mut() {
+                                *face = None;
+                            }
                         }
-                        // TODO: if the cast makes the build slow then use unsafe
-                        let block_id = chunk.blocks[nx as usize][ny as usize][nz as usize];
-                        let block = block_registry.get_item(block_id);
-                        should_render = should_render | block.air;
-                    }
-                    let block_id = chunk.blocks[x as usize][y as usize][z as usize];
-                    let block = block_registry.get_item(block_id);
-                    if !block.air && should_render {
-                        super::cube::generate_cube(
-                            Vector3::new(x as f32, y as f32, z as f32),
-                            &mut output,
-                        );
+
+                        let mut offset = Vector3::new(0.0, 0.0, 0.0);
+                        offset[d] = layer as f32;
+                        offset[u] = a as f32;
+                        offset[v] = b as f32;
+                        let mut size = Vector3::new(1.0, 1.0, 1.0);
+                        size[u] = width as f32;
+                        size[v] = height as f32;
+                        let tile = atlas.block_tile(block_id, side);
+                        cube::generate_quad(offset, side, size, tile, ao, colors, &mut output);
+                        b += height;
                     }
                 }
             }
@@ -149,3 +518,585 @@ pub mod chunk {
         output
     }
 }
+
+/// Compact chunk mesh formats
+pub mod buffer {
+    use super::cube::MAX_AO;
+    use amethyst::core::nalgebra::{Vector2, Vector3};
+    use std::collections::HashMap;
+
+    /// A vertex of a chunk mesh
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub struct ChunkVertex {
+        pub position: Vector3<f32>,
+        pub normal: Vector3<f32>,
+        /// Texture coordinates in blocks, the tile is repeated once per block
+        pub tex_coord: Vector2<f32>,
+        /// Tile of the texture atlas
+        pub tile: u16,
+        /// Ambient occlusion, from 0 (fully occluded) to 1 (not occluded)
+        pub ao: f32,
+        /// Multipliers of the texture colors, the color of the light times the grass tint of the
+        /// tinted blocks
+        pub color: [f32; 3],
+    }
+
+    /// Index buffer of a mesh, using `u16` indices when there are few enough vertices
+    #[derive(Clone, Debug, PartialEq)]
+    pub enum Indices {
+        U16(Vec<u16>),
+        U32(Vec<u32>),
+    }
+
+    impl Indices {
+        /// Use the smallest index type able to address `vertex_count` vertices
+        pub fn new(indices: Vec<u32>, vertex_count: usize) -> Self {
+            if vertex_count <= u16::max_value() as usize + 1 {
+                Indices::U16(indices.into_iter().map(|i| i as u16).collect())
+            } else {
+                Indices::U32(indices)
+            }
+        }
+
+        pub fn len(&self) -> usize {
+            match self {
+                Indices::U16(indices) => indices.len(),
+                Indices::U32(indices) => indices.len(),
+            }
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.len() == 0
+        }
+
+        #[cfg(test)]
+        pub fn get(&self, i: usize) -> usize {
+            match self {
+                Indices::U16(indices) => indices[i] as usize,
+                Indices::U32(indices) => indices[i] as usize,
+            }
+        }
+    }
+
+    /// A triangle list stored as deduplicated vertices and an index buffer
+    #[derive(Clone, Debug)]
+    pub struct IndexedMesh<V> {
+        pub vertices: Vec<V>,
+        pub indices: Indices,
+    }
+
+    impl<V: Clone> IndexedMesh<V> {
+        pub fn is_empty(&self) -> bool {
+            self.indices.is_empty()
+        }
+
+        /// Expand the mesh back to a plain triangle list
+        #[cfg(test)]
+        pub fn to_triangle_list(&self) -> Vec<V> {
+            (0..self.indices.len())
+                .map(|i| self.vertices[self.indices.get(i)].clone())
+                .collect()
+        }
+    }
+
+    impl IndexedMesh<ChunkVertex> {
+        /// Deduplicate the vertices of a triangle list. Vertices are merged only if they are
+        /// bitwise identical.
+        pub fn from_triangle_list(triangles: &[ChunkVertex]) -> Self {
+            let mut vertices = Vec::new();
+            let mut indices = Vec::with_capacity(triangles.len());
+            let mut vertex_indices: HashMap<[u32; 13], u32> = HashMap::new();
+            for vertex in triangles {
+                let key = [
+                    vertex.position[0].to_bits(),
+                    vertex.position[1].to_bits(),
+                    vertex.position[2].to_bits(),
+                    vertex.normal[0].to_bits(),
+                    vertex.normal[1].to_bits(),
+                    vertex.normal[2].to_bits(),
+                    vertex.tex_coord[0].to_bits(),
+                    vertex.tex_coord[1].to_bits(),
+                    vertex.tile as u32,
+                    vertex.ao.to_bits(),
+                    vertex.color[0].to_bits(),
+                    vertex.color[1].to_bits(),
+                    vertex.color[2].to_bits(),
+                ];
+                let index = *vertex_indices.entry(key).or_insert_with(|| {
+                    vertices.push(*vertex);
+                    (vertices.len() - 1) as u32
+                });
+                indices.push(index);
+            }
+            let indices = Indices::new(indices, vertices.len());
+            Self { vertices, indices }
+        }
+
+        /// Convert the mesh to the packed vertex layout, or return `None` if a vertex can't be
+        /// represented exactly.
+        pub fn pack(&self) -> Option<IndexedMesh<PackedVertex>> {
+            let vertices = self
+                .vertices
+                .iter()
+                .map(PackedVertex::pack)
+                .collect::<Option<Vec<_>>>()?;
+            Some(IndexedMesh {
+                vertices,
+                indices: self.indices.clone(),
+            })
+        }
+    }
+
+    #[cfg(test)]
+    impl IndexedMesh<PackedVertex> {
+        pub fn unpack(&self) -> IndexedMesh<ChunkVertex> {
+            IndexedMesh {
+                vertices: self.vertices.iter().map(PackedVertex::unpack).collect(),
+                indices: self.indices.clone(),
+            }
+        }
+    }
+
+    /// A chunk mesh vertex packed in 12 bytes instead of 52. Chunk meshes only have block-aligned
+    /// positions, axis-aligned normals, whole texture coordinates and 8-bit colors, so they fit
+    /// in bytes.
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+    pub struct PackedVertex {
+        /// Position relative to the chunk origin, from 0 to `CHUNK_SIZE` included
+        pub position: [u8; 3],
+        /// Index of the face in `cube::NORMALS` in the low 3 bits, then the ambient occlusion
+        /// level in the next 2 bits
+        pub face_ao: u8,
+        /// Texture coordinates, in blocks
+        pub tex_coord: [u8; 2],
+        /// Index of the texture in the block texture atlas
+        pub tile: u16,
+        /// Multipliers of the texture colors, from 0 to 255 for multipliers from 0 to 1
+        pub color: [u8; 3],
+        /// Always 0, so that no byte of the vertex is left uninitialized when it is uploaded
+        padding: u8,
+    }
+
+    impl PackedVertex {
+        fn pack(vertex: &ChunkVertex) -> Option<Self> {
+            fn to_byte(c: f32) -> Option<u8> {
+                if c >= 0.0 && c <= 255.0 && c.fract() == 0.0 {
+                    Some(c as u8)
+                } else {
+                    None
+                }
+            }
+            fn to_unorm(c: f32) -> Option<u8> {
+                let byte = (c * 255.0).round();
+                if (0.0..=255.0).contains(&byte) && byte / 255.0 == c {
+                    Some(byte as u8)
+                } else {
+                    None
+                }
+            }
+            let face = super::cube::NORMALS
+                .iter()
+                .position(|n| Vector3::from(*n) == vertex.normal)?;
+            let ao = (vertex.ao * MAX_AO as f32).round() as u8;
+            Some(Self {
+                position: [
+                    to_byte(vertex.position[0])?,
+                    to_byte(vertex.position[1])?,
+                    to_byte(vertex.position[2])?,
+                ],
+                face_ao: face as u8 | ao << 3,
+                tex_coord: [to_byte(vertex.tex_coord[0])?, to_byte(vertex.tex_coord[1])?],
+                tile: vertex.tile,
+                color: [
+                    to_unorm(vertex.color[0])?,
+                    to_unorm(vertex.color[1])?,
+                    to_unorm(vertex.color[2])?,
+                ],
+                padding: 0,
+            })
+        }
+
+        #[cfg(test)]
+        pub fn unpack(&self) -> ChunkVertex {
+            ChunkVertex {
+                position: Vector3::new(
+                    self.position[0] as f32,
+                    self.position[1] as f32,
+                    self.position[2] as f32,
+                ),
+                normal: Vector3::from(super::cube::NORMALS[(self.face_ao & 0b111) as usize]),
+                tex_coord: Vector2::new(self.tex_coord[0] as f32, self.tex_coord[1] as f32),
+                tile: self.tile,
+                ao: (self.face_ao >> 3) as f32 / MAX_AO as f32,
+                color: [
+                    f32::from(self.color[0]) / 255.0,
+                    f32::from(self.color[1]) / 255.0,
+                    f32::from(self.color[2]) / 255.0,
+                ],
+            }
+        }
+    }
+
+    /// Vertex layout of the chunk meshes built by the workers
+    #[derive(PartialEq, Eq, Debug, Clone, Copy)]
+    pub enum VertexLayout {
+        /// Full `ChunkVertex` vertices
+        Full,
+        /// `PackedVertex`, a quarter of the size
+        Packed,
+    }
+
+    /// An indexed chunk mesh in either vertex layout
+    #[derive(Clone, Debug)]
+    pub enum ChunkMesh {
+        Full(IndexedMesh<ChunkVertex>),
+        Packed(IndexedMesh<PackedVertex>),
+    }
+
+    impl ChunkMesh {
+        /// Index a triangle list, falling back to full vertices if it can't be packed
+        pub fn new(triangles: &[ChunkVertex], layout: VertexLayout) -> Self {
+            let mesh = IndexedMesh::from_triangle_list(triangles);
+            match layout {
+                VertexLayout::Packed => match mesh.pack() {
+                    Some(packed) => ChunkMesh::Packed(packed),
+                    None => ChunkMesh::Full(mesh),
+                },
+                VertexLayout::Full => ChunkMesh::Full(mesh),
+            }
+        }
+
+        pub fn is_empty(&self) -> bool {
+            match self {
+                ChunkMesh::Full(mesh) => mesh.is_empty(),
+                ChunkMesh::Packed(mesh) => mesh.is_empty(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        buffer::{ChunkMesh, ChunkVertex, Indices, VertexLayout},
+        chunk::{generate_chunk, ChunkNeighbourhood, MeshingMode},
+    };
+    use crate::{
+        atlas::TextureAtlas,
+        light::light_chunk,
+        world::{
+            test_blocks::{block_registry, AIR, DIRT, GRASS, STONE},
+            Chunk, ColumnPos, CHUNK_SIZE,
+        },
+        worldgen::{BiomeConfig, BiomeSource, ClimateConfig},
+    };
+    use amethyst::core::nalgebra::Vector2;
+    use image::{Rgba, RgbaImage};
+
+    fn texture_atlas() -> TextureAtlas {
+        let (atlas, _) = TextureAtlas::build(&block_registry(), |_| {
+            Ok(RgbaImage::from_pixel(1, 1, Rgba([0, 0, 0, 255])))
+        })
+        .unwrap();
+        atlas
+    }
+
+    /// Total area of the triangles of a mesh, for every face direction. Also checks that every
+    /// triangle faces the direction of its normal.
+    fn area_by_normal(mesh: &[ChunkVertex]) -> [f32; 6] {
+        let mut areas = [0.0; 6];
+        for triangle in mesh.chunks(3) {
+            let normal = triangle[0].normal;
+            let axis = (0..3).find(|i| normal[*i] != 0.0).unwrap();
+            let side = 2 * axis + if normal[axis] > 0.0 { 0 } else { 1 };
+            let ab = triangle[1].position - triangle[0].position;
+            let ac = triangle[2].position - triangle[0].position;
+            let cross = ab.cross(&ac);
+            assert!(
+                cross.dot(&normal) > 0.0,
+                "Wrong winding order: {:?}",
+                triangle
+            );
+            areas[side] += cross.norm() / 2.0;
+        }
+        areas
+    }
+
+    fn assert_same_area(neighbourhood: &ChunkNeighbourhood) -> (usize, usize) {
+        let block_registry = block_registry();
+        let atlas = texture_atlas();
+        let naive = generate_chunk(neighbourhood, &block_registry, &atlas, MeshingMode::Naive);
+        let greedy = generate_chunk(neighbourhood, &block_registry, &atlas, MeshingMode::Greedy);
+        let naive_areas = area_by_normal(&naive);
+        let greedy_areas = area_by_normal(&greedy);
+        for side in 0..6 {
+            assert!(
+                (naive_areas[side] - greedy_areas[side]).abs() < 1e-3,
+                "Different areas on side {}: {} (naive) and {} (greedy)",
+                side,
+                naive_areas[side],
+                greedy_areas[side],
+            );
+        }
+        (naive.len(), greedy.len())
+    }
+
+    #[test]
+    fn flat_plain() {
+        let mut chunk = Chunk::filled(AIR);
+        for x in 0..CHUNK_SIZE {
+            for y in 0..16 {
+                for z in 0..CHUNK_SIZE {
+                    chunk.set(x, y, z, DIRT);
+                }
+            }
+        }
+        let air = Chunk::filled(AIR);
+        let dirt = Chunk::filled(DIRT);
+        let neighbourhood =
+            ChunkNeighbourhood::from_fn(&chunk, |offset| if offset[1] < 0 { &dirt } else { &air });
+        let (naive, greedy) = assert_same_area(&neighbourhood);
+        assert_eq!(naive, 6 * (CHUNK_SIZE * CHUNK_SIZE + 4 * 16 * CHUNK_SIZE));
+        // The top is a single quad. On every side, the bottom row is occluded by the ground of
+        // the neighbouring chunk so its faces are not merged, and the rest is a single quad.
+        assert_eq!(greedy, 6 * (1 + 4 * (CHUNK_SIZE + 1)));
+    }
+
+    #[test]
+    fn mixed_blocks() {
+        let mut chunk = Chunk::filled(AIR);
+        let mut seed: u32 = 12345;
+        for x in 0..CHUNK_SIZE {
+            for y in 0..CHUNK_SIZE {
+                for z in 0..CHUNK_SIZE {
+                    seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                    // Blocks are more likely to be solid near the bottom of the chunk
+                    let block_id = if (seed >> 16) as usize % CHUNK_SIZE < y {
+                        AIR
+                    } else if (seed >> 8) % 2 == 0 {
+                        DIRT
+                    } else {
+                        STONE
+                    };
+                    chunk.set(x, y, z, block_id);
+                }
+            }
+        }
+        let air = Chunk::filled(AIR);
+        let stone = Chunk::filled(STONE);
+        let neighbourhood = ChunkNeighbourhood::from_fn(&chunk, |offset| {
+            if offset.iter().any(|c| *c < 0) {
+                &stone
+            } else {
+                &air
+            }
+        });
+        let (naive, greedy) = assert_same_area(&neighbourhood);
+        assert!(greedy < naive);
+    }
+
+    #[test]
+    fn greedy_texture_coordinates_tile() {
+        let mut chunk = Chunk::filled(AIR);
+        for x in 0..4 {
+            for z in 0..3 {
+                chunk.set(x, 0, z, DIRT);
+            }
+        }
+        let stone = Chunk::filled(STONE);
+        let air = Chunk::filled(AIR);
+        let neighbourhood =
+            ChunkNeighbourhood::from_fn(&chunk, |offset| if offset[1] < 0 { &stone } else { &air });
+        let mesh = generate_chunk(
+            &neighbourhood,
+            &block_registry(),
+            &texture_atlas(),
+            MeshingMode::Greedy,
+        );
+        let top: Vec<&ChunkVertex> = mesh.iter().filter(|v| v.normal[1] > 0.0).collect();
+        assert_eq!(top.len(), 6);
+        // The texture is repeated once per block in both directions
+        for vertex in top {
+            let expected_u = if vertex.position[2] == 0.0 { 0.0 } else { 3.0 };
+            let expected_v = if vertex.position[0] == 0.0 { 0.0 } else { 4.0 };
+            assert_eq!(vertex.tex_coord[0], expected_u);
+            assert_eq!(vertex.tex_coord[1], expected_v);
+        }
+    }
+
+    #[test]
+    fn ambient_occlusion_inner_corner() {
+        let mut chunk = Chunk::filled(AIR);
+        for x in 0..CHUNK_SIZE {
+            for z in 0..CHUNK_SIZE {
+                chunk.set(x, 0, z, DIRT);
+            }
+        }
+        chunk.set(5, 1, 4, DIRT);
+        chunk.set(4, 1, 5, DIRT);
+        let air = Chunk::filled(AIR);
+        let dirt = Chunk::filled(DIRT);
+        let neighbourhood =
+            ChunkNeighbourhood::from_fn(&chunk, |offset| if offset[1] < 0 { &dirt } else { &air });
+        let mesh = generate_chunk(
+            &neighbourhood,
+            &block_registry(),
+            &texture_atlas(),
+            MeshingMode::Naive,
+        );
+
+        // Top face of the floor block at (4, 0, 4), in the corner between the two blocks
+        let in_face = |v: &ChunkVertex| {
+            v.normal[1] > 0.0
+                && v.position[1] == 1.0
+                && (4.0..=5.0).contains(&v.position[0])
+                && (4.0..=5.0).contains(&v.position[2])
+        };
+        let triangles: Vec<&[ChunkVertex]> = mesh
+            .chunks(3)
+            .filter(|triangle| triangle.iter().all(in_face))
+            .collect();
+        assert_eq!(triangles.len(), 2);
+        for triangle in triangles.iter() {
+            for vertex in triangle.iter() {
+                let expected = match (vertex.position[0] as usize, vertex.position[2] as usize) {
+                    (4, 4) => 1.0,
+                    (5, 5) => 0.0,
+                    _ => 2.0 / 3.0,
+                };
+                assert_eq!(vertex.ao, expected);
+            }
+            // The quad is split along the diagonal that doesn't touch the dark corner
+            let on_diagonal = |x: f32, z: f32| {
+                triangle
+                    .iter()
+                    .any(|v| v.position[0] == x && v.position[2] == z)
+            };
+            assert!(on_diagonal(5.0, 4.0) && on_diagonal(4.0, 5.0));
+        }
+    }
+
+    #[test]
+    fn light_is_baked() {
+        let block_registry = block_registry();
+        // A floor with a roof over half of it
+        let mut chunk = Chunk::filled(AIR);
+        for x in 0..CHUNK_SIZE {
+            for z in 0..CHUNK_SIZE {
+                chunk.set(x, 0, z, DIRT);
+                if x < CHUNK_SIZE / 2 {
+                    chunk.set(x, 8, z, STONE);
+                }
+            }
+        }
+        let mut air = Chunk::filled(AIR);
+        let mut dirt = Chunk::filled(DIRT);
+        for chunk in [&mut chunk, &mut air, &mut dirt].iter_mut() {
+            light_chunk(chunk, &block_registry);
+        }
+        let neighbourhood =
+            ChunkNeighbourhood::from_fn(&chunk, |offset| if offset[1] < 0 { &dirt } else { &air });
+        let mesh = generate_chunk(
+            &neighbourhood,
+            &block_registry,
+            &texture_atlas(),
+            MeshingMode::Naive,
+        );
+
+        let floor = mesh
+            .iter()
+            .filter(|v| v.normal[1] > 0.0 && v.position[1] == 1.0);
+        let (mut sunlit, mut shaded) = (0, 0);
+        for vertex in floor {
+            if vertex.position[0] >= 20.0 {
+                assert_eq!(vertex.color, [1.0, 1.0, 1.0]);
+                sunlit += 1;
+            } else if (4.0..=8.0).contains(&vertex.position[0]) {
+                // Lit from the sides of the roof only, by the grey sky light
+                assert!(vertex.color[0] < 0.5);
+                assert!(vertex.color.iter().all(|c| *c == vertex.color[0]));
+                shaded += 1;
+            }
+        }
+        assert!(sunlit > 0 && shaded > 0);
+    }
+
+    #[test]
+    fn grass_is_tinted() {
+        let block_registry = block_registry();
+        let mut chunk = Chunk::filled(AIR);
+        for x in 0..CHUNK_SIZE {
+            for z in 0..CHUNK_SIZE {
+                chunk.set(x, 0, z, if x < 2 { GRASS } else { DIRT });
+            }
+        }
+        let mut air = Chunk::filled(AIR);
+        light_chunk(&mut chunk, &block_registry);
+        light_chunk(&mut air, &block_registry);
+        // A single biome, so that every column has the same tint
+        let biome = BiomeConfig {
+            grass_tint: [0.4, 0.8, 0.2],
+            ..BiomeConfig::defaults().remove(0)
+        };
+        let biome_source =
+            BiomeSource::new(1, &ClimateConfig::default(), &[biome], &block_registry).unwrap();
+        let biome_map = biome_source.biome_map(&ColumnPos(Vector2::new(0, 0)));
+        let neighbourhood =
+            ChunkNeighbourhood::from_fn(&chunk, |_| &air).with_biome_map(&biome_map);
+        let mesh = generate_chunk(
+            &neighbourhood,
+            &block_registry,
+            &texture_atlas(),
+            MeshingMode::Greedy,
+        );
+
+        let tint = [102.0 / 255.0, 204.0 / 255.0, 51.0 / 255.0];
+        let (mut tinted, mut untinted) = (0, 0);
+        for vertex in mesh.iter().filter(|v| v.normal[1] > 0.0) {
+            // Only the grass is tinted, the dirt keeps the color of the light
+            if vertex.position[0] < 2.0 {
+                assert_eq!(vertex.color, tint);
+                tinted += 1;
+            } else if vertex.position[0] > 2.0 {
+                assert_eq!(vertex.color, [1.0, 1.0, 1.0]);
+                untinted += 1;
+            }
+        }
+        assert!(tinted > 0 && untinted > 0);
+    }
+
+    #[test]
+    fn indexed_packed_round_trip() {
+        let mut chunk = Chunk::filled(AIR);
+        for x in 0..CHUNK_SIZE {
+            for z in 0..CHUNK_SIZE {
+                for y in 0..(x + z) % 5 {
+                    chunk.set(x, y, z, DIRT);
+                }
+            }
+        }
+        let air = Chunk::filled(AIR);
+        let dirt = Chunk::filled(DIRT);
+        let neighbourhood =
+            ChunkNeighbourhood::from_fn(&chunk, |offset| if offset[1] < 0 { &dirt } else { &air });
+        let triangles = generate_chunk(
+            &neighbourhood,
+            &block_registry(),
+            &texture_atlas(),
+            MeshingMode::Naive,
+        );
+        let mesh = match ChunkMesh::new(&triangles, VertexLayout::Packed) {
+            ChunkMesh::Packed(mesh) => mesh,
+            ChunkMesh::Full(_) => panic!("Chunk mesh could not be packed"),
+        };
+        // Every quad shares 2 of its 6 vertices
+        assert_eq!(mesh.vertices.len(), triangles.len() / 6 * 4);
+        match mesh.indices {
+            Indices::U16(_) => (),
+            Indices::U32(_) => panic!("Small mesh uses 32-bit indices"),
+        }
+        let unpacked = mesh.unpack().to_triangle_list();
+        assert_eq!(unpacked, triangles);
+    }
+}
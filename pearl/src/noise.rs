@@ -0,0 +1,212 @@
+//! Seeded gradient noise used by the world generation.
+//!
+//! The noise is implemented here rather than taken from a crate so that a given seed always
+//! generates the same world, whatever the dependency versions.
+
+/// Small deterministic random number generator (SplitMix64), used to derive noise permutations
+/// and other seeded values.
+#[derive(Clone, Debug)]
+pub struct SplitMix64(u64);
+
+impl SplitMix64 {
+    pub fn new(seed: u64) -> Self {
+        SplitMix64(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Random number between 0 and 1, 1 excluded
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Generator for a position, so that every position gets its own random numbers
+    pub fn for_position(seed: u64, x: isize, y: isize, z: isize) -> Self {
+        let mut rng = SplitMix64::new(
+            seed ^ (x as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15)
+                ^ (y as u64).wrapping_mul(0xC2B2_AE3D_27D4_EB4F)
+                ^ (z as u64).wrapping_mul(0x1656_67B1_9E37_79F9),
+        );
+        rng.next_u64();
+        rng
+    }
+}
+
+/// Gradients of the 2D noise, the 4 axes and the 4 diagonals
+const GRADIENTS_2: [(f64, f64); 8] = [
+    (1.0, 0.0),
+    (-1.0, 0.0),
+    (0.0, 1.0),
+    (0.0, -1.0),
+    (1.0, 1.0),
+    (-1.0, 1.0),
+    (1.0, -1.0),
+    (-1.0, -1.0),
+];
+
+/// Gradients of the 3D noise, the middles of the 12 edges of a cube
+const GRADIENTS_3: [(f64, f64, f64); 12] = [
+    (1.0, 1.0, 0.0),
+    (-1.0, 1.0, 0.0),
+    (1.0, -1.0, 0.0),
+    (-1.0, -1.0, 0.0),
+    (1.0, 0.0, 1.0),
+    (-1.0, 0.0, 1.0),
+    (1.0, 0.0, -1.0),
+    (-1.0, 0.0, -1.0),
+    (0.0, 1.0, 1.0),
+    (0.0, -1.0, 1.0),
+    (0.0, 1.0, -1.0),
+    (0.0, -1.0, -1.0),
+];
+
+/// Perlin gradient noise. Values are roughly between -1 and 1, and are 0 at integer coordinates.
+#[derive(Clone)]
+pub struct Noise {
+    /// A permutation of 0..256, repeated twice to avoid wrapping indices
+    permutation: Vec<u8>,
+}
+
+impl Noise {
+    pub fn new(seed: u64) -> Self {
+        let mut permutation: Vec<u8> = (0..=255).collect();
+        let mut rng = SplitMix64::new(seed);
+        for i in (1..256).rev() {
+            let j = (rng.next_u64() % (i as u64 + 1)) as usize;
+            permutation.swap(i, j);
+        }
+        let repeated = permutation.clone();
+        permutation.extend(repeated);
+        Self { permutation }
+    }
+
+    fn hash(&self, x: i64, y: i64) -> usize {
+        let p = &self.permutation;
+        p[p[(x & 255) as usize] as usize + (y & 255) as usize] as usize
+    }
+
+    fn hash3(&self, x: i64, y: i64, z: i64) -> usize {
+        let p = &self.permutation;
+        p[self.hash(x, y) + (z & 255) as usize] as usize
+    }
+
+    /// Noise value at a 2D position
+    pub fn get2(&self, x: f64, y: f64) -> f64 {
+        let (x0, y0) = (x.floor(), y.floor());
+        let (dx, dy) = (x - x0, y - y0);
+        let (xi, yi) = (x0 as i64, y0 as i64);
+        let gradient = |cx: i64, cy: i64, dx: f64, dy: f64| {
+            let (gx, gy) = GRADIENTS_2[self.hash(xi + cx, yi + cy) & 7];
+            gx * dx + gy * dy
+        };
+        let (u, v) = (fade(dx), fade(dy));
+        lerp(
+            v,
+            lerp(u, gradient(0, 0, dx, dy), gradient(1, 0, dx - 1.0, dy)),
+            lerp(
+                u,
+                gradient(0, 1, dx, dy - 1.0),
+                gradient(1, 1, dx - 1.0, dy - 1.0),
+            ),
+        )
+    }
+
+    /// Noise value at a 3D position
+    pub fn get3(&self, x: f64, y: f64, z: f64) -> f64 {
+        let (x0, y0, z0) = (x.floor(), y.floor(), z.floor());
+        let (dx, dy, dz) = (x - x0, y - y0, z - z0);
+        let (xi, yi, zi) = (x0 as i64, y0 as i64, z0 as i64);
+        let gradient = |cx: i64, cy: i64, cz: i64| {
+            let (gx, gy, gz) = GRADIENTS_3[self.hash3(xi + cx, yi + cy, zi + cz) % 12];
+            gx * (dx - cx as f64) + gy * (dy - cy as f64) + gz * (dz - cz as f64)
+        };
+        let (u, v, w) = (fade(dx), fade(dy), fade(dz));
+        let layer = |cz| {
+            lerp(
+                v,
+                lerp(u, gradient(0, 0, cz), gradient(1, 0, cz)),
+                lerp(u, gradient(0, 1, cz), gradient(1, 1, cz)),
+            )
+        };
+        lerp(w, layer(0), layer(1))
+    }
+}
+
+/// Smoothstep used to interpolate between the lattice points
+fn fade(t: f64) -> f64 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp(t: f64, a: f64, b: f64) -> f64 {
+    a + t * (b - a)
+}
+
+/// Sum of several octaves of noise, each with a higher frequency and a lower amplitude than the
+/// previous one
+#[derive(Clone)]
+pub struct FractalNoise {
+    noise: Noise,
+    octaves: u32,
+    /// Frequency multiplier between two octaves
+    lacunarity: f64,
+    /// Amplitude multiplier between two octaves
+    persistence: f64,
+}
+
+impl FractalNoise {
+    pub fn new(seed: u64, octaves: u32, lacunarity: f64, persistence: f64) -> Self {
+        Self {
+            noise: Noise::new(seed),
+            octaves: octaves.max(1),
+            lacunarity,
+            persistence,
+        }
+    }
+
+    /// Noise value at a 2D position, normalized to stay roughly between -1 and 1
+    pub fn get2(&self, x: f64, y: f64) -> f64 {
+        let mut frequency = 1.0;
+        let mut amplitude = 1.0;
+        let mut sum = 0.0;
+        let mut total_amplitude = 0.0;
+        for octave in 0..self.octaves {
+            // Shift every octave so that they aren't all 0 at the origin
+            let shift = f64::from(octave) * 17.31;
+            sum += amplitude
+                * self
+                    .noise
+                    .get2(x * frequency + shift, y * frequency + shift);
+            total_amplitude += amplitude;
+            frequency *= self.lacunarity;
+            amplitude *= self.persistence;
+        }
+        sum / total_amplitude
+    }
+
+    /// Noise value at a 3D position, normalized to stay roughly between -1 and 1
+    pub fn get3(&self, x: f64, y: f64, z: f64) -> f64 {
+        let mut frequency = 1.0;
+        let mut amplitude = 1.0;
+        let mut sum = 0.0;
+        let mut total_amplitude = 0.0;
+        for octave in 0..self.octaves {
+            let shift = f64::from(octave) * 17.31;
+            sum += amplitude
+                * self.noise.get3(
+                    x * frequency + shift,
+                    y * frequency + shift,
+                    z * frequency + shift,
+                );
+            total_amplitude += amplitude;
+            frequency *= self.lacunarity;
+            amplitude *= self.persistence;
+        }
+        sum / total_amplitude
+    }
+}
@@ -1,76 +1,50 @@
 use amethyst::{
-    assets::{Loader, ProgressCounter},
-    core::{nalgebra::Vector3, Transform},
+    assets::Loader,
+    core::Transform,
     ecs::prelude::{Join, WriteStorage},
     input::is_close_requested,
     prelude::*,
-    renderer::{
-        AmbientColor, Camera, DirectionalLight, Light, Material, MaterialDefaults, MeshHandle,
-        PngFormat, Projection, TextureMetadata,
-    },
+    renderer::{Camera, Projection, TextureData, TextureMetadata},
+    utils::application_root_dir,
     winit::{Event, WindowEvent},
 };
 use exploration_camera::ExplorationControlTag;
+use log::error;
+use std::sync::Arc;
 
 use crate::{
+    atlas::{AtlasImage, TextureAtlas},
+    player::Player,
+    region::RegionStorage,
     registry::Registry,
-    world::{Block, Chunk, ChunkMap, ChunkPos, CHUNK_SIZE},
-    worldgen::ChunkGenerator,
+    render::ChunkTexture,
+    world::{Block, ChunkEntities, MeshedChunks, World as VoxelWorld},
+    worldgen::{BiomeMaps, WorldGenerator},
 };
 
 /// State representing the client game
-#[derive(Default)]
 pub struct Pearl {
-    chunk_material: Option<Material>,
-    chunk_generator: Option<ChunkGenerator>,
+    /// The blocks loaded from the block definitions, until they are added to the world
+    block_registry: Option<Registry<Block>>,
+    /// The generator of the new chunks, until it is added to the world
+    world_generator: Option<Arc<dyn WorldGenerator>>,
+    /// The atlas of the block textures and its pixels, until they are added to the world
+    texture_atlas: Option<(TextureAtlas, AtlasImage)>,
 }
 
 impl SimpleState for Pearl {
     fn on_start(&mut self, data: StateData<'_, GameData<'_, '_>>) {
-        use crate::mesh::chunk::ADJACENCY;
-
         let world = data.world;
 
         initialise_camera(world);
-        self.initialize_light(world);
         self.initialize_block_registry(world);
-        self.initialize_chunk_generator(world);
+        self.initialize_world_generator(world);
         self.initialize_chunk_texture(world);
-        let mut chunk_map = ChunkMap::new();
-        let chunk_generator = self.chunk_generator.as_mut().unwrap();
-        for i in -4..=4 {
-            for j in -4..=4 {
-                for k in -4..=4 {
-                    let pos = Vector3::new(i, j, k);
-                    let chunk = chunk_generator.generate_chunk(&pos);
-                    chunk_map.insert(ChunkPos(pos.clone()), chunk);
-                }
-            }
-        }
-        for i in -1..=1 {
-            for j in -1..=1 {
-                for k in -1..=1 {
-                    let adjacent_chunks: Vec<&Chunk> = (0..6)
-                        .map(|side| {
-                            chunk_map
-                                .get(&ChunkPos(Vector3::new(
-                                    i + ADJACENCY[side][0],
-                                    j + ADJACENCY[side][1],
-                                    k + ADJACENCY[side][2],
-                                )))
-                                .unwrap()
-                        })
-                        .collect();
-                    let pos = Vector3::new(i, j, k);
-                    self.initialize_chunk(
-                        world,
-                        chunk_map.get(&ChunkPos(pos)).unwrap(),
-                        &adjacent_chunks,
-                        &pos,
-                    );
-                }
-            }
-        }
+        self.initialize_voxel_world(world);
+    }
+
+    fn on_stop(&mut self, data: StateData<'_, GameData<'_, '_>>) {
+        self.save_chunks(data.world);
     }
 
     fn handle_event(
@@ -102,78 +76,80 @@ impl SimpleState for Pearl {
 }
 
 impl Pearl {
-    fn initialize_light(&mut self, world: &mut World) {
-        world
-            .create_entity()
-            .with(Transform::default())
-            .with(Light::Directional(DirectionalLight {
-                color: [0.1, 0.1, 0.1, 0.05].into(),
-                direction: [-1.0, -2.0, -3.0],
-            }))
-            .build();
-        world.add_resource(AmbientColor([0.4, 0.4, 0.4, 1.0].into()));
+    pub fn new(
+        block_registry: Registry<Block>,
+        world_generator: Arc<dyn WorldGenerator>,
+        texture_atlas: TextureAtlas,
+        atlas_image: AtlasImage,
+    ) -> Self {
+        Self {
+            block_registry: Some(block_registry),
+            world_generator: Some(world_generator),
+            texture_atlas: Some((texture_atlas, atlas_image)),
+        }
     }
 
     fn initialize_block_registry(&mut self, world: &mut World) {
-        let mut block_registry = Registry::<Block>::new();
-        block_registry.register("default:air", Block { air: true });
-        block_registry.register("default:dirt", Block { air: false });
+        let block_registry = self
+            .block_registry
+            .take()
+            .expect("The game was already started");
         world.add_resource(block_registry);
     }
 
-    fn initialize_chunk_generator(&mut self, world: &mut World) {
-        self.chunk_generator = Some(ChunkGenerator::new(&world.read_resource()));
+    fn initialize_world_generator(&mut self, world: &mut World) {
+        let world_generator = self
+            .world_generator
+            .take()
+            .expect("The game was already started");
+        world.add_resource(world_generator);
     }
 
+    /// Upload the atlas used by every chunk
     fn initialize_chunk_texture(&mut self, world: &mut World) {
-        let material_defaults = world.read_resource::<MaterialDefaults>().0.clone();
-        let texture_storage = world.read_resource();
-        let loader = world.read_resource::<Loader>();
-        let texture_handle = loader.load(
-            "assets/dirt.png",
-            PngFormat,
-            TextureMetadata::srgb_scale(),
-            (),
-            &texture_storage,
-        );
-        self.chunk_material = Some(Material {
-            albedo: texture_handle,
-            ..material_defaults
-        });
-    }
-
-    fn initialize_chunk(
-        &mut self,
-        world: &mut World,
-        chunk: &Chunk,
-        adjacent_chunks: &[&Chunk],
-        position: &Vector3<isize>,
-    ) {
-        let chunk_mesh: MeshHandle = {
-            let mesh_storage = world.read_resource();
+        let (texture_atlas, atlas_image) = self
+            .texture_atlas
+            .take()
+            .expect("The game was already started");
+        let texture_handle = {
+            let texture_storage = world.read_resource();
             let loader = world.read_resource::<Loader>();
-            let block_registry = world.read_resource();
-            let mesh_data =
-                crate::mesh::chunk::generate_chunk(chunk, adjacent_chunks, &block_registry);
-            if mesh_data.len() == 0 {
-                return;
-            }
-            let mut progress = ProgressCounter::new();
-            loader.load_from_data(mesh_data.into(), &mut progress, &mesh_storage)
+            let metadata = TextureMetadata::srgb_scale()
+                .with_size(atlas_image.width as u16, atlas_image.height as u16);
+            loader.load_from_data(
+                TextureData::U8(atlas_image.pixels, metadata),
+                (),
+                &texture_storage,
+            )
         };
+        world.add_resource(texture_atlas);
+        world.add_resource(ChunkTexture(texture_handle));
+    }
+
+    /// Create the empty world, its chunks are loaded by the `ChunkStreamingSystem`
+    fn initialize_voxel_world(&mut self, world: &mut World) {
+        let directory = format!("{}/saves/world", application_root_dir());
+        world.add_resource(RegionStorage::new(directory));
+        world.add_resource(VoxelWorld::new());
+        world.add_resource(BiomeMaps::default());
+        world.add_resource(ChunkEntities::default());
+        world.add_resource(MeshedChunks::default());
+    }
 
-        let mut transform = Transform::default();
-        transform.set_position(Vector3::from([
-            position[0] as f32 * CHUNK_SIZE as f32,
-            position[1] as f32 * CHUNK_SIZE as f32,
-            position[2] as f32 * CHUNK_SIZE as f32,
-        ]));
-        world
-            .create_entity()
-            .with(transform)
-            .with(chunk_mesh)
-            .with(self.chunk_material.clone().unwrap())
-            .build();
+    /// Save the chunks edited since they were loaded, the other ones are already saved or can be
+    /// generated again
+    fn save_chunks(&mut self, world: &mut World) {
+        let voxel_world = world.read_resource::<VoxelWorld>();
+        let block_registry = world.read_resource::<Registry<Block>>();
+        let mut region_storage = world.write_resource::<RegionStorage>();
+        for (pos, chunk) in voxel_world
+            .chunks()
+            .filter(|(_, chunk)| chunk.is_modified())
+        {
+            if let Err(e) = region_storage.save_chunk(pos, chunk, &block_registry) {
+                error!("Failed to save chunk {:?}: {}", pos, e);
+            }
+        }
     }
 
     fn update_camera_ratio(&mut self, world: &mut World, ratio: f32) {
@@ -193,5 +169,6 @@ fn initialise_camera(world: &mut World) {
         .with(Camera::from(Projection::perspective(1.0, 0.5)))
         .with(transform)
         .with(ExplorationControlTag::default())
+        .with(Player::default())
         .build();
 }
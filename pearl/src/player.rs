@@ -0,0 +1,180 @@
+//! Walking in the world, or flying through it.
+use amethyst::{
+    core::{nalgebra::Vector3, Time, Transform},
+    ecs::prelude::{Component, HashMapStorage, Join, Read, ReadExpect, System, WriteStorage},
+    input::InputHandler,
+};
+use exploration_camera::ExplorationControlTag;
+
+use crate::{
+    collision::{move_body, Aabb},
+    registry::Registry,
+    world::{Block, World},
+};
+
+/// Input action switching between flying and walking, as named in `keybindings.ron`
+const TOGGLE_FLYING: &str = "toggle_flying";
+
+/// Size of the body of the player, in blocks
+const BODY_WIDTH: f32 = 0.6;
+const BODY_HEIGHT: f32 = 1.8;
+/// Height of the camera above the feet of the player
+const EYE_HEIGHT: f32 = 1.62;
+
+/// How the player moves
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum MovementMode {
+    /// Through the blocks, moved by the `ExplorationMovementSystem`
+    Flying,
+    /// On the ground, stopped by the solid blocks
+    Walking,
+}
+
+/// The player, on the entity of the camera
+pub struct Player {
+    pub mode: MovementMode,
+    /// In blocks per second
+    velocity: Vector3<f32>,
+    on_ground: bool,
+}
+
+impl Default for Player {
+    fn default() -> Self {
+        Self {
+            mode: MovementMode::Flying,
+            velocity: Vector3::zeros(),
+            on_ground: false,
+        }
+    }
+}
+
+impl Component for Player {
+    type Storage = HashMapStorage<Self>;
+}
+
+impl Player {
+    /// The body of the player when the camera is at `eye`
+    pub fn body(eye: &Vector3<f32>) -> Aabb {
+        let feet = eye - Vector3::new(0.0, EYE_HEIGHT, 0.0);
+        let half_width = BODY_WIDTH / 2.0;
+        Aabb::new(
+            feet - Vector3::new(half_width, 0.0, half_width),
+            feet + Vector3::new(half_width, BODY_HEIGHT, half_width),
+        )
+    }
+}
+
+/// The system that moves the walking player. The player falls, jumps with the positive
+/// `move_y` axis and walks with the other axes, in the direction the camera looks.
+///
+/// The body of the player collides with the solid blocks, and with the blocks of the chunks that
+/// are not loaded yet so that it doesn't fall through the world while it is generated. The
+/// `toggle_flying` action switches between walking and the flying camera.
+pub struct PlayerMovementSystem {
+    /// In blocks per second
+    walking_speed: f32,
+    /// Vertical speed at the start of a jump
+    jump_speed: f32,
+    /// In blocks per second squared
+    gravity: f32,
+    max_fall_speed: f32,
+    /// Height of the highest blocks that the player walks onto without jumping
+    step_height: f32,
+    /// Whether the toggle action was down during the last run
+    toggle_down: bool,
+}
+
+impl Default for PlayerMovementSystem {
+    fn default() -> Self {
+        Self {
+            walking_speed: 4.3,
+            jump_speed: 8.5,
+            gravity: 28.0,
+            max_fall_speed: 50.0,
+            step_height: 0.6,
+            toggle_down: false,
+        }
+    }
+}
+
+impl<'a> System<'a> for PlayerMovementSystem {
+    type SystemData = (
+        WriteStorage<'a, Player>,
+        WriteStorage<'a, ExplorationControlTag>,
+        WriteStorage<'a, Transform>,
+        Read<'a, Time>,
+        Read<'a, InputHandler<String, String>>,
+        ReadExpect<'a, World>,
+        ReadExpect<'a, Registry<Block>>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            mut players,
+            mut tags,
+            mut transforms,
+            time,
+            input,
+            world,
+            block_registry,
+        ): Self::SystemData,
+    ) {
+        let toggle_down = input.action_is_down(TOGGLE_FLYING).unwrap_or(false);
+        let toggled = toggle_down && !self.toggle_down;
+        self.toggle_down = toggle_down;
+
+        let axis = |name: &str| input.axis_value(name).unwrap_or(0.0) as f32;
+        let (x, y, z) = (axis("move_x"), axis("move_y"), axis("move_z"));
+        // The body could go through blocks during long frames
+        let delta = time.delta_seconds().min(0.1);
+
+        for (player, tag, transform) in (&mut players, &mut tags, &mut transforms).join() {
+            if toggled {
+                player.mode = match player.mode {
+                    MovementMode::Flying => MovementMode::Walking,
+                    MovementMode::Walking => MovementMode::Flying,
+                };
+                player.velocity = Vector3::zeros();
+                player.on_ground = false;
+            }
+            tag.fixed_position = player.mode == MovementMode::Walking;
+            if player.mode == MovementMode::Flying {
+                continue;
+            }
+
+            // Rotated by the yaw of the camera, as in the `ExplorationMovementSystem`
+            let (sin, cos) = tag.yaw.sin_cos();
+            let direction = Vector3::new(cos * x - sin * z, 0.0, -sin * x - cos * z);
+            let direction = if direction.norm() > 1.0 {
+                direction.normalize()
+            } else {
+                direction
+            };
+            player.velocity[0] = direction[0] * self.walking_speed;
+            player.velocity[2] = direction[2] * self.walking_speed;
+            if player.on_ground && y > 0.0 {
+                player.velocity[1] = self.jump_speed;
+            }
+            player.velocity[1] =
+                (player.velocity[1] - self.gravity * delta).max(-self.max_fall_speed);
+
+            let eye = *transform.translation();
+            let movement = move_body(
+                &Player::body(&eye),
+                player.velocity * delta,
+                self.step_height,
+                |pos| {
+                    world
+                        .get_block(pos)
+                        .map_or(true, |id| block_registry[id].solid)
+                },
+            );
+            if movement.collided[1] {
+                player.velocity[1] = 0.0;
+            }
+            player.on_ground = movement.on_ground;
+            transform.set_position(eye + movement.offset);
+        }
+    }
+}
@@ -0,0 +1,246 @@
+//! Finding the first block along a ray, e.g. the block the camera looks at.
+use amethyst::core::nalgebra::Vector3;
+
+use crate::world::{BlockId, BlockPos, World};
+
+/// A block hit by a ray
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct RaycastHit {
+    /// Position of the block
+    pub pos: BlockPos,
+    /// Normal of the face of the block where the ray entered it. Zero if the ray started inside
+    /// the block.
+    pub normal: Vector3<isize>,
+    /// Distance along the ray from its origin to the face of the block
+    pub distance: f32,
+    /// Position of the block in front of the face, where the ray was before the hit. This is
+    /// where a block placed against the face goes. Same as `pos` if the ray started inside the
+    /// block.
+    pub adjacent: BlockPos,
+}
+
+/// Find the first block along a ray for which `is_hit` returns true, at most `max_distance` away
+/// from `origin`. The blocks of the chunks that are not loaded are never hit.
+///
+/// The ray goes through every block it touches, in order, by stepping to the closest block face
+/// along one of the axes every time (Amanatides and Woo's voxel traversal).
+pub fn raycast<F>(
+    world: &World,
+    origin: Vector3<f32>,
+    direction: Vector3<f32>,
+    max_distance: f32,
+    mut is_hit: F,
+) -> Option<RaycastHit>
+where
+    F: FnMut(BlockId) -> bool,
+{
+    let norm = direction.norm();
+    if norm == 0.0 || !norm.is_finite() {
+        return None;
+    }
+    let origin = origin.map(f64::from);
+    let direction = direction.map(|c| f64::from(c / norm));
+
+    let mut pos = BlockPos(origin.map(|c| c.floor() as isize));
+    let mut step = Vector3::zeros();
+    // Distance along the ray to the next block face on every axis, and between two faces
+    let mut next_face = Vector3::repeat(std::f64::INFINITY);
+    let mut face_interval = Vector3::repeat(std::f64::INFINITY);
+    for axis in 0..3 {
+        let d = direction[axis];
+        if d > 0.0 {
+            step[axis] = 1;
+            next_face[axis] = (pos.0[axis] as f64 + 1.0 - origin[axis]) / d;
+        } else if d < 0.0 {
+            step[axis] = -1;
+            // A ray starting on a face and going down the axis starts in the lower block
+            if origin[axis] == pos.0[axis] as f64 {
+                pos.0[axis] -= 1;
+            }
+            next_face[axis] = (origin[axis] - pos.0[axis] as f64) / -d;
+        }
+        if d != 0.0 {
+            face_interval[axis] = 1.0 / d.abs();
+        }
+    }
+
+    let mut normal = Vector3::zeros();
+    let mut distance = 0.0;
+    while distance <= f64::from(max_distance) {
+        if let Some(block_id) = world.get_block(&pos) {
+            if is_hit(block_id) {
+                return Some(RaycastHit {
+                    pos,
+                    normal,
+                    distance: distance as f32,
+                    adjacent: BlockPos(pos.0 + normal),
+                });
+            }
+        }
+        let axis = next_face.imin();
+        distance = next_face[axis];
+        next_face[axis] += face_interval[axis];
+        pos.0[axis] += step[axis];
+        normal = Vector3::zeros();
+        normal[axis] = -step[axis];
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        noise::SplitMix64,
+        world::{
+            test_blocks::{AIR, STONE},
+            Chunk, ChunkPos,
+        },
+    };
+
+    /// Scattered stone blocks in the 8 chunks around the origin
+    fn world() -> World {
+        let mut world = World::new();
+        let mut rng = SplitMix64::new(3);
+        for x in -1..=0 {
+            for y in -1..=0 {
+                for z in -1..=0 {
+                    let pos = ChunkPos(Vector3::new(x, y, z));
+                    let mut chunk = Chunk::filled(AIR);
+                    for block_pos in pos.region().iter() {
+                        if rng.next_f64() < 0.01 {
+                            let local = block_pos.local_pos().0;
+                            chunk.set(local[0], local[1], local[2], STONE);
+                        }
+                    }
+                    world.insert_chunk(pos, chunk);
+                }
+            }
+        }
+        world
+    }
+
+    /// Walk along the ray in small steps until a stone block is found. Returns the block and the
+    /// one before it.
+    fn brute_force(
+        world: &World,
+        origin: Vector3<f32>,
+        direction: Vector3<f32>,
+        max_distance: f32,
+    ) -> Option<(BlockPos, BlockPos, f32)> {
+        const STEP: f32 = 1e-3;
+        let direction = direction.normalize();
+        let mut previous = None;
+        for i in 0..=(max_distance / STEP) as usize {
+            let distance = i as f32 * STEP;
+            let point = origin + direction * distance;
+            let pos = BlockPos(point.map(|c| c.floor() as isize));
+            if previous == Some(pos) {
+                continue;
+            }
+            if world.get_block(&pos) == Some(STONE) {
+                return Some((pos, previous.unwrap_or(pos), distance));
+            }
+            previous = Some(pos);
+        }
+        None
+    }
+
+    #[test]
+    fn hits_match_brute_force() {
+        let world = world();
+        let mut rng = SplitMix64::new(11);
+        let mut random = |min: f32, max: f32| min + (max - min) * rng.next_f64() as f32;
+        let mut hits = 0;
+        for i in 0..300 {
+            let origin = Vector3::new(
+                random(-20.0, 20.0),
+                random(-20.0, 20.0),
+                random(-20.0, 20.0),
+            );
+            let mut direction =
+                Vector3::new(random(-1.0, 1.0), random(-1.0, 1.0), random(-1.0, 1.0));
+            // Some rays are parallel to one or two axes
+            direction[i % 3] *= (i % 2) as f32;
+            if i % 5 == 0 {
+                direction[(i + 1) % 3] = 0.0;
+            }
+            let max_distance = 24.0;
+
+            let hit = raycast(&world, origin, direction, max_distance, |id| id == STONE);
+            let expected = brute_force(&world, origin, direction, max_distance);
+            let (hit, (pos, before, distance)) = match (hit, expected) {
+                (Some(hit), Some(expected)) => (hit, expected),
+                // The last step of the brute force can fall short of the maximum distance
+                (Some(hit), None) => {
+                    assert!(hit.distance > max_distance - 0.01, "{:?}", hit);
+                    continue;
+                }
+                (None, None) => continue,
+                (None, Some(expected)) => panic!("Missed {:?}", expected),
+            };
+            assert_eq!(hit.pos, pos, "ray from {:?} to {:?}", origin, direction);
+            assert!(
+                (hit.distance - distance).abs() < 2e-3,
+                "{:?} {}",
+                hit,
+                distance
+            );
+            assert_eq!(hit.adjacent, BlockPos(hit.pos.0 + hit.normal));
+            if before != pos {
+                // The brute force can cut an edge of a block, going through two faces at once
+                let offset = before.0 - pos.0;
+                if offset.iter().map(|c| c.abs()).sum::<isize>() == 1 {
+                    assert_eq!(hit.normal, offset);
+                }
+                assert_eq!(world.get_block(&hit.adjacent), Some(AIR));
+            }
+            hits += 1;
+        }
+        assert!(hits > 50, "{} hits", hits);
+    }
+
+    #[test]
+    fn hits_across_chunk_borders() {
+        let mut world = World::new();
+        for x in -1..=0 {
+            world.insert_chunk(ChunkPos(Vector3::new(x, 0, 0)), Chunk::filled(AIR));
+        }
+        world.set_block(&BlockPos::new(-5, 3, 3), STONE);
+        world.set_block(&BlockPos::new(2, 3, 3), STONE);
+        let is_stone = |id| id == STONE;
+
+        // From the border of two chunks, towards negative coordinates
+        let origin = Vector3::new(0.0, 3.5, 3.5);
+        let left = Vector3::new(-1.0, 0.0, 0.0);
+        let hit = raycast(&world, origin, left, 10.0, is_stone).unwrap();
+        assert_eq!(hit.pos, BlockPos::new(-5, 3, 3));
+        assert_eq!(hit.normal, Vector3::new(1, 0, 0));
+        assert_eq!(hit.adjacent, BlockPos::new(-4, 3, 3));
+        assert_eq!(hit.distance, 4.0);
+        // Too short
+        assert_eq!(raycast(&world, origin, left, 3.5, is_stone), None);
+
+        let right = Vector3::new(1.0, 0.0, 0.0);
+        let hit = raycast(&world, origin, right, 10.0, is_stone).unwrap();
+        assert_eq!(hit.pos, BlockPos::new(2, 3, 3));
+        assert_eq!(hit.normal, Vector3::new(-1, 0, 0));
+        assert_eq!(hit.distance, 2.0);
+
+        // Starting inside a block
+        let inside = Vector3::new(-4.5, 3.5, 3.5);
+        let hit = raycast(&world, inside, right, 10.0, is_stone).unwrap();
+        assert_eq!(hit.pos, BlockPos::new(-5, 3, 3));
+        assert_eq!(hit.normal, Vector3::zeros());
+        assert_eq!(hit.adjacent, hit.pos);
+        assert_eq!(hit.distance, 0.0);
+
+        // No direction, or nothing to hit
+        assert_eq!(
+            raycast(&world, origin, Vector3::zeros(), 10.0, is_stone),
+            None
+        );
+        let up = Vector3::new(0.0, 1.0, 0.0);
+        assert_eq!(raycast(&world, origin, up, 100.0, is_stone), None);
+    }
+}
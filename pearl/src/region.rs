@@ -0,0 +1,600 @@
+//! Region files, used to save chunks to disk.
+//!
+//! Chunks are grouped into regions of `REGION_SIZE`^3 chunks, and every region is stored in its
+//! own file. A region file starts with a header:
+//!
+//! - the magic bytes `PRLR` and the format version,
+//! - the location of the block name table,
+//! - one location per chunk of the region, 0 if the chunk was never saved.
+//!
+//! Every location is a (sector, length) pair of little-endian `u32`s pointing to a zlib-compressed
+//! payload of `length` bytes, starting at the given `SECTOR_SIZE` sector of the file. Payloads
+//! take whole sectors. The sectors of the payloads that are replaced are reused by the next ones,
+//! but only once the header doesn't point to them anymore, so that an interrupted save leaves
+//! the previous version of the file.
+//!
+//! Chunk payloads refer to blocks by their index in the name table of the file, and not by their
+//! id in the `Registry`, so that ids can be remapped when the file is loaded. Blocks that are not
+//! registered anymore are loaded as the `MISSING_BLOCK`.
+use crate::{
+    registry::{Registry, RegistryMapping, RemapTable},
+    world::{floor_div, floor_mod, Block, BlockId, Chunk, ChunkPos, CHUNK_SIZE, MISSING_BLOCK},
+};
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+use log::warn;
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+/// Number of chunks in a region along every axis.
+pub const REGION_SIZE: isize = 8;
+const REGION_VOLUME: usize = (REGION_SIZE * REGION_SIZE * REGION_SIZE) as usize;
+
+const MAGIC: &[u8; 4] = b"PRLR";
+const VERSION: u32 = 2;
+/// Magic, version and name table location, then one location per chunk.
+const HEADER_SIZE: u64 = 16 + 8 * REGION_VOLUME as u64;
+/// Size of the units in which the space of the file is allocated.
+const SECTOR_SIZE: u64 = 4096;
+const HEADER_SECTORS: usize = ((HEADER_SIZE + SECTOR_SIZE - 1) / SECTOR_SIZE) as usize;
+
+/// Position of a region, in regions.
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
+pub struct RegionPos(pub isize, pub isize, pub isize);
+
+impl RegionPos {
+    /// Get the region containing the chunk at `pos`
+    pub fn from_chunk(pos: &ChunkPos) -> Self {
+        RegionPos(
+            floor_div(pos.0[0], REGION_SIZE),
+            floor_div(pos.0[1], REGION_SIZE),
+            floor_div(pos.0[2], REGION_SIZE),
+        )
+    }
+}
+
+/// Index of the chunk in the location table of its region
+fn chunk_index(pos: &ChunkPos) -> usize {
+    let local = |c: isize| floor_mod(c, REGION_SIZE) as usize;
+    let size = REGION_SIZE as usize;
+    (local(pos.0[0]) * size + local(pos.0[1])) * size + local(pos.0[2])
+}
+
+fn invalid_data<S: Into<String>>(message: S) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+/// A single region file
+pub struct RegionFile {
+    file: File,
+    /// Block names, indexed by their id in this file
+    mapping: RegistryMapping,
+    ids_by_name: HashMap<String, usize>,
+    /// Converts the ids of this file to registry ids, built on the first load
+    remap_table: Option<RemapTable<Block>>,
+    names_location: (u32, u32),
+    chunk_locations: Vec<(u32, u32)>,
+    /// Whether every sector of the file is used by the header or a payload
+    used_sectors: Vec<bool>,
+}
+
+impl RegionFile {
+    /// Open a region file, creating it if it doesn't exist
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path)?;
+        let mut region = Self {
+            file,
+            mapping: RegistryMapping::default(),
+            ids_by_name: HashMap::new(),
+            remap_table: None,
+            names_location: (0, 0),
+            chunk_locations: vec![(0, 0); REGION_VOLUME],
+            used_sectors: vec![true; HEADER_SECTORS],
+        };
+        if region.file.metadata()?.len() == 0 {
+            region.write_header()?;
+        } else {
+            region.read_header()?;
+        }
+        Ok(region)
+    }
+
+    /// Load the chunk at `pos`, or return `None` if it was never saved
+    pub fn load_chunk(
+        &mut self,
+        pos: &ChunkPos,
+        block_registry: &Registry<Block>,
+    ) -> io::Result<Option<Chunk>> {
+        let location = self.chunk_locations[chunk_index(pos)];
+        if location.1 == 0 {
+            return Ok(None);
+        }
+        let payload = self.read_payload(location)?;
+        let mut reader = &payload[..];
+
+        // The chunk is read with the ids of this file, then converted to registry ids
+        let palette_len = read_u32(&mut reader)? as usize;
+        let mut palette = Vec::with_capacity(palette_len);
+        for _ in 0..palette_len {
+            let file_id = read_u32(&mut reader)? as usize;
+            if file_id >= self.mapping.names().len() {
+                return Err(invalid_data(format!("Invalid block id {}", file_id)));
+            }
+            palette.push(BlockId::from_index(file_id));
+        }
+        if palette.is_empty() {
+            return Err(invalid_data("Empty chunk palette"));
+        }
+
+        let mut chunk = Chunk::filled(palette[0]);
+        if palette.len() > 1 {
+            for x in 0..CHUNK_SIZE {
+                for y in 0..CHUNK_SIZE {
+                    for z in 0..CHUNK_SIZE {
+                        let index = read_u16(&mut reader)? as usize;
+                        let id = *palette
+                            .get(index)
+                            .ok_or_else(|| invalid_data("Invalid palette index"))?;
+                        chunk.set(x, y, z, id);
+                    }
+                }
+            }
+        }
+        let remap_table = self.remap_table(block_registry)?;
+        if !remap_table.is_identity() {
+            chunk.remap(|id| remap_table.get(id));
+        }
+        Ok(Some(chunk))
+    }
+
+    /// Save the chunk at `pos`, replacing the previously saved version if any
+    pub fn save_chunk(
+        &mut self,
+        pos: &ChunkPos,
+        chunk: &Chunk,
+        block_registry: &Registry<Block>,
+    ) -> io::Result<()> {
+        let names_len = self.mapping.names().len();
+        let mut palette: Vec<BlockId> = Vec::new();
+        let mut indices: Vec<u16> = Vec::new();
+        let mut palette_indices: HashMap<BlockId, u16> = HashMap::new();
+        for x in 0..CHUNK_SIZE {
+            for y in 0..CHUNK_SIZE {
+                for z in 0..CHUNK_SIZE {
+                    let id = chunk.get(x, y, z);
+                    let index = *palette_indices.entry(id).or_insert_with(|| {
+                        palette.push(id);
+                        (palette.len() - 1) as u16
+                    });
+                    indices.push(index);
+                }
+            }
+        }
+
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&(palette.len() as u32).to_le_bytes());
+        for id in palette.iter() {
+            let name = block_registry
+                .get_item_name(*id)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            let file_id = self.file_id(name);
+            payload.extend_from_slice(&(file_id as u32).to_le_bytes());
+        }
+        if palette.len() > 1 {
+            for index in indices {
+                payload.extend_from_slice(&index.to_le_bytes());
+            }
+        }
+
+        // The payloads replaced by this save are kept until the header stops pointing to them
+        let mut replaced = Vec::new();
+        let location = self.write_payload(&payload)?;
+        replaced.push(std::mem::replace(
+            &mut self.chunk_locations[chunk_index(pos)],
+            location,
+        ));
+        if self.mapping.names().len() != names_len {
+            let names = self.mapping.names();
+            let mut names_payload = Vec::new();
+            names_payload.extend_from_slice(&(names.len() as u32).to_le_bytes());
+            for name in names.iter() {
+                names_payload.extend_from_slice(&(name.len() as u32).to_le_bytes());
+                names_payload.extend_from_slice(name.as_bytes());
+            }
+            let location = self.write_payload(&names_payload)?;
+            replaced.push(std::mem::replace(&mut self.names_location, location));
+        }
+        // The payloads must be on the disk before the header points to them
+        self.file.sync_data()?;
+        self.write_header()?;
+        self.file.sync_data()?;
+        for location in replaced {
+            self.free_sectors(location);
+        }
+        Ok(())
+    }
+
+    /// Get the id of a block name in this file, adding it to the name table if necessary
+    fn file_id(&mut self, name: &str) -> usize {
+        if let Some(id) = self.ids_by_name.get(name) {
+            return *id;
+        }
+        let id = self.mapping.push(name);
+        self.ids_by_name.insert(name.to_owned(), id);
+        self.remap_table = None;
+        id
+    }
+
+    /// Get the table converting the ids of this file to the ids of `block_registry`
+    fn remap_table(&mut self, block_registry: &Registry<Block>) -> io::Result<&RemapTable<Block>> {
+        if self.remap_table.is_none() {
+            // The missing block is only needed if some blocks of the file are not registered
+            let missing_block = block_registry.get_item_id(MISSING_BLOCK).ok();
+            let remap_table = block_registry
+                .remap_table(&self.mapping, missing_block)
+                .map_err(|e| io::Error::new(io::ErrorKind::NotFound, e))?;
+            for name in remap_table.missing_names() {
+                warn!("Unknown block {} replaced by {}", name, MISSING_BLOCK);
+            }
+            self.remap_table = Some(remap_table);
+        }
+        Ok(self.remap_table.as_ref().unwrap())
+    }
+
+    fn read_header(&mut self) -> io::Result<()> {
+        let mut header = vec![0; HEADER_SIZE as usize];
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_exact(&mut header)?;
+        let mut reader = &header[..];
+
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data("Not a region file"));
+        }
+        let version = read_u32(&mut reader)?;
+        if version != VERSION {
+            return Err(invalid_data(format!(
+                "Unsupported region file version {}",
+                version
+            )));
+        }
+        self.names_location = (read_u32(&mut reader)?, read_u32(&mut reader)?);
+        for location in self.chunk_locations.iter_mut() {
+            *location = (read_u32(&mut reader)?, read_u32(&mut reader)?);
+        }
+        let locations: Vec<(u32, u32)> = std::iter::once(self.names_location)
+            .chain(self.chunk_locations.iter().cloned())
+            .collect();
+        for location in locations {
+            for sector in sectors(location) {
+                if sector < HEADER_SECTORS || self.used_sectors.get(sector) == Some(&true) {
+                    return Err(invalid_data("Overlapping payloads"));
+                }
+                if sector >= self.used_sectors.len() {
+                    self.used_sectors.resize(sector + 1, false);
+                }
+                self.used_sectors[sector] = true;
+            }
+        }
+
+        if self.names_location.1 != 0 {
+            let payload = self.read_payload(self.names_location)?;
+            let mut reader = &payload[..];
+            let names_len = read_u32(&mut reader)?;
+            for _ in 0..names_len {
+                let mut name = vec![0; read_u32(&mut reader)? as usize];
+                reader.read_exact(&mut name)?;
+                let name = String::from_utf8(name)
+                    .map_err(|_| invalid_data("Block name is not valid UTF-8"))?;
+                self.file_id(&name);
+            }
+        }
+        Ok(())
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let mut header = Vec::with_capacity(HEADER_SIZE as usize);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&VERSION.to_le_bytes());
+        let locations = std::iter::once(&self.names_location).chain(self.chunk_locations.iter());
+        for (offset, length) in locations {
+            header.extend_from_slice(&offset.to_le_bytes());
+            header.extend_from_slice(&length.to_le_bytes());
+        }
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&header)
+    }
+
+    fn read_payload(&mut self, (sector, length): (u32, u32)) -> io::Result<Vec<u8>> {
+        let mut compressed = vec![0; length as usize];
+        self.file
+            .seek(SeekFrom::Start(u64::from(sector) * SECTOR_SIZE))?;
+        self.file.read_exact(&mut compressed)?;
+        let mut payload = Vec::new();
+        ZlibDecoder::new(&compressed[..]).read_to_end(&mut payload)?;
+        Ok(payload)
+    }
+
+    /// Compress and write a payload in the first free sectors large enough, and return its
+    /// location
+    fn write_payload(&mut self, payload: &[u8]) -> io::Result<(u32, u32)> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(payload)?;
+        let compressed = encoder.finish()?;
+        if compressed.len() as u64 > u64::from(u32::max_value()) {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Chunk payload too large",
+            ));
+        }
+
+        let sector_count = ((compressed.len() as u64 + SECTOR_SIZE - 1) / SECTOR_SIZE) as usize;
+        let mut start = HEADER_SECTORS;
+        while start < self.used_sectors.len() {
+            match self.used_sectors[start..]
+                .iter()
+                .take(sector_count)
+                .position(|used| *used)
+            {
+                Some(used) => start += used + 1,
+                None => break,
+            }
+        }
+        let end = start + sector_count;
+        if end as u64 > u64::from(u32::max_value()) {
+            return Err(io::Error::new(io::ErrorKind::Other, "Region file is full"));
+        }
+        if end > self.used_sectors.len() {
+            self.used_sectors.resize(end, false);
+        }
+        for used in self.used_sectors[start..end].iter_mut() {
+            *used = true;
+        }
+
+        self.file
+            .seek(SeekFrom::Start(start as u64 * SECTOR_SIZE))?;
+        self.file.write_all(&compressed)?;
+        Ok((start as u32, compressed.len() as u32))
+    }
+
+    /// Allow the sectors of a payload to be used by the next ones
+    fn free_sectors(&mut self, location: (u32, u32)) {
+        for sector in sectors(location) {
+            self.used_sectors[sector] = false;
+        }
+    }
+}
+
+/// The sectors used by the payload at `location`
+fn sectors((sector, length): (u32, u32)) -> std::ops::Range<usize> {
+    let sector_count = (u64::from(length) + SECTOR_SIZE - 1) / SECTOR_SIZE;
+    sector as usize..sector as usize + sector_count as usize
+}
+
+fn read_u32(reader: &mut &[u8]) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u16(reader: &mut &[u8]) -> io::Result<u16> {
+    let mut bytes = [0; 2];
+    reader.read_exact(&mut bytes)?;
+    Ok(u16::from_le_bytes(bytes))
+}
+
+/// All the region files of a saved world, stored in a single directory
+pub struct RegionStorage {
+    directory: PathBuf,
+    regions: HashMap<RegionPos, RegionFile>,
+}
+
+impl RegionStorage {
+    pub fn new<P: Into<PathBuf>>(directory: P) -> Self {
+        Self {
+            directory: directory.into(),
+            regions: HashMap::new(),
+        }
+    }
+
+    /// Load the chunk at `pos`, or return `None` if it was never saved
+    pub fn load_chunk(
+        &mut self,
+        pos: &ChunkPos,
+        block_registry: &Registry<Block>,
+    ) -> io::Result<Option<Chunk>> {
+        let region_pos = RegionPos::from_chunk(pos);
+        if !self.regions.contains_key(&region_pos) && !self.region_path(&region_pos).exists() {
+            return Ok(None);
+        }
+        self.region(region_pos)?.load_chunk(pos, block_registry)
+    }
+
+    /// Save the chunk at `pos`
+    pub fn save_chunk(
+        &mut self,
+        pos: &ChunkPos,
+        chunk: &Chunk,
+        block_registry: &Registry<Block>,
+    ) -> io::Result<()> {
+        self.region(RegionPos::from_chunk(pos))?
+            .save_chunk(pos, chunk, block_registry)
+    }
+
+    fn region_path(&self, pos: &RegionPos) -> PathBuf {
+        self.directory
+            .join(format!("r.{}.{}.{}.region", pos.0, pos.1, pos.2))
+    }
+
+    fn region(&mut self, pos: RegionPos) -> io::Result<&mut RegionFile> {
+        if !self.regions.contains_key(&pos) {
+            fs::create_dir_all(&self.directory)?;
+            let region = RegionFile::open(&self.region_path(&pos))?;
+            self.regions.insert(pos, region);
+        }
+        Ok(self.regions.get_mut(&pos).unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use amethyst::core::nalgebra::Vector3;
+
+    /// An empty directory for the region files of a test
+    fn test_directory(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("pearl-region-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        directory
+    }
+
+    fn block_registry(names: &[&str]) -> Registry<Block> {
+        let mut block_registry = Registry::new();
+        for name in names {
+            block_registry.register(*name, Block::default()).unwrap();
+        }
+        block_registry
+    }
+
+    /// A chunk made of the ids from 0 to `types - 1`, in a pattern depending on `seed`
+    fn test_chunk(types: usize, seed: usize) -> Chunk {
+        let mut chunk = Chunk::filled(BlockId::from_index(0));
+        for x in 0..CHUNK_SIZE {
+            for z in 0..CHUNK_SIZE {
+                let y = (x * 7 + z * 3 + seed) % CHUNK_SIZE;
+                chunk.set(x, y, z, BlockId::from_index((x + z + seed) % types));
+            }
+        }
+        chunk
+    }
+
+    fn assert_same_blocks(a: &Chunk, b: &Chunk) {
+        for x in 0..CHUNK_SIZE {
+            for y in 0..CHUNK_SIZE {
+                for z in 0..CHUNK_SIZE {
+                    assert_eq!(a.get(x, y, z), b.get(x, y, z), "{} {} {}", x, y, z);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn save_and_load() {
+        let directory = test_directory("save_and_load");
+        let names = ["air", "stone", "dirt"];
+        let block_registry = block_registry(&names);
+        let positions: Vec<ChunkPos> = [(0, 0, 0), (-1, 0, 0), (7, -8, 8), (-9, 3, -17)]
+            .iter()
+            .map(|&(x, y, z)| ChunkPos(Vector3::new(x, y, z)))
+            .collect();
+        let chunks: Vec<Chunk> = (0..positions.len())
+            .map(|i| test_chunk(names.len(), i))
+            .collect();
+
+        let mut storage = RegionStorage::new(&directory);
+        for (pos, chunk) in positions.iter().zip(chunks.iter()) {
+            storage.save_chunk(pos, chunk, &block_registry).unwrap();
+        }
+        let uniform_pos = ChunkPos(Vector3::new(1, 1, 1));
+        let uniform = Chunk::filled(BlockId::from_index(2));
+        storage
+            .save_chunk(&uniform_pos, &uniform, &block_registry)
+            .unwrap();
+
+        // Loaded by the storage that saved them, and after the files are opened again
+        for storage in &mut [storage, RegionStorage::new(&directory)] {
+            for (pos, chunk) in positions.iter().zip(chunks.iter()) {
+                let loaded = storage.load_chunk(pos, &block_registry).unwrap().unwrap();
+                assert_same_blocks(&loaded, chunk);
+                assert!(!loaded.is_modified());
+            }
+            let loaded = storage.load_chunk(&uniform_pos, &block_registry);
+            assert!(loaded.unwrap().unwrap().is_uniform());
+            // In a saved region, and in a region without a file
+            for pos in &[Vector3::new(2, 2, 2), Vector3::new(100, 0, 0)] {
+                let loaded = storage.load_chunk(&ChunkPos(*pos), &block_registry);
+                assert!(loaded.unwrap().is_none());
+            }
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn remap_on_load() {
+        let directory = test_directory("remap_on_load");
+        let old_registry = block_registry(&[MISSING_BLOCK, "air", "stone", "dirt"]);
+        let pos = ChunkPos(Vector3::new(-3, 5, 0));
+        let chunk = test_chunk(old_registry.len(), 0);
+        let mut storage = RegionStorage::new(&directory);
+        storage.save_chunk(&pos, &chunk, &old_registry).unwrap();
+
+        // "stone" was removed and "sand" added before "air"
+        let registry = block_registry(&["dirt", "sand", "air", MISSING_BLOCK]);
+        let loaded = RegionStorage::new(&directory)
+            .load_chunk(&pos, &registry)
+            .unwrap()
+            .unwrap();
+        let remapped = |id: BlockId| {
+            let name = match old_registry.get_item_name(id).unwrap() {
+                "stone" => MISSING_BLOCK,
+                name => name,
+            };
+            registry.get_item_id(name).unwrap()
+        };
+        for x in 0..CHUNK_SIZE {
+            for y in 0..CHUNK_SIZE {
+                for z in 0..CHUNK_SIZE {
+                    assert_eq!(loaded.get(x, y, z), remapped(chunk.get(x, y, z)));
+                }
+            }
+        }
+
+        // Without a missing block, the unknown block can't be loaded
+        let registry = block_registry(&["dirt", "air"]);
+        match RegionStorage::new(&directory).load_chunk(&pos, &registry) {
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::NotFound),
+            Ok(_) => panic!("Loaded a chunk with an unknown block"),
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn replaced_payloads_are_reused() {
+        let directory = test_directory("replaced_payloads_are_reused");
+        let names = ["air", "stone", "dirt"];
+        let block_registry = block_registry(&names);
+        let pos = ChunkPos(Vector3::new(0, 0, 0));
+        let mut storage = RegionStorage::new(&directory);
+        let file_sectors = |storage: &RegionStorage| {
+            let path = storage.region_path(&RegionPos::from_chunk(&pos));
+            (fs::metadata(path).unwrap().len() + SECTOR_SIZE - 1) / SECTOR_SIZE
+        };
+
+        // The new payload is written before the previous one is freed, so two alternate
+        for seed in 0..2 {
+            let chunk = test_chunk(names.len(), seed);
+            storage.save_chunk(&pos, &chunk, &block_registry).unwrap();
+        }
+        let sectors = file_sectors(&storage);
+        for seed in 2..20 {
+            let chunk = test_chunk(names.len(), seed);
+            storage.save_chunk(&pos, &chunk, &block_registry).unwrap();
+            assert_eq!(file_sectors(&storage), sectors);
+        }
+        let loaded = RegionStorage::new(&directory)
+            .load_chunk(&pos, &block_registry)
+            .unwrap()
+            .unwrap();
+        assert_same_blocks(&loaded, &test_chunk(names.len(), 19));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -1,7 +1,103 @@
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    error::Error,
+    fmt,
+    hash::{Hash, Hasher},
+    marker::PhantomData,
+    ops::Index,
+};
 
+/// The id of an item of a `Registry<T>`. Ids of different registries have different types, so
+/// they can't be mixed up.
+pub struct Id<T> {
+    index: u32,
+    marker: PhantomData<fn() -> T>,
+}
+
+impl<T> Id<T> {
+    /// Create an id from its index. There is no guarantee that the id exists in the registry.
+    pub const fn from_index(index: usize) -> Self {
+        Self {
+            index: index as u32,
+            marker: PhantomData,
+        }
+    }
+
+    /// The index of the id, ids are numbered from 0 in the order of registration.
+    pub fn index(self) -> usize {
+        self.index as usize
+    }
+}
+
+// Implemented manually because the derives would require `T` to implement the traits as well
+impl<T> Clone for Id<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Id<T> {}
+
+impl<T> PartialEq for Id<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.index == other.index
+    }
+}
+
+impl<T> Eq for Id<T> {}
+
+impl<T> PartialOrd for Id<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Id<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.index.cmp(&other.index)
+    }
+}
+
+impl<T> Hash for Id<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.index.hash(state);
+    }
+}
+
+impl<T> fmt::Debug for Id<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Id({})", self.index)
+    }
+}
+
+/// Error returned by the `Registry` methods
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegistryError {
+    /// An item with this name was already registered
+    DuplicateName(String),
+    /// No item has this name
+    UnknownName(String),
+    /// No item has this id
+    UnknownId(usize),
+}
+
+impl fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RegistryError::DuplicateName(name) => write!(f, "{} is already registered", name),
+            RegistryError::UnknownName(name) => write!(f, "{} is not registered", name),
+            RegistryError::UnknownId(index) => write!(f, "No item has the id {}", index),
+        }
+    }
+}
+
+impl Error for RegistryError {}
+
+#[derive(Clone)]
 pub struct Registry<T> {
-    ids_by_name: HashMap<String, usize>,
+    ids_by_name: HashMap<String, Id<T>>,
     names_by_id: Vec<String>,
     items_by_id: Vec<T>,
 }
@@ -17,34 +113,234 @@ impl<T> Registry<T> {
     }
 
     /// Register a new item in the Registry with a given name.
-    pub fn register<S>(&mut self, name: S, item: T) -> usize
+    pub fn register<S>(&mut self, name: S, item: T) -> Result<Id<T>, RegistryError>
     where
         S: Into<String>,
     {
         let name = name.into();
         if self.ids_by_name.contains_key(&name) {
-            panic!(format!("Key {} already exists in registry!", name));
-        } else {
-            let id = self.names_by_id.len();
-            self.ids_by_name.insert(name.clone(), id);
-            self.names_by_id.push(name);
-            self.items_by_id.push(item);
-            id
+            return Err(RegistryError::DuplicateName(name));
         }
+        let id = Id::from_index(self.names_by_id.len());
+        self.ids_by_name.insert(name.clone(), id);
+        self.names_by_id.push(name);
+        self.items_by_id.push(item);
+        Ok(id)
+    }
+
+    /// Get an item by its id.
+    pub fn get_item(&self, id: Id<T>) -> Result<&T, RegistryError> {
+        self.items_by_id
+            .get(id.index())
+            .ok_or_else(|| RegistryError::UnknownId(id.index()))
     }
 
-    /// Get an item by its id. Panics if the item doesn't exist.
-    /// TODO: proper error handling
-    pub fn get_item(&self, id: usize) -> &T {
-        &self.items_by_id[id]
+    /// Get an item's name by its id.
+    pub fn get_item_name(&self, id: Id<T>) -> Result<&str, RegistryError> {
+        self.names_by_id
+            .get(id.index())
+            .map(|name| name.as_str())
+            .ok_or_else(|| RegistryError::UnknownId(id.index()))
     }
 
     /// Get an item's id given its name.
-    pub fn get_item_id<S>(&self, name: S) -> Option<usize>
-    where
-        S: Into<String>,
-    {
-        let name = name.into();
-        self.ids_by_name.get(&name).map(|id| *id)
+    pub fn get_item_id(&self, name: &str) -> Result<Id<T>, RegistryError> {
+        self.ids_by_name
+            .get(name)
+            .cloned()
+            .ok_or_else(|| RegistryError::UnknownName(name.to_owned()))
+    }
+
+    /// Number of registered items.
+    pub fn len(&self) -> usize {
+        self.items_by_id.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items_by_id.is_empty()
+    }
+
+    /// Iterate over the items and their ids, in the order of the ids.
+    pub fn iter(&self) -> impl Iterator<Item = (Id<T>, &T)> {
+        self.items_by_id
+            .iter()
+            .enumerate()
+            .map(|(index, item)| (Id::from_index(index), item))
+    }
+
+    /// Export the name of every id, to be stored alongside data using these ids.
+    pub fn export_mapping(&self) -> RegistryMapping {
+        RegistryMapping {
+            names: self.names_by_id.clone(),
+        }
+    }
+
+    /// Build the table converting the ids of an exported mapping to the ids of this registry.
+    /// Names that are not registered anymore are converted to `missing`, and are an
+    /// `UnknownName` error if there is no `missing` id.
+    pub fn remap_table(
+        &self,
+        mapping: &RegistryMapping,
+        missing: Option<Id<T>>,
+    ) -> Result<RemapTable<T>, RegistryError> {
+        let mut missing_names = Vec::new();
+        let mut ids = Vec::with_capacity(mapping.names.len());
+        for name in mapping.names.iter() {
+            let id = match self.ids_by_name.get(name.as_str()) {
+                Some(id) => *id,
+                None => {
+                    missing_names.push(name.clone());
+                    missing.ok_or_else(|| RegistryError::UnknownName(name.clone()))?
+                }
+            };
+            ids.push(id);
+        }
+        Ok(RemapTable { ids, missing_names })
+    }
+}
+
+impl<T> Default for Registry<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Get an item by its id. Panics if the item doesn't exist, use `get_item` otherwise.
+impl<T> Index<Id<T>> for Registry<T> {
+    type Output = T;
+
+    fn index(&self, id: Id<T>) -> &T {
+        &self.items_by_id[id.index()]
+    }
+}
+
+/// The name of every id of a registry, as exported by `Registry::export_mapping`.
+///
+/// Ids are assigned in registration order, so they change meaning when items are added or
+/// reordered. Data saved or received with ids must come with the mapping of its registry, so
+/// that the ids can be converted with `Registry::remap_table`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RegistryMapping {
+    /// Item names, indexed by id
+    names: Vec<String>,
+}
+
+impl RegistryMapping {
+    /// Create a mapping from item names, indexed by id.
+    pub fn new(names: Vec<String>) -> Self {
+        Self { names }
+    }
+
+    /// Item names, indexed by id.
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    /// Add a name to the mapping, and return its id.
+    pub fn push<S: Into<String>>(&mut self, name: S) -> usize {
+        self.names.push(name.into());
+        self.names.len() - 1
+    }
+}
+
+/// Converts the ids of an old `RegistryMapping` to the ids of the current `Registry`
+pub struct RemapTable<T> {
+    /// New ids, indexed by old id
+    ids: Vec<Id<T>>,
+    missing_names: Vec<String>,
+}
+
+impl<T> RemapTable<T> {
+    /// Get the current id of an old id. Panics if the id is not in the old mapping.
+    pub fn get(&self, old_id: Id<T>) -> Id<T> {
+        self.ids[old_id.index()]
+    }
+
+    /// Names of the old mapping that are not registered anymore.
+    pub fn missing_names(&self) -> &[String] {
+        &self.missing_names
+    }
+
+    /// Return true if every old id keeps its value, in which case data doesn't need to be
+    /// rewritten.
+    pub fn is_identity(&self) -> bool {
+        self.missing_names.is_empty()
+            && self
+                .ids
+                .iter()
+                .enumerate()
+                .all(|(index, id)| id.index() == index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registry_errors() {
+        let mut registry = Registry::new();
+        let a = registry.register("a", 1).unwrap();
+        let b = registry.register("b", 2).unwrap();
+
+        // A duplicate name keeps the first item
+        assert_eq!(
+            registry.register("a", 3),
+            Err(RegistryError::DuplicateName("a".to_owned()))
+        );
+        assert_eq!(registry.len(), 2);
+        assert_eq!(registry.get_item(a), Ok(&1));
+
+        assert_eq!(registry.get_item_id("b"), Ok(b));
+        assert_eq!(registry.get_item_name(b), Ok("b"));
+        let unknown_id = Id::from_index(2);
+        assert_eq!(
+            registry.get_item(unknown_id),
+            Err(RegistryError::UnknownId(2))
+        );
+        assert_eq!(
+            registry.get_item_name(unknown_id),
+            Err(RegistryError::UnknownId(2))
+        );
+        assert_eq!(
+            registry.get_item_id("c"),
+            Err(RegistryError::UnknownName("c".to_owned()))
+        );
+        assert_eq!(
+            RegistryError::UnknownName("c".to_owned()).to_string(),
+            "c is not registered"
+        );
+    }
+
+    #[test]
+    fn remap_from_old_mapping() {
+        let mut old_registry = Registry::new();
+        for name in &["a", "b", "c"] {
+            old_registry.register(*name, ()).unwrap();
+        }
+        let mapping = old_registry.export_mapping();
+        // Without a missing id, as every name is still registered
+        assert!(old_registry
+            .remap_table(&mapping, None)
+            .unwrap()
+            .is_identity());
+
+        // "b" was removed and "d" added before "a"
+        let mut registry = Registry::new();
+        let missing = registry.register("missing", ()).unwrap();
+        let c = registry.register("c", ()).unwrap();
+        registry.register("d", ()).unwrap();
+        let a = registry.register("a", ()).unwrap();
+
+        assert_eq!(
+            registry.remap_table(&mapping, None).err(),
+            Some(RegistryError::UnknownName("b".to_owned()))
+        );
+        let remap_table = registry.remap_table(&mapping, Some(missing)).unwrap();
+        assert!(!remap_table.is_identity());
+        assert_eq!(remap_table.get(Id::from_index(0)), a);
+        assert_eq!(remap_table.get(Id::from_index(1)), missing);
+        assert_eq!(remap_table.get(Id::from_index(2)), c);
+        assert_eq!(remap_table.missing_names(), &["b".to_owned()]);
     }
 }
@@ -0,0 +1,163 @@
+use amethyst::{
+    core::Transform,
+    ecs::prelude::{Entities, ReadExpect, System, WriteExpect, WriteStorage},
+};
+use log::error;
+use std::sync::Arc;
+
+use crate::{
+    atlas::TextureAtlas,
+    jobs::{ChunkJobs, JobKind, JobResult},
+    mesh::{buffer::VertexLayout, chunk::MeshingMode},
+    registry::Registry,
+    render::ChunkModel,
+    streaming::create_chunk_entity,
+    world::{neighbour_offsets, Block, Chunk, ChunkEntities, ChunkPos, MeshedChunks, World},
+    worldgen::{BiomeMaps, WorldGenerator},
+};
+
+/// The system that rebuilds the meshes of the chunks that changed since they were meshed, e.g.
+/// after a block edit, and replaces the mesh of their entity.
+///
+/// A chunk is dirty when its version is not the one its mesh was built from anymore. Dirty chunks
+/// are meshed once however many times they changed in the meantime. Meshing runs on a worker
+/// thread of its own, so that edits don't wait behind the chunks being streamed.
+pub struct ChunkRemeshSystem {
+    /// Maximum number of meshing jobs started per frame, each one copies the chunk and its
+    /// neighbours. The other dirty chunks wait for the next frames.
+    chunks_per_frame: usize,
+    meshing_mode: MeshingMode,
+    vertex_layout: VertexLayout,
+    jobs: Option<ChunkJobs>,
+}
+
+impl ChunkRemeshSystem {
+    /// See `ChunkStreamingSystem::remesh_system`
+    pub fn new(
+        chunks_per_frame: usize,
+        meshing_mode: MeshingMode,
+        vertex_layout: VertexLayout,
+    ) -> Self {
+        Self {
+            chunks_per_frame,
+            meshing_mode,
+            vertex_layout,
+            jobs: None,
+        }
+    }
+}
+
+impl<'a> System<'a> for ChunkRemeshSystem {
+    type SystemData = (
+        Entities<'a>,
+        WriteStorage<'a, Transform>,
+        WriteStorage<'a, ChunkModel>,
+        ReadExpect<'a, World>,
+        ReadExpect<'a, BiomeMaps>,
+        WriteExpect<'a, ChunkEntities>,
+        WriteExpect<'a, MeshedChunks>,
+        ReadExpect<'a, Arc<dyn WorldGenerator>>,
+        ReadExpect<'a, Registry<Block>>,
+        ReadExpect<'a, TextureAtlas>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            entities,
+            mut transforms,
+            mut models,
+            world,
+            biome_maps,
+            mut chunk_entities,
+            mut meshed,
+            world_generator,
+            block_registry,
+            texture_atlas,
+        ): Self::SystemData,
+    ) {
+        let meshing_mode = self.meshing_mode;
+        let vertex_layout = self.vertex_layout;
+        let jobs = self.jobs.get_or_insert_with(|| {
+            ChunkJobs::new(
+                1,
+                Arc::new(block_registry.clone()),
+                Arc::new(texture_atlas.clone()),
+                world_generator.clone(),
+                meshing_mode,
+                vertex_layout,
+            )
+        });
+
+        // The chunks unloaded since the jobs were started don't need a mesh anymore
+        jobs.cancel_where(|pos, _| !meshed.0.contains_key(pos));
+
+        for (pos, result) in jobs.poll() {
+            let (chunk_mesh, version) = match result {
+                JobResult::Meshed(chunk_mesh, version) => (chunk_mesh, version),
+                JobResult::Generated(_) => continue,
+            };
+            if chunk_mesh.is_empty() {
+                // An edit can leave nothing to draw
+                if let Some(entity) = chunk_entities.0.remove(&pos) {
+                    if let Err(e) = entities.delete(entity) {
+                        error!("Failed to delete chunk entity {:?}: {}", pos, e);
+                    }
+                }
+            } else {
+                let model = ChunkModel {
+                    mesh: chunk_mesh,
+                    version,
+                };
+                match chunk_entities.0.get(&pos) {
+                    Some(&entity) => {
+                        if let Err(e) = models.insert(entity, model) {
+                            error!("Failed to replace the mesh of chunk {:?}: {}", pos, e);
+                        }
+                    }
+                    None => {
+                        let entity = create_chunk_entity(
+                            &entities,
+                            &mut transforms,
+                            &mut models,
+                            &pos,
+                            model,
+                        );
+                        chunk_entities.0.insert(pos, entity);
+                    }
+                }
+            }
+            // The chunk is still dirty if it changed while it was meshed
+            meshed.0.insert(pos, version);
+        }
+
+        let dirty: Vec<ChunkPos> = meshed
+            .0
+            .iter()
+            .filter(|(pos, version)| {
+                let changed = world
+                    .get_chunk(pos)
+                    .map_or(false, |chunk| chunk.version() != **version);
+                changed && !jobs.is_pending(pos, JobKind::Mesh)
+            })
+            .map(|(pos, _)| *pos)
+            .collect();
+        // The chunks whose neighbours are not all loaded don't count, so that they don't hold
+        // back the other ones
+        let mut budget = self.chunks_per_frame;
+        for pos in dirty {
+            if budget == 0 {
+                break;
+            }
+            let chunk = world.get_chunk(&pos);
+            let neighbours: Option<Vec<Chunk>> = neighbour_offsets()
+                .map(|offset| world.get_chunk(&ChunkPos(pos.0 + offset)).cloned())
+                .collect();
+            if let (Some(chunk), Some(neighbours)) = (chunk, neighbours) {
+                let biome_map = biome_maps.get(&pos.column()).cloned();
+                jobs.mesh(pos, chunk.clone(), neighbours, biome_map);
+                budget -= 1;
+            }
+        }
+    }
+}
@@ -0,0 +1,356 @@
+//! Drawing the chunk meshes.
+//!
+//! Chunk meshes don't go through amethyst `Mesh` assets: the amethyst 0.10 `MeshData` has no
+//! index buffer, and the vertex formats of its passes would expand the compact vertices again.
+//! `DrawChunks` uploads the `ChunkMesh` of every `ChunkModel` as it is, with its index buffer,
+//! and draws it with its own shaders. The atlas tiles are repeated once per block by the
+//! fragment shader, so the quads of greedy meshes are not stretched.
+//!
+//! There is one pass per vertex layout, each pass draws the meshes of its layout.
+use amethyst::{
+    assets::AssetStorage,
+    core::{nalgebra::Matrix4, GlobalTransform},
+    ecs::prelude::{
+        Component, DenseVecStorage, Entities, Entity, Join, Read, ReadExpect, ReadStorage,
+    },
+    renderer::{
+        error::Result,
+        pipe::{
+            pass::{Pass, PassData},
+            DepthMode, Effect, NewEffect,
+        },
+        ActiveCamera, Attributes, Camera, Encoder, Factory, Resources, Texture, TextureHandle,
+    },
+};
+use gfx::{
+    buffer::Role,
+    format::{ChannelType, Format, SurfaceType},
+    handle::RawBuffer,
+    memory::{Bind, Typed},
+    pso::buffer::{ElemStride, Element},
+    traits::{Factory as GfxFactory, FactoryExt, Pod},
+    Slice,
+};
+use log::error;
+use std::{
+    borrow::Cow,
+    collections::hash_map::{Entry, HashMap},
+    marker::PhantomData,
+    mem,
+};
+
+use crate::{
+    atlas::TextureAtlas,
+    mesh::buffer::{ChunkMesh, ChunkVertex, Indices, PackedVertex},
+};
+
+const PACKED_VERTEX_SHADER: &[u8] = include_bytes!("shaders/vertex/chunk_packed.glsl");
+const FULL_VERTEX_SHADER: &[u8] = include_bytes!("shaders/vertex/chunk_full.glsl");
+const FRAGMENT_SHADER: &[u8] = include_bytes!("shaders/fragment/chunk.glsl");
+
+/// The mesh drawn by a chunk entity
+pub struct ChunkModel {
+    pub mesh: ChunkMesh,
+    /// Version of the chunk the mesh was built from. The mesh is uploaded again when it changes.
+    pub version: u64,
+}
+
+impl Component for ChunkModel {
+    type Storage = DenseVecStorage<Self>;
+}
+
+/// The texture of the block atlas, used by every chunk
+pub struct ChunkTexture(pub TextureHandle);
+
+/// A chunk vertex layout that can be drawn by `DrawChunks`
+pub trait ChunkVertexFormat: Pod + Copy + Send + Sync + 'static {
+    /// The attributes read by `VERTEX_SHADER`
+    const ATTRIBUTES: Attributes<'static>;
+    const VERTEX_SHADER: &'static [u8];
+
+    /// The vertices and indices of `mesh`, or `None` if it uses another layout
+    fn vertices(mesh: &ChunkMesh) -> Option<(Cow<'_, [Self]>, &Indices)>;
+}
+
+unsafe impl Pod for PackedVertex {}
+
+impl ChunkVertexFormat for PackedVertex {
+    const ATTRIBUTES: Attributes<'static> = &[
+        (
+            "position",
+            Element {
+                format: Format(SurfaceType::R8_G8_B8_A8, ChannelType::Uint),
+                offset: 0,
+            },
+        ),
+        (
+            "tex_coord",
+            Element {
+                format: Format(SurfaceType::R8_G8, ChannelType::Uint),
+                offset: 4,
+            },
+        ),
+        (
+            "tile",
+            Element {
+                format: Format(SurfaceType::R16, ChannelType::Uint),
+                offset: 6,
+            },
+        ),
+        // The padding byte is read as the alpha of the color
+        (
+            "color",
+            Element {
+                format: Format(SurfaceType::R8_G8_B8_A8, ChannelType::Unorm),
+                offset: 8,
+            },
+        ),
+    ];
+    const VERTEX_SHADER: &'static [u8] = PACKED_VERTEX_SHADER;
+
+    fn vertices(mesh: &ChunkMesh) -> Option<(Cow<'_, [Self]>, &Indices)> {
+        match mesh {
+            ChunkMesh::Packed(mesh) => Some((Cow::Borrowed(&mesh.vertices[..]), &mesh.indices)),
+            ChunkMesh::Full(_) => None,
+        }
+    }
+}
+
+/// `ChunkVertex` with plain arrays, as uploaded to the GPU
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct FullVertex {
+    position: [f32; 3],
+    normal: [f32; 3],
+    tex_coord: [f32; 2],
+    tile: f32,
+    ao: f32,
+    color: [f32; 3],
+}
+
+impl<'a> From<&'a ChunkVertex> for FullVertex {
+    fn from(vertex: &ChunkVertex) -> Self {
+        Self {
+            position: vertex.position.into(),
+            normal: vertex.normal.into(),
+            tex_coord: vertex.tex_coord.into(),
+            tile: f32::from(vertex.tile),
+            ao: vertex.ao,
+            color: vertex.color,
+        }
+    }
+}
+
+unsafe impl Pod for FullVertex {}
+
+impl ChunkVertexFormat for FullVertex {
+    const ATTRIBUTES: Attributes<'static> = &[
+        (
+            "position",
+            Element {
+                format: Format(SurfaceType::R32_G32_B32, ChannelType::Float),
+                offset: 0,
+            },
+        ),
+        (
+            "normal",
+            Element {
+                format: Format(SurfaceType::R32_G32_B32, ChannelType::Float),
+                offset: 12,
+            },
+        ),
+        (
+            "tex_coord",
+            Element {
+                format: Format(SurfaceType::R32_G32, ChannelType::Float),
+                offset: 24,
+            },
+        ),
+        (
+            "tile",
+            Element {
+                format: Format(SurfaceType::R32, ChannelType::Float),
+                offset: 32,
+            },
+        ),
+        (
+            "ao",
+            Element {
+                format: Format(SurfaceType::R32, ChannelType::Float),
+                offset: 36,
+            },
+        ),
+        (
+            "color",
+            Element {
+                format: Format(SurfaceType::R32_G32_B32, ChannelType::Float),
+                offset: 40,
+            },
+        ),
+    ];
+    const VERTEX_SHADER: &'static [u8] = FULL_VERTEX_SHADER;
+
+    fn vertices(mesh: &ChunkMesh) -> Option<(Cow<'_, [Self]>, &Indices)> {
+        match mesh {
+            ChunkMesh::Full(mesh) => {
+                let vertices: Vec<Self> = mesh.vertices.iter().map(Self::from).collect();
+                Some((Cow::Owned(vertices), &mesh.indices))
+            }
+            ChunkMesh::Packed(_) => None,
+        }
+    }
+}
+
+/// The uniform block of the chunk shaders
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct ChunkArgs {
+    proj: [[f32; 4]; 4],
+    view: [[f32; 4]; 4],
+    model: [[f32; 4]; 4],
+    /// See `TextureAtlas::tile_grid`
+    tile_grid: [f32; 4],
+}
+
+/// The buffers of an uploaded chunk mesh
+struct ChunkBuffers {
+    /// Version of the chunk the mesh was built from
+    version: u64,
+    vertices: RawBuffer<Resources>,
+    /// Range of the index buffer to draw, all of it
+    slice: Slice<Resources>,
+}
+
+impl ChunkBuffers {
+    /// Upload the mesh of `model`, or return `None` if it doesn't use the vertex layout `V`
+    fn upload<V: ChunkVertexFormat>(factory: &mut Factory, model: &ChunkModel) -> Option<Self> {
+        let (vertices, indices) = V::vertices(&model.mesh)?;
+        let vertices = match factory.create_buffer_immutable(&vertices, Role::Vertex, Bind::empty())
+        {
+            Ok(vertices) => vertices,
+            Err(e) => {
+                error!("Failed to upload a chunk mesh: {:?}", e);
+                return None;
+            }
+        };
+        let index_buffer = match indices {
+            Indices::U16(indices) => factory.create_index_buffer(&indices[..]),
+            Indices::U32(indices) => factory.create_index_buffer(&indices[..]),
+        };
+        Some(Self {
+            version: model.version,
+            vertices: vertices.raw().clone(),
+            slice: Slice {
+                start: 0,
+                end: indices.len() as u32,
+                base_vertex: 0,
+                instances: None,
+                buffer: index_buffer,
+            },
+        })
+    }
+}
+
+/// The pass drawing the chunk meshes that use the vertex layout `V`
+pub struct DrawChunks<V> {
+    /// Uploaded meshes, by chunk entity
+    buffers: HashMap<Entity, ChunkBuffers>,
+    vertex_format: PhantomData<V>,
+}
+
+impl<V> Default for DrawChunks<V> {
+    fn default() -> Self {
+        Self {
+            buffers: HashMap::new(),
+            vertex_format: PhantomData,
+        }
+    }
+}
+
+impl<'a, V: ChunkVertexFormat> PassData<'a> for DrawChunks<V> {
+    type Data = (
+        Entities<'a>,
+        Option<Read<'a, ActiveCamera>>,
+        ReadStorage<'a, Camera>,
+        ReadStorage<'a, GlobalTransform>,
+        ReadStorage<'a, ChunkModel>,
+        ReadExpect<'a, ChunkTexture>,
+        ReadExpect<'a, TextureAtlas>,
+        Read<'a, AssetStorage<Texture>>,
+    );
+}
+
+impl<V: ChunkVertexFormat> Pass for DrawChunks<V> {
+    fn compile(&mut self, effect: NewEffect<'_>) -> Result<Effect> {
+        let mut builder = effect.simple(V::VERTEX_SHADER, FRAGMENT_SHADER);
+        builder.with_raw_vertex_buffer(V::ATTRIBUTES, mem::size_of::<V>() as ElemStride, 0);
+        builder.with_raw_constant_buffer("ChunkArgs", mem::size_of::<ChunkArgs>(), 1);
+        builder.with_texture("albedo");
+        builder.with_output("color", Some(DepthMode::LessEqualWrite));
+        builder.build()
+    }
+
+    fn apply<'a, 'b: 'a>(
+        &'a mut self,
+        encoder: &mut Encoder,
+        effect: &mut Effect,
+        mut factory: Factory,
+        (
+            entities,
+            active_camera,
+            cameras,
+            global_transforms,
+            models,
+            chunk_texture,
+            texture_atlas,
+            texture_storage,
+        ): <Self as PassData<'a>>::Data,
+    ) {
+        let camera = active_camera
+            .and_then(|active| {
+                let camera = cameras.get(active.entity)?;
+                Some((camera, global_transforms.get(active.entity)?))
+            })
+            .or_else(|| (&cameras, &global_transforms).join().next());
+        let (camera, camera_transform) = match camera {
+            Some(camera) => camera,
+            None => return,
+        };
+        let texture = match texture_storage.get(&chunk_texture.0) {
+            Some(texture) => texture,
+            None => return,
+        };
+        let view = camera_transform
+            .0
+            .try_inverse()
+            .unwrap_or_else(Matrix4::identity);
+
+        // Free the meshes of the chunks that were unloaded or meshed again
+        self.buffers.retain(|entity, buffers| {
+            models
+                .get(*entity)
+                .map_or(false, |model| model.version == buffers.version)
+        });
+        for (entity, model, transform) in (&entities, &models, &global_transforms).join() {
+            let buffers = match self.buffers.entry(entity) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => match ChunkBuffers::upload::<V>(&mut factory, model) {
+                    Some(buffers) => entry.insert(buffers),
+                    None => continue,
+                },
+            };
+            let args = ChunkArgs {
+                proj: camera.proj.into(),
+                view: view.into(),
+                model: transform.0.into(),
+                tile_grid: texture_atlas.tile_grid(),
+            };
+            effect.update_constant_buffer("ChunkArgs", &args, encoder);
+            effect.data.textures.push(texture.view().clone());
+            effect.data.samplers.push(texture.sampler().clone());
+            effect.data.vertex_bufs.push(buffers.vertices.clone());
+            effect.draw(&buffers.slice, encoder);
+            effect.clear();
+        }
+    }
+}
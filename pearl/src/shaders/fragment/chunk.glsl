@@ -0,0 +1,31 @@
+#version 150 core
+
+uniform sampler2D albedo;
+
+in VertexData {
+    vec2 tex_coord;
+    // Bottom left corner and size of the atlas tile
+    flat vec4 tile;
+    float shade;
+    // Color of the light baked in the mesh, times the grass tint of the tinted blocks, as
+    // multipliers of the texture colors
+    vec3 tint;
+    // Ambient occlusion, from 0 (fully occluded) to 1
+    float ao;
+} vertex;
+
+out vec4 color;
+
+// Brightness of the fully occluded corners
+const float MIN_AO_BRIGHTNESS = 0.5;
+
+void main() {
+    // The tile is repeated once per block. The gradients are taken before wrapping, otherwise
+    // the jump of the coordinates at block borders would select the smallest mipmap level.
+    vec2 tex_coord = vertex.tile.xy + fract(vertex.tex_coord) * vertex.tile.zw;
+    vec2 dx = dFdx(vertex.tex_coord) * vertex.tile.zw;
+    vec2 dy = dFdy(vertex.tex_coord) * vertex.tile.zw;
+    vec4 albedo_color = textureGrad(albedo, tex_coord, dx, dy);
+    float ao = mix(MIN_AO_BRIGHTNESS, 1.0, vertex.ao);
+    color = vec4(albedo_color.rgb * vertex.tint * vertex.shade * ao, albedo_color.a);
+}
@@ -0,0 +1,39 @@
+#version 150 core
+
+layout (std140) uniform ChunkArgs {
+    uniform mat4 proj;
+    uniform mat4 view;
+    uniform mat4 model;
+    uniform vec4 tile_grid;
+};
+
+in vec3 position;
+in vec3 normal;
+// Texture coordinates in blocks
+in vec2 tex_coord;
+in float tile;
+// Ambient occlusion, from 0 (fully occluded) to 1
+in float ao;
+// Color of the light, times the grass tint
+in vec3 color;
+
+out VertexData {
+    vec2 tex_coord;
+    flat vec4 tile;
+    float shade;
+    vec3 tint;
+    float ao;
+} vertex;
+
+void main() {
+    float columns = tile_grid.x;
+    vec2 cell = vec2(mod(tile, columns), floor(tile / columns));
+    vertex.tex_coord = tex_coord;
+    vertex.tile = vec4(cell * tile_grid.y + tile_grid.z, vec2(tile_grid.w));
+    // Same brightness as `FACE_SHADES` in the packed shader
+    float vertical = normal.y > 0.0 ? 1.0 : 0.5;
+    vertex.shade = dot(abs(normal), vec3(0.8, vertical, 0.65));
+    vertex.tint = color;
+    vertex.ao = ao;
+    gl_Position = proj * view * model * vec4(position, 1.0);
+}
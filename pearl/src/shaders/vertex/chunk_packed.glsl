@@ -0,0 +1,42 @@
+#version 150 core
+
+layout (std140) uniform ChunkArgs {
+    uniform mat4 proj;
+    uniform mat4 view;
+    uniform mat4 model;
+    uniform vec4 tile_grid;
+};
+
+// Position in the chunk, then the face in the low 3 bits of w and the ambient occlusion level
+// in the next 2 bits
+in uvec4 position;
+// Texture coordinates in blocks
+in uvec2 tex_coord;
+in uint tile;
+// Color of the light, times the grass tint. The alpha is unused.
+in vec4 color;
+
+out VertexData {
+    vec2 tex_coord;
+    flat vec4 tile;
+    float shade;
+    vec3 tint;
+    float ao;
+} vertex;
+
+// Brightness of every face, in the order of `cube::NORMALS`
+const float FACE_SHADES[6] = float[](0.8, 0.8, 1.0, 0.5, 0.65, 0.65);
+// `cube::MAX_AO`
+const uint MAX_AO = 3u;
+
+void main() {
+    uint face = position.w & 7u;
+    float columns = tile_grid.x;
+    vec2 cell = vec2(mod(float(tile), columns), floor(float(tile) / columns));
+    vertex.tex_coord = vec2(tex_coord);
+    vertex.tile = vec4(cell * tile_grid.y + tile_grid.z, vec2(tile_grid.w));
+    vertex.shade = FACE_SHADES[face];
+    vertex.tint = color.rgb;
+    vertex.ao = float(position.w >> 3) / float(MAX_AO);
+    gl_Position = proj * view * model * vec4(vec3(position.xyz), 1.0);
+}
@@ -0,0 +1,400 @@
+use amethyst::{
+    core::{nalgebra::Vector3, Transform},
+    ecs::prelude::{
+        Entities, Entity, Join, ReadExpect, ReadStorage, System, WriteExpect, WriteStorage,
+    },
+};
+use exploration_camera::ExplorationControlTag;
+use log::error;
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+    sync::Arc,
+};
+
+use crate::{
+    atlas::TextureAtlas,
+    jobs::{ChunkJobs, JobKind, JobResult},
+    light::{connect_chunk, light_chunk},
+    mesh::{buffer::VertexLayout, chunk::MeshingMode},
+    region::RegionStorage,
+    registry::Registry,
+    remesh::ChunkRemeshSystem,
+    render::ChunkModel,
+    world::{
+        floor_div, neighbour_offsets, Block, Chunk, ChunkEntities, ChunkPos, MeshedChunks, World,
+        CHUNK_SIZE,
+    },
+    worldgen::{BiomeMaps, GenerationPipeline, WorldGenerator},
+};
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy)]
+enum ChunkTaskKind {
+    /// Load the chunk from the save or request its generation
+    Load,
+    /// Mesh the chunk and spawn its entity
+    Mesh,
+}
+
+/// Work to be done on a chunk, ordered by priority
+#[derive(PartialEq, Eq, Debug, Clone)]
+struct ChunkTask {
+    /// Squared distance to the camera chunk, closer chunks are processed first
+    priority: isize,
+    kind: ChunkTaskKind,
+    pos: ChunkPos,
+}
+
+impl Ord for ChunkTask {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.priority, self.kind).cmp(&(other.priority, other.kind))
+    }
+}
+
+impl PartialOrd for ChunkTask {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// The system that loads and meshes the chunks around the camera, and unloads the chunks that
+/// are too far away.
+///
+/// Chunks are meshed within `view_distance` chunks of the camera. Their neighbours are loaded as
+/// well, since meshing a chunk requires the 26 chunks around it. Generation and meshing run on
+/// worker threads. Meshing jobs of chunks that go out of range are cancelled, while generation
+/// stages always complete since they hold the chunks of the `GenerationPipeline`.
+///
+/// Chunks are only meshed once, the `ChunkRemeshSystem` rebuilds the meshes of the chunks that
+/// changed afterwards.
+pub struct ChunkStreamingSystem {
+    /// Meshing radius, in chunks
+    view_distance: isize,
+    /// Maximum number of chunks loaded or sent to the workers every frame
+    chunks_per_frame: usize,
+    /// Chunk containing the camera when the queue was last filled
+    center: Option<ChunkPos>,
+    queue: BinaryHeap<Reverse<ChunkTask>>,
+    /// Number of threads used to generate and mesh chunks
+    worker_count: usize,
+    meshing_mode: MeshingMode,
+    vertex_layout: VertexLayout,
+    jobs: Option<ChunkJobs>,
+    pipeline: GenerationPipeline,
+}
+
+impl ChunkStreamingSystem {
+    pub fn new(view_distance: isize, chunks_per_frame: usize, worker_count: usize) -> Self {
+        Self {
+            view_distance,
+            chunks_per_frame,
+            center: None,
+            queue: BinaryHeap::new(),
+            worker_count,
+            meshing_mode: MeshingMode::Greedy,
+            vertex_layout: VertexLayout::Packed,
+            jobs: None,
+            pipeline: GenerationPipeline::new(),
+        }
+    }
+
+    /// Change the algorithm used to mesh chunks
+    pub fn with_meshing_mode(mut self, meshing_mode: MeshingMode) -> Self {
+        self.meshing_mode = meshing_mode;
+        self
+    }
+
+    /// Change the vertex layout of the meshes sent back by the workers
+    pub fn with_vertex_layout(mut self, vertex_layout: VertexLayout) -> Self {
+        self.vertex_layout = vertex_layout;
+        self
+    }
+
+    /// Create the system remeshing the chunks meshed by this one, with the same settings and
+    /// frame budget
+    pub fn remesh_system(&self) -> ChunkRemeshSystem {
+        ChunkRemeshSystem::new(self.chunks_per_frame, self.meshing_mode, self.vertex_layout)
+    }
+
+    /// Queue every missing chunk around `center`
+    fn fill_queue(&mut self, center: &ChunkPos, world: &World, meshed: &MeshedChunks) {
+        self.queue.clear();
+        // The corner neighbours of the meshed chunks are up to sqrt(3) chunks farther away
+        let load_distance = self.view_distance + 2;
+        for i in -load_distance..=load_distance {
+            for j in -load_distance..=load_distance {
+                for k in -load_distance..=load_distance {
+                    let offset = Vector3::new(i, j, k);
+                    let distance = offset.dot(&offset);
+                    let pos = ChunkPos(center.0 + offset);
+                    if distance <= load_distance * load_distance && world.get_chunk(&pos).is_none()
+                    {
+                        self.queue.push(Reverse(ChunkTask {
+                            priority: distance,
+                            kind: ChunkTaskKind::Load,
+                            pos,
+                        }));
+                    }
+                    if distance <= self.view_distance * self.view_distance
+                        && !meshed.0.contains_key(&pos)
+                    {
+                        // Mesh after the farthest neighbour, a corner one, is loaded
+                        let coordinate_sum: isize = offset.iter().map(|c| c.abs()).sum();
+                        self.queue.push(Reverse(ChunkTask {
+                            priority: distance + 2 * coordinate_sum + 3,
+                            kind: ChunkTaskKind::Mesh,
+                            pos,
+                        }));
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<'a> System<'a> for ChunkStreamingSystem {
+    type SystemData = (
+        Entities<'a>,
+        ReadStorage<'a, ExplorationControlTag>,
+        WriteStorage<'a, Transform>,
+        WriteStorage<'a, ChunkModel>,
+        WriteExpect<'a, World>,
+        WriteExpect<'a, BiomeMaps>,
+        WriteExpect<'a, ChunkEntities>,
+        WriteExpect<'a, MeshedChunks>,
+        WriteExpect<'a, RegionStorage>,
+        ReadExpect<'a, Arc<dyn WorldGenerator>>,
+        ReadExpect<'a, Registry<Block>>,
+        ReadExpect<'a, TextureAtlas>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            entities,
+            tags,
+            mut transforms,
+            mut models,
+            mut world,
+            mut biome_maps,
+            mut chunk_entities,
+            mut meshed,
+            mut region_storage,
+            world_generator,
+            block_registry,
+            texture_atlas,
+        ): Self::SystemData,
+    ) {
+        let worker_count = self.worker_count;
+        let meshing_mode = self.meshing_mode;
+        let vertex_layout = self.vertex_layout;
+        // The registry and the generator don't change once the game is started
+        let jobs = self.jobs.get_or_insert_with(|| {
+            ChunkJobs::new(
+                worker_count,
+                Arc::new(block_registry.clone()),
+                Arc::new(texture_atlas.clone()),
+                world_generator.clone(),
+                meshing_mode,
+                vertex_layout,
+            )
+        });
+
+        for (pos, result) in jobs.poll() {
+            match result {
+                JobResult::Generated(task) => self.pipeline.complete(task),
+                JobResult::Meshed(chunk_mesh, version) => {
+                    if world.get_chunk(&pos).is_none() {
+                        continue;
+                    }
+                    if !chunk_mesh.is_empty() {
+                        let model = ChunkModel {
+                            mesh: chunk_mesh,
+                            version,
+                        };
+                        let entity = create_chunk_entity(
+                            &entities,
+                            &mut transforms,
+                            &mut models,
+                            &pos,
+                            model,
+                        );
+                        chunk_entities.0.insert(pos, entity);
+                    }
+                    meshed.0.insert(pos, version);
+                }
+            }
+        }
+
+        let unload_distance = self.view_distance + 3;
+        let in_range = |center: &ChunkPos, pos: &ChunkPos| {
+            let offset = pos.0 - center.0;
+            offset.dot(&offset) <= unload_distance * unload_distance
+        };
+        for (pos, chunk) in self.pipeline.take_complete() {
+            let wanted = self.center.map_or(true, |center| in_range(&center, &pos));
+            if wanted && world.get_chunk(&pos).is_none() {
+                insert_chunk(
+                    &mut world,
+                    &mut biome_maps,
+                    &**world_generator,
+                    &block_registry,
+                    pos,
+                    chunk,
+                );
+            }
+        }
+
+        let camera_position = match (&tags, &transforms).join().next() {
+            Some((_, transform)) => *transform.translation(),
+            None => return,
+        };
+        let center =
+            ChunkPos(camera_position.map(|c| floor_div(c.floor() as isize, CHUNK_SIZE as isize)));
+
+        if self.center != Some(center) {
+            // Unload the chunks that are out of range, with some margin to avoid unloading and
+            // reloading chunks when the camera moves back and forth across a chunk border
+            let out_of_range = |pos: &ChunkPos| !in_range(&center, pos);
+            jobs.cancel_where(|pos, kind| kind == JobKind::Mesh && out_of_range(pos));
+            // The pipeline remembers the chunks a bit longer, so that the features of the
+            // unloaded chunks are not decided again for the chunks generated next to them
+            let forget_distance = unload_distance + 2;
+            self.pipeline.forget_where(|pos| {
+                let offset = pos.0 - center.0;
+                offset.dot(&offset) > forget_distance * forget_distance
+            });
+            let unloaded: Vec<ChunkPos> = world
+                .chunks()
+                .map(|(pos, _)| *pos)
+                .filter(out_of_range)
+                .collect();
+            for pos in unloaded {
+                // The chunks that were not edited are already saved, or are generated again
+                let chunk = world.remove_chunk(&pos);
+                if let Some(chunk) = chunk.filter(Chunk::is_modified) {
+                    if let Err(e) = region_storage.save_chunk(&pos, &chunk, &block_registry) {
+                        error!("Failed to save chunk {:?}: {}", pos, e);
+                    }
+                }
+                if let Some(entity) = chunk_entities.0.remove(&pos) {
+                    if let Err(e) = entities.delete(entity) {
+                        error!("Failed to delete chunk entity {:?}: {}", pos, e);
+                    }
+                }
+                meshed.0.remove(&pos);
+            }
+            biome_maps.remove_where(|column| {
+                let offset = column.0 - center.column().0;
+                offset.dot(&offset) > unload_distance * unload_distance
+            });
+
+            self.center = Some(center);
+            self.fill_queue(&center, &world, &meshed);
+        }
+
+        let jobs = self.jobs.as_mut().unwrap();
+        let max_pending_jobs = 2 * worker_count;
+        let mut deferred = Vec::new();
+        let mut budget = self.chunks_per_frame;
+        while budget > 0 && jobs.pending_count() < max_pending_jobs {
+            let task = match self.queue.pop() {
+                Some(Reverse(task)) => task,
+                None => break,
+            };
+            match task.kind {
+                ChunkTaskKind::Load => {
+                    if world.get_chunk(&task.pos).is_some() || self.pipeline.is_requested(&task.pos)
+                    {
+                        continue;
+                    }
+                    match region_storage.load_chunk(&task.pos, &block_registry) {
+                        Ok(Some(mut chunk)) => {
+                            // The light is not saved
+                            light_chunk(&mut chunk, &block_registry);
+                            self.pipeline.insert_complete(task.pos);
+                            insert_chunk(
+                                &mut world,
+                                &mut biome_maps,
+                                &**world_generator,
+                                &block_registry,
+                                task.pos,
+                                chunk,
+                            );
+                        }
+                        Ok(None) => self.pipeline.request(task.pos),
+                        Err(e) => {
+                            error!("Failed to load chunk {:?}: {}", task.pos, e);
+                            self.pipeline.request(task.pos);
+                        }
+                    }
+                }
+                ChunkTaskKind::Mesh => {
+                    if meshed.0.contains_key(&task.pos) || jobs.is_pending(&task.pos, JobKind::Mesh)
+                    {
+                        continue;
+                    }
+                    let chunk = world.get_chunk(&task.pos);
+                    let neighbours: Option<Vec<Chunk>> = neighbour_offsets()
+                        .map(|offset| world.get_chunk(&ChunkPos(task.pos.0 + offset)).cloned())
+                        .collect();
+                    match (chunk, neighbours) {
+                        (Some(chunk), Some(neighbours)) => {
+                            let biome_map = biome_maps.get(&task.pos.column()).cloned();
+                            jobs.mesh(task.pos, chunk.clone(), neighbours, biome_map)
+                        }
+                        // Wait until the chunk and its neighbours are generated. Looking at the
+                        // task still counts, so that the queue is not drained every frame.
+                        _ => deferred.push(task),
+                    }
+                }
+            }
+            budget -= 1;
+        }
+        self.queue.extend(deferred.into_iter().map(Reverse));
+
+        while jobs.pending_count() < max_pending_jobs {
+            match self.pipeline.next_task() {
+                Some(task) => jobs.generate(task),
+                None => break,
+            }
+        }
+    }
+}
+
+/// Add a lit chunk to the world, along with the biome map of its column if it is the first chunk
+/// of the column, and spread the light across its borders
+fn insert_chunk(
+    world: &mut World,
+    biome_maps: &mut BiomeMaps,
+    world_generator: &dyn WorldGenerator,
+    block_registry: &Registry<Block>,
+    pos: ChunkPos,
+    chunk: Chunk,
+) {
+    let column = pos.column();
+    if biome_maps.get(&column).is_none() {
+        if let Some(biome_source) = world_generator.biome_source() {
+            biome_maps.insert(column, biome_source.biome_map(&column));
+        }
+    }
+    world.insert_chunk(pos, chunk);
+    connect_chunk(world, block_registry, &pos);
+}
+
+/// Spawn the entity drawing the mesh of the chunk at `pos`
+pub fn create_chunk_entity(
+    entities: &Entities,
+    transforms: &mut WriteStorage<Transform>,
+    models: &mut WriteStorage<ChunkModel>,
+    pos: &ChunkPos,
+    model: ChunkModel,
+) -> Entity {
+    let mut transform = Transform::default();
+    transform.set_position(pos.origin().0.map(|c| c as f32));
+    entities
+        .build_entity()
+        .with(transform, transforms)
+        .with(model, models)
+        .build()
+}
@@ -1,18 +1,163 @@
-use amethyst::core::nalgebra::Vector3;
+use amethyst::{
+    core::{
+        nalgebra::{Vector2, Vector3},
+        shrev::{EventChannel, ReaderId},
+    },
+    ecs::Entity,
+};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     hash::{Hash, Hasher},
 };
 
+use crate::{light::ChunkLight, registry::Id};
+
+/// Properties of a kind of block. Blocks are defined in the `resources/blocks.ron` file.
+#[derive(Clone, Debug)]
 pub struct Block {
-    pub air: bool,
+    /// Entities collide with solid blocks
+    pub solid: bool,
+    /// Transparent blocks don't hide the faces of the blocks next to them
+    pub transparent: bool,
+    /// Light level emitted by the block, from 0 to `MAX_LIGHT_EMISSION`
+    pub light_emission: u8,
+    /// How long it takes to break the block
+    pub hardness: f32,
+    /// Textures of the faces, `None` if the block is never drawn
+    pub textures: Option<BlockTextures>,
+    /// The top face is multiplied by the grass tint of the biome
+    pub tinted: bool,
+}
+
+/// The id of a block in the `Registry<Block>`
+pub type BlockId = Id<Block>;
+
+/// Maximum light level emitted by a block
+pub const MAX_LIGHT_EMISSION: u8 = 15;
+
+/// Name of the block replacing saved blocks that are not registered anymore
+pub const MISSING_BLOCK: &str = "default:missing";
+
+impl Default for Block {
+    fn default() -> Self {
+        Self {
+            solid: true,
+            transparent: false,
+            light_emission: 0,
+            hardness: 1.0,
+            textures: None,
+            tinted: false,
+        }
+    }
+}
+
+/// Texture names of the faces of a block, in the order of `mesh::cube::NORMALS`
+#[derive(Clone, Debug)]
+pub struct BlockTextures(pub [String; 6]);
+
+impl BlockTextures {
+    /// The same texture on every face
+    pub fn all<S: Into<String>>(name: S) -> Self {
+        let name = name.into();
+        BlockTextures([
+            name.clone(),
+            name.clone(),
+            name.clone(),
+            name.clone(),
+            name.clone(),
+            name,
+        ])
+    }
+
+    /// One texture for the top, one for the bottom, and one for the four sides
+    pub fn top_side_bottom<S: Into<String>>(top: S, side: S, bottom: S) -> Self {
+        let side = side.into();
+        BlockTextures([
+            side.clone(),
+            side.clone(),
+            top.into(),
+            bottom.into(),
+            side.clone(),
+            side,
+        ])
+    }
 }
 
 pub const CHUNK_SIZE: usize = 32;
+pub const CHUNK_VOLUME: usize = CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE;
+
+/// Division rounding towards negative infinity
+pub fn floor_div(a: isize, b: isize) -> isize {
+    let d = a / b;
+    if a % b < 0 {
+        d - 1
+    } else {
+        d
+    }
+}
+
+/// Remainder of `floor_div`, always positive
+pub fn floor_mod(a: isize, b: isize) -> isize {
+    a - floor_div(a, b) * b
+}
 
-#[derive(PartialEq, Eq, Debug, Clone)]
+/// Offsets of the six neighbours of a block, in the order +x, -x, +y, -y, +z, -z
+pub const ADJACENCY: [[isize; 3]; 6] = [
+    [1, 0, 0],
+    [-1, 0, 0],
+    [0, 1, 0],
+    [0, -1, 0],
+    [0, 0, 1],
+    [0, 0, -1],
+];
+
+/// Offsets of the 26 chunks around a chunk, including the edge and corner neighbours.
+pub fn neighbour_offsets() -> impl Iterator<Item = Vector3<isize>> {
+    (-1..=1)
+        .flat_map(|x| (-1..=1).flat_map(move |y| (-1..=1).map(move |z| Vector3::new(x, y, z))))
+        .filter(|offset| *offset != Vector3::zeros())
+}
+
+/// Chunks whose mesh depends on the block at `pos`: its own chunk, and the neighbours that it
+/// borders since meshing reads the blocks one block around the chunk.
+pub fn chunks_around_block(pos: &BlockPos) -> Vec<ChunkPos> {
+    let chunk_pos = pos.chunk_pos();
+    let local = pos.local_pos().0;
+    // The offsets towards the neighbours on every axis, 0 for the chunk itself
+    let axis_offsets: Vec<Vec<isize>> = (0..3)
+        .map(|axis| match local[axis] {
+            0 => vec![0, -1],
+            c if c == CHUNK_SIZE - 1 => vec![0, 1],
+            _ => vec![0],
+        })
+        .collect();
+    let mut chunks = Vec::new();
+    for &x in &axis_offsets[0] {
+        for &y in &axis_offsets[1] {
+            for &z in &axis_offsets[2] {
+                chunks.push(ChunkPos(chunk_pos.0 + Vector3::new(x, y, z)));
+            }
+        }
+    }
+    chunks
+}
+
+/// Position of a chunk, in chunks
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
 pub struct ChunkPos(pub Vector3<isize>);
 
+/// Position of a block in the world
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct BlockPos(pub Vector3<isize>);
+
+/// Position of a block relative to the chunk that contains it
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct LocalPos(pub Vector3<usize>);
+
+/// Position of a column of chunks, the x and z coordinates of its chunks
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct ColumnPos(pub Vector2<isize>);
+
 pub type ChunkMap = HashMap<ChunkPos, Chunk>;
 
 impl Hash for ChunkPos {
@@ -23,14 +168,850 @@ impl Hash for ChunkPos {
     }
 }
 
+impl Hash for ColumnPos {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0[0].hash(state);
+        self.0[1].hash(state);
+    }
+}
+
+impl Hash for BlockPos {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0[0].hash(state);
+        self.0[1].hash(state);
+        self.0[2].hash(state);
+    }
+}
+
+impl ChunkPos {
+    /// Position of the block at the minimum corner of the chunk
+    pub fn origin(&self) -> BlockPos {
+        BlockPos(self.0 * CHUNK_SIZE as isize)
+    }
+
+    /// Position in the world of a block of this chunk
+    pub fn block_pos(&self, local: &LocalPos) -> BlockPos {
+        BlockPos(self.origin().0 + local.0.map(|c| c as isize))
+    }
+
+    /// Position of the column containing this chunk
+    pub fn column(&self) -> ColumnPos {
+        ColumnPos(Vector2::new(self.0[0], self.0[2]))
+    }
+
+    /// Region covering every block of this chunk
+    pub fn region(&self) -> BlockRegion {
+        let origin = self.origin();
+        BlockRegion::new(
+            origin,
+            BlockPos(origin.0.map(|c| c + CHUNK_SIZE as isize - 1)),
+        )
+    }
+}
+
+impl ColumnPos {
+    /// The x and z coordinates of the blocks at the minimum corner of the column
+    pub fn origin(&self) -> Vector2<isize> {
+        self.0 * CHUNK_SIZE as isize
+    }
+}
+
+impl BlockPos {
+    pub fn new(x: isize, y: isize, z: isize) -> Self {
+        BlockPos(Vector3::new(x, y, z))
+    }
+
+    /// Position of the chunk containing this block
+    pub fn chunk_pos(&self) -> ChunkPos {
+        ChunkPos(self.0.map(|c| floor_div(c, CHUNK_SIZE as isize)))
+    }
+
+    /// Position of this block inside its chunk
+    pub fn local_pos(&self) -> LocalPos {
+        LocalPos(self.0.map(|c| floor_mod(c, CHUNK_SIZE as isize) as usize))
+    }
+
+    /// Position of the block offset by `(dx, dy, dz)`
+    pub fn offset(&self, dx: isize, dy: isize, dz: isize) -> Self {
+        BlockPos(self.0 + Vector3::new(dx, dy, dz))
+    }
+}
+
+/// An axis-aligned box of blocks, bounds included
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct BlockRegion {
+    pub min: BlockPos,
+    pub max: BlockPos,
+}
+
+impl BlockRegion {
+    /// Create the smallest region containing both corners
+    pub fn new(a: BlockPos, b: BlockPos) -> Self {
+        Self {
+            min: BlockPos(a.0.zip_map(&b.0, |a, b| a.min(b))),
+            max: BlockPos(a.0.zip_map(&b.0, |a, b| a.max(b))),
+        }
+    }
+
+    pub fn contains(&self, pos: &BlockPos) -> bool {
+        (0..3).all(|i| self.min.0[i] <= pos.0[i] && pos.0[i] <= self.max.0[i])
+    }
+
+    /// Overlap of two regions, if any
+    pub fn intersection(&self, other: &BlockRegion) -> Option<BlockRegion> {
+        let min = self.min.0.zip_map(&other.min.0, |a, b| a.max(b));
+        let max = self.max.0.zip_map(&other.max.0, |a, b| a.min(b));
+        if (0..3).all(|i| min[i] <= max[i]) {
+            Some(BlockRegion {
+                min: BlockPos(min),
+                max: BlockPos(max),
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Iterate over the positions of the region, z varying the fastest
+    pub fn iter(&self) -> impl Iterator<Item = BlockPos> {
+        let (min, max) = (self.min.0, self.max.0);
+        (min[0]..=max[0]).flat_map(move |x| {
+            (min[1]..=max[1])
+                .flat_map(move |y| (min[2]..=max[2]).map(move |z| BlockPos::new(x, y, z)))
+        })
+    }
+
+    /// Iterate over the positions of the chunks that overlap the region
+    pub fn chunks(&self) -> impl Iterator<Item = ChunkPos> {
+        let min = self.min.chunk_pos().0;
+        let max = self.max.chunk_pos().0;
+        BlockRegion::new(BlockPos(min), BlockPos(max))
+            .iter()
+            .map(|pos| ChunkPos(pos.0))
+    }
+}
+
+/// Event sent every time blocks of the `World` are changed
+#[derive(Debug, Clone, Copy)]
+pub struct BlockChange {
+    /// The changed blocks, all in the same chunk. A single block for `World::set_block`, the
+    /// overlap of the filled region with every chunk for `World::fill`.
+    pub region: BlockRegion,
+    pub block_id: BlockId,
+}
+
+/// The blocks of the world, stored in chunks.
+///
+/// Every edit is sent as a `BlockChange` event. Systems can read the events using a reader
+/// from `register_change_reader`. Edits also increase the version of the chunks showing the
+/// changed blocks, see `Chunk::version`.
+#[derive(Default)]
+pub struct World {
+    chunks: ChunkMap,
+    changes: EventChannel<BlockChange>,
+}
+
+impl World {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get_chunk(&self, pos: &ChunkPos) -> Option<&Chunk> {
+        self.chunks.get(pos)
+    }
+
+    pub fn get_chunk_mut(&mut self, pos: &ChunkPos) -> Option<&mut Chunk> {
+        self.chunks.get_mut(pos)
+    }
+
+    /// Add a chunk to the world, returning the chunk it replaced if any
+    pub fn insert_chunk(&mut self, pos: ChunkPos, chunk: Chunk) -> Option<Chunk> {
+        self.chunks.insert(pos, chunk)
+    }
+
+    pub fn remove_chunk(&mut self, pos: &ChunkPos) -> Option<Chunk> {
+        self.chunks.remove(pos)
+    }
+
+    pub fn chunks(&self) -> impl Iterator<Item = (&ChunkPos, &Chunk)> {
+        self.chunks.iter()
+    }
+
+    /// Get the id of the block at `pos`, or `None` if its chunk is not loaded
+    pub fn get_block(&self, pos: &BlockPos) -> Option<BlockId> {
+        let local = pos.local_pos().0;
+        self.chunks
+            .get(&pos.chunk_pos())
+            .map(|chunk| chunk.get(local[0], local[1], local[2]))
+    }
+
+    /// Set the block at `pos` and return the id of the block it replaced.
+    /// Does nothing and returns `None` if its chunk is not loaded.
+    pub fn set_block(&mut self, pos: &BlockPos, block_id: BlockId) -> Option<BlockId> {
+        let local = pos.local_pos().0;
+        let chunk = self.chunks.get_mut(&pos.chunk_pos())?;
+        let previous = chunk.get(local[0], local[1], local[2]);
+        if previous != block_id {
+            chunk.set(local[0], local[1], local[2], block_id);
+            chunk.modified = true;
+            self.changes.single_write(BlockChange {
+                region: BlockRegion::new(*pos, *pos),
+                block_id,
+            });
+            self.mark_changed(pos);
+        }
+        Some(previous)
+    }
+
+    /// Increase the version of the loaded chunks whose mesh shows the block at `pos`: its chunk,
+    /// and the neighbours that the block borders
+    pub fn mark_changed(&mut self, pos: &BlockPos) {
+        for chunk_pos in chunks_around_block(pos) {
+            if let Some(chunk) = self.chunks.get_mut(&chunk_pos) {
+                chunk.mark_changed();
+            }
+        }
+    }
+
+    /// Increase the version of the loaded chunks whose mesh shows a block of `region`, which is
+    /// inside a single chunk. The chunks around its corners include the chunks around any of its
+    /// blocks, and every chunk is only marked once.
+    fn mark_region_changed(&mut self, region: &BlockRegion) {
+        let (min, max) = (region.min.0, region.max.0);
+        let mut chunks = HashSet::new();
+        for &x in &[min[0], max[0]] {
+            for &y in &[min[1], max[1]] {
+                for &z in &[min[2], max[2]] {
+                    chunks.extend(chunks_around_block(&BlockPos::new(x, y, z)));
+                }
+            }
+        }
+        for chunk_pos in chunks {
+            if let Some(chunk) = self.chunks.get_mut(&chunk_pos) {
+                chunk.mark_changed();
+            }
+        }
+    }
+
+    /// Get a reader for the `BlockChange` events
+    pub fn register_change_reader(&mut self) -> ReaderId<BlockChange> {
+        self.changes.register_reader()
+    }
+
+    /// Read the `BlockChange` events that happened since the last read with `reader`
+    pub fn read_changes<'a>(
+        &'a self,
+        reader: &'a mut ReaderId<BlockChange>,
+    ) -> impl Iterator<Item = &'a BlockChange> {
+        self.changes.read(reader)
+    }
+
+    /// Iterate over the loaded blocks of a region, chunk by chunk
+    pub fn blocks_in<'a>(
+        &'a self,
+        region: &BlockRegion,
+    ) -> impl Iterator<Item = (BlockPos, BlockId)> + 'a {
+        let region = *region;
+        region.chunks().flat_map(move |chunk_pos| {
+            let chunk = self.chunks.get(&chunk_pos);
+            let blocks = chunk.and_then(|chunk| {
+                region
+                    .intersection(&chunk_pos.region())
+                    .map(|overlap| (chunk, overlap))
+            });
+            blocks.into_iter().flat_map(|(chunk, overlap)| {
+                overlap.iter().map(move |pos| {
+                    let local = pos.local_pos().0;
+                    (pos, chunk.get(local[0], local[1], local[2]))
+                })
+            })
+        })
+    }
+
+    /// Set every loaded block of a region to `block_id`, sending one `BlockChange` per chunk
+    pub fn fill(&mut self, region: &BlockRegion, block_id: BlockId) {
+        for chunk_pos in region.chunks() {
+            if let Some(chunk) = self.chunks.get_mut(&chunk_pos) {
+                if let Some(overlap) = region.intersection(&chunk_pos.region()) {
+                    for pos in overlap.iter() {
+                        let local = pos.local_pos().0;
+                        chunk.set(local[0], local[1], local[2], block_id);
+                    }
+                    chunk.modified = true;
+                    self.changes.single_write(BlockChange {
+                        region: overlap,
+                        block_id,
+                    });
+                    self.mark_region_changed(&overlap);
+                }
+            }
+        }
+    }
+}
+
+/// The entity holding the mesh of every meshed chunk
+#[derive(Default)]
+pub struct ChunkEntities(pub HashMap<ChunkPos, Entity>);
+
+/// The version of every meshed chunk when its mesh was built, including the chunks without an
+/// entity because they are invisible
+#[derive(Default)]
+pub struct MeshedChunks(pub HashMap<ChunkPos, u64>);
+
+/// A cube of `CHUNK_SIZE`^3 blocks.
+///
+/// Blocks are stored as indices into a per-chunk palette of block ids. The indices are
+/// bit-packed, and the number of bits per index grows when new block types are added. The
+/// palette entries of the block types that disappear are reused, and the indices are packed
+/// again when they fit in fewer bits. A chunk containing only one block type doesn't store any
+/// index at all.
+///
+/// The light levels of the blocks are stored next to them, see `light`.
+#[derive(Clone)]
 pub struct Chunk {
-    pub blocks: Box<[[[usize; CHUNK_SIZE]; CHUNK_SIZE]; CHUNK_SIZE]>,
+    palette: Vec<BlockId>,
+    /// Number of blocks using every palette entry. Unused entries are reused by new block types.
+    counts: Vec<u32>,
+    /// Number of bits used by one palette index. 0 if the palette has a single entry.
+    bits_per_block: usize,
+    data: Vec<u64>,
+    light: ChunkLight,
+    version: u64,
+    modified: bool,
 }
 
 impl Chunk {
-    pub fn filled(block_id: usize) -> Self {
+    pub fn filled(block_id: BlockId) -> Self {
         Self {
-            blocks: Box::new([[[block_id; CHUNK_SIZE]; CHUNK_SIZE]; CHUNK_SIZE]),
+            palette: vec![block_id],
+            counts: vec![CHUNK_VOLUME as u32],
+            bits_per_block: 0,
+            data: Vec::new(),
+            light: ChunkLight::dark(),
+            version: 0,
+            modified: false,
+        }
+    }
+
+    /// Get the id of the block at the given position inside the chunk
+    pub fn get(&self, x: usize, y: usize, z: usize) -> BlockId {
+        if self.bits_per_block == 0 {
+            self.palette[0]
+        } else {
+            self.palette[self.get_index(Self::block_offset(x, y, z))]
+        }
+    }
+
+    /// Set the id of the block at the given position inside the chunk
+    pub fn set(&mut self, x: usize, y: usize, z: usize, block_id: BlockId) {
+        let offset = Self::block_offset(x, y, z);
+        let previous_index = if self.bits_per_block == 0 {
+            0
+        } else {
+            self.get_index(offset)
+        };
+        if self.palette[previous_index] == block_id {
+            return;
+        }
+        let palette_index = match self.palette.iter().position(|id| *id == block_id) {
+            Some(palette_index) => palette_index,
+            None => match self.counts.iter().position(|count| *count == 0) {
+                Some(unused_index) => {
+                    self.palette[unused_index] = block_id;
+                    unused_index
+                }
+                None => {
+                    self.palette.push(block_id);
+                    self.counts.push(0);
+                    let required_bits = Self::required_bits(self.palette.len());
+                    if required_bits > self.bits_per_block {
+                        self.resize_indices(required_bits);
+                    }
+                    self.palette.len() - 1
+                }
+            },
+        };
+        self.set_index(offset, palette_index);
+        self.counts[previous_index] -= 1;
+        self.counts[palette_index] += 1;
+        if self.counts[previous_index] == 0 {
+            let used = self.counts.iter().filter(|count| **count != 0).count();
+            if Self::required_bits(used) < self.bits_per_block {
+                self.compact();
+            }
+        }
+    }
+
+    /// Return true if the whole chunk is made of a single block type
+    pub fn is_uniform(&self) -> bool {
+        self.bits_per_block == 0
+    }
+
+    /// Light levels of the blocks, dark until the chunk is lit
+    pub fn light(&self) -> &ChunkLight {
+        &self.light
+    }
+
+    pub fn light_mut(&mut self) -> &mut ChunkLight {
+        &mut self.light
+    }
+
+    /// Number of changes to the blocks or the light shown by the mesh of the chunk since it was
+    /// loaded. A mesh is outdated when the version changed since it was built.
+    ///
+    /// `set` doesn't change the version, the edits of a loaded chunk go through
+    /// `World::set_block` or `World::fill` which mark the neighbours as well.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn mark_changed(&mut self) {
+        self.version += 1;
+    }
+
+    /// Whether blocks were edited with `World::set_block` or `World::fill` since the chunk was
+    /// generated or loaded. Chunks that were not modified are the same as in their save, or can
+    /// be generated again, so they don't need to be saved.
+    pub fn is_modified(&self) -> bool {
+        self.modified
+    }
+
+    /// Replace every block id by `remap(id)`, e.g. to convert the ids of an older registry
+    pub fn remap<F: FnMut(BlockId) -> BlockId>(&mut self, mut remap: F) {
+        for block_id in self.palette.iter_mut() {
+            *block_id = remap(*block_id);
+        }
+        // Several ids can be replaced by the same one, in which case their entries are merged
+        let mut unique_ids = self.palette.clone();
+        unique_ids.sort();
+        unique_ids.dedup();
+        if unique_ids.len() != self.palette.len() {
+            self.compact();
+        }
+    }
+
+    /// Remove the unused and duplicate palette entries, and pack the indices with as few bits as
+    /// possible
+    fn compact(&mut self) {
+        let mut palette = Vec::new();
+        let mut counts: Vec<u32> = Vec::new();
+        let mut new_indices = Vec::with_capacity(self.palette.len());
+        for (block_id, count) in self.palette.iter().zip(self.counts.iter()) {
+            let new_index = match palette.iter().position(|id| id == block_id) {
+                Some(new_index) => new_index,
+                None if *count != 0 => {
+                    palette.push(*block_id);
+                    counts.push(0);
+                    palette.len() - 1
+                }
+                // Unused, nothing refers to it
+                None => 0,
+            };
+            if *count != 0 {
+                counts[new_index] += count;
+            }
+            new_indices.push(new_index);
+        }
+
+        let bits_per_block = Self::required_bits(palette.len());
+        let data = if bits_per_block == 0 {
+            Vec::new()
+        } else {
+            let mut data = Self::empty_data(bits_per_block);
+            for offset in 0..CHUNK_VOLUME {
+                let index = new_indices[self.get_index(offset)];
+                Self::write_index(&mut data, bits_per_block, offset, index);
+            }
+            data
+        };
+        self.palette = palette;
+        self.counts = counts;
+        self.bits_per_block = bits_per_block;
+        self.data = data;
+    }
+
+    fn block_offset(x: usize, y: usize, z: usize) -> usize {
+        debug_assert!(x < CHUNK_SIZE && y < CHUNK_SIZE && z < CHUNK_SIZE);
+        (x * CHUNK_SIZE + y) * CHUNK_SIZE + z
+    }
+
+    /// Smallest number of bits that can hold `palette_len` different indices
+    fn required_bits(palette_len: usize) -> usize {
+        let mut bits = 0;
+        while (1 << bits) < palette_len {
+            bits += 1;
+        }
+        bits
+    }
+
+    /// Indices never straddle two words, so some bits may be left unused at the end of a word
+    fn indices_per_word(bits_per_block: usize) -> usize {
+        64 / bits_per_block
+    }
+
+    fn get_index(&self, offset: usize) -> usize {
+        let per_word = Self::indices_per_word(self.bits_per_block);
+        let shift = (offset % per_word) * self.bits_per_block;
+        let mask = (1u64 << self.bits_per_block) - 1;
+        ((self.data[offset / per_word] >> shift) & mask) as usize
+    }
+
+    fn set_index(&mut self, offset: usize, index: usize) {
+        Self::write_index(&mut self.data, self.bits_per_block, offset, index);
+    }
+
+    fn write_index(data: &mut [u64], bits_per_block: usize, offset: usize, index: usize) {
+        let per_word = Self::indices_per_word(bits_per_block);
+        let shift = (offset % per_word) * bits_per_block;
+        let mask = (1u64 << bits_per_block) - 1;
+        let word = &mut data[offset / per_word];
+        *word = (*word & !(mask << shift)) | ((index as u64 & mask) << shift);
+    }
+
+    /// Indices of a whole chunk, all set to 0
+    fn empty_data(bits_per_block: usize) -> Vec<u64> {
+        let per_word = Self::indices_per_word(bits_per_block);
+        vec![0; (CHUNK_VOLUME + per_word - 1) / per_word]
+    }
+
+    /// Repack every index using more bits, `bits_per_block`
+    fn resize_indices(&mut self, bits_per_block: usize) {
+        let mut data = Self::empty_data(bits_per_block);
+        // A chunk without indices only uses the first palette entry, i.e. index 0
+        if self.bits_per_block != 0 {
+            for offset in 0..CHUNK_VOLUME {
+                Self::write_index(&mut data, bits_per_block, offset, self.get_index(offset));
+            }
         }
+        self.bits_per_block = bits_per_block;
+        self.data = data;
+    }
+}
+
+/// Blocks shared by the tests working on chunks and worlds
+#[cfg(test)]
+pub mod test_blocks {
+    use super::{Block, BlockId, BlockTextures};
+    use crate::registry::Registry;
+
+    pub const AIR: BlockId = BlockId::from_index(0);
+    pub const STONE: BlockId = BlockId::from_index(1);
+    pub const DIRT: BlockId = BlockId::from_index(2);
+    /// Tinted by the biome color on its top face
+    pub const GRASS: BlockId = BlockId::from_index(3);
+    /// Emits light, and has no texture
+    pub const LAMP: BlockId = BlockId::from_index(4);
+
+    /// A registry with the blocks above, in this order, named `default:<block>`
+    pub fn block_registry() -> Registry<Block> {
+        let mut block_registry = Registry::new();
+        let air = Block {
+            solid: false,
+            transparent: true,
+            ..Block::default()
+        };
+        block_registry.register("default:air", air).unwrap();
+        for name in ["stone", "dirt"].iter() {
+            let block = Block {
+                textures: Some(BlockTextures::all(*name)),
+                ..Block::default()
+            };
+            block_registry
+                .register(format!("default:{}", name), block)
+                .unwrap();
+        }
+        let grass = Block {
+            textures: Some(BlockTextures::top_side_bottom(
+                "grass",
+                "grass_side",
+                "dirt",
+            )),
+            tinted: true,
+            ..Block::default()
+        };
+        block_registry.register("default:grass", grass).unwrap();
+        let lamp = Block {
+            light_emission: 12,
+            ..Block::default()
+        };
+        block_registry.register("default:lamp", lamp).unwrap();
+        block_registry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        test_blocks::{AIR, DIRT, GRASS, LAMP, STONE},
+        *,
+    };
+
+    const SIZE: isize = CHUNK_SIZE as isize;
+
+    #[test]
+    fn floor_division() {
+        for &(a, div, rem) in &[
+            (0, 0, 0),
+            (1, 0, 1),
+            (SIZE - 1, 0, SIZE - 1),
+            (SIZE, 1, 0),
+            (-1, -1, SIZE - 1),
+            (-SIZE + 1, -1, 1),
+            (-SIZE, -1, 0),
+            (-SIZE - 1, -2, SIZE - 1),
+            (-2 * SIZE, -2, 0),
+        ] {
+            assert_eq!(floor_div(a, SIZE), div, "{}", a);
+            assert_eq!(floor_mod(a, SIZE), rem, "{}", a);
+        }
+    }
+
+    #[test]
+    fn negative_block_positions() {
+        for &(c, chunk, local) in &[
+            (0, 0, 0),
+            (-1, -1, SIZE - 1),
+            (-SIZE + 1, -1, 1),
+            (-SIZE, -1, 0),
+            (-SIZE - 1, -2, SIZE - 1),
+        ] {
+            let pos = BlockPos::new(c, c, c);
+            assert_eq!(pos.chunk_pos(), ChunkPos(Vector3::new(chunk, chunk, chunk)));
+            let local = local as usize;
+            assert_eq!(pos.local_pos(), LocalPos(Vector3::new(local, local, local)));
+            // The chunk and the local position give the block back
+            assert_eq!(pos.chunk_pos().block_pos(&pos.local_pos()), pos);
+        }
+
+        let pos = BlockPos::new(-1, SIZE, -SIZE - 1);
+        assert_eq!(pos.chunk_pos(), ChunkPos(Vector3::new(-1, 1, -2)));
+        assert_eq!(
+            pos.local_pos(),
+            LocalPos(Vector3::new(CHUNK_SIZE - 1, 0, CHUNK_SIZE - 1))
+        );
+    }
+
+    #[test]
+    fn region_chunks() {
+        let chunks = |min: (isize, isize, isize), max: (isize, isize, isize)| {
+            let region = BlockRegion::new(
+                BlockPos::new(min.0, min.1, min.2),
+                BlockPos::new(max.0, max.1, max.2),
+            );
+            region
+                .chunks()
+                .map(|pos| (pos.0[0], pos.0[1], pos.0[2]))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(chunks((-1, -1, -1), (-1, -1, -1)), vec![(-1, -1, -1)]);
+        assert_eq!(chunks((-SIZE, 0, 0), (-1, 0, 0)), vec![(-1, 0, 0)]);
+        assert_eq!(
+            chunks((-SIZE - 1, 0, 0), (0, 0, 0)),
+            vec![(-2, 0, 0), (-1, 0, 0), (0, 0, 0)]
+        );
+        assert_eq!(
+            chunks((0, -1, 0), (0, 0, -SIZE)),
+            vec![(0, -1, -1), (0, -1, 0), (0, 0, -1), (0, 0, 0)]
+        );
+    }
+
+    #[test]
+    fn chunks_around_block_borders() {
+        let chunks = |x, y, z| {
+            let mut chunks: Vec<Vector3<isize>> = chunks_around_block(&BlockPos::new(x, y, z))
+                .into_iter()
+                .map(|pos| pos.0)
+                .collect();
+            chunks.sort_by_key(|pos| (pos[0], pos[1], pos[2]));
+            chunks
+        };
+        let size = CHUNK_SIZE as isize;
+        assert_eq!(chunks(3, 5, 7), vec![Vector3::zeros()]);
+        // On a face
+        assert_eq!(
+            chunks(size - 1, 5, 7),
+            vec![Vector3::new(0, 0, 0), Vector3::new(1, 0, 0)]
+        );
+        // On an edge of a chunk with negative coordinates
+        assert_eq!(
+            chunks(-size, 5, -1),
+            vec![
+                Vector3::new(-2, 0, -1),
+                Vector3::new(-2, 0, 0),
+                Vector3::new(-1, 0, -1),
+                Vector3::new(-1, 0, 0),
+            ]
+        );
+        // In a corner
+        let corner = chunks(0, 0, 0);
+        assert_eq!(corner.len(), 8);
+        assert!(corner.contains(&Vector3::new(-1, -1, -1)));
+        assert!(!corner.contains(&Vector3::new(1, 0, 0)));
+    }
+
+    #[test]
+    fn fill_sends_one_change_per_chunk() {
+        let mut world = World::new();
+        for x in -1..=1 {
+            world.insert_chunk(ChunkPos(Vector3::new(x, 0, 0)), Chunk::filled(AIR));
+        }
+        let mut reader = world.register_change_reader();
+        // Across the three chunks, and into the chunks behind them that are not loaded
+        let region = BlockRegion::new(BlockPos::new(-5, 0, 0), BlockPos::new(SIZE + 4, 3, SIZE));
+        world.fill(&region, STONE);
+
+        let changes: Vec<BlockRegion> = world
+            .read_changes(&mut reader)
+            .map(|change| change.region)
+            .collect();
+        let overlap = |min_x, max_x| {
+            BlockRegion::new(
+                BlockPos::new(min_x, 0, 0),
+                BlockPos::new(max_x, 3, SIZE - 1),
+            )
+        };
+        assert_eq!(
+            changes,
+            vec![
+                overlap(-5, -1),
+                overlap(0, SIZE - 1),
+                overlap(SIZE, SIZE + 4)
+            ]
+        );
+        assert!(world
+            .blocks_in(&region)
+            .all(|(_, block_id)| block_id == STONE));
+        assert_eq!(world.get_block(&BlockPos::new(0, 4, 0)), Some(AIR));
+    }
+
+    /// A block among the ids from 0 to `types - 1`, scattered so that every one is used
+    fn pattern(types: usize, x: usize, y: usize, z: usize) -> BlockId {
+        BlockId::from_index(Chunk::block_offset(x, y, z) * 7919 % types)
+    }
+
+    /// Set every block of a chunk to the `pattern`, and check that they read back
+    fn fill_pattern(chunk: &mut Chunk, types: usize) {
+        for x in 0..CHUNK_SIZE {
+            for y in 0..CHUNK_SIZE {
+                for z in 0..CHUNK_SIZE {
+                    chunk.set(x, y, z, pattern(types, x, y, z));
+                }
+            }
+        }
+        for x in 0..CHUNK_SIZE {
+            for y in 0..CHUNK_SIZE {
+                for z in 0..CHUNK_SIZE {
+                    assert_eq!(chunk.get(x, y, z), pattern(types, x, y, z));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn palette_bit_widths() {
+        for &(types, bits) in &[(2, 1), (3, 2), (4, 2), (5, 3), (17, 5), (300, 9)] {
+            let mut chunk = Chunk::filled(AIR);
+            fill_pattern(&mut chunk, types);
+            assert_eq!(chunk.palette.len(), types);
+            assert_eq!(chunk.bits_per_block, bits, "{} block types", types);
+            assert!(!chunk.is_uniform());
+            // Edits keep the other blocks
+            chunk.set(3, 4, 5, STONE);
+            chunk.set(31, 0, 31, AIR);
+            assert_eq!(chunk.get(3, 4, 5), STONE);
+            assert_eq!(chunk.get(31, 0, 31), AIR);
+            assert_eq!(chunk.get(3, 4, 6), pattern(types, 3, 4, 6));
+        }
+    }
+
+    #[test]
+    fn palette_shrinks() {
+        let mut chunk = Chunk::filled(AIR);
+        fill_pattern(&mut chunk, 5);
+        assert_eq!(chunk.bits_per_block, 3);
+        // Once the block types that are left fit in fewer bits, the indices are packed again
+        fill_pattern(&mut chunk, 3);
+        assert_eq!(chunk.bits_per_block, 2);
+
+        // A chunk edited back to a single block type is uniform again
+        for x in 0..CHUNK_SIZE {
+            for y in 0..CHUNK_SIZE {
+                for z in 0..CHUNK_SIZE {
+                    chunk.set(x, y, z, AIR);
+                }
+            }
+        }
+        assert!(chunk.is_uniform());
+        assert_eq!(chunk.palette, vec![AIR]);
+        assert!(chunk.data.is_empty());
+        assert_eq!(chunk.get(7, 8, 9), AIR);
+    }
+
+    #[test]
+    fn unused_palette_entries_are_reused() {
+        let mut chunk = Chunk::filled(AIR);
+        chunk.set(0, 0, 0, STONE);
+        chunk.set(1, 0, 0, DIRT);
+        chunk.set(2, 0, 0, GRASS);
+        assert_eq!(chunk.bits_per_block, 2);
+        // Stone is not used anymore, its entry is given to the next new block type
+        chunk.set(0, 0, 0, AIR);
+        chunk.set(3, 0, 0, LAMP);
+        assert_eq!(chunk.palette, vec![AIR, LAMP, DIRT, GRASS]);
+        assert_eq!(chunk.bits_per_block, 2);
+        let blocks: Vec<BlockId> = (0..4).map(|x| chunk.get(x, 0, 0)).collect();
+        assert_eq!(blocks, vec![AIR, DIRT, GRASS, LAMP]);
+    }
+
+    #[test]
+    fn remap_merges_entries() {
+        let mut chunk = Chunk::filled(AIR);
+        fill_pattern(&mut chunk, 3);
+        chunk.remap(|id| if id == AIR { STONE } else { id });
+        assert_eq!(chunk.palette.len(), 2);
+        assert_eq!(chunk.bits_per_block, 1);
+        for x in 0..CHUNK_SIZE {
+            for y in 0..CHUNK_SIZE {
+                for z in 0..CHUNK_SIZE {
+                    let block_id = pattern(3, x, y, z);
+                    let expected = if block_id == AIR { STONE } else { block_id };
+                    assert_eq!(chunk.get(x, y, z), expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn edits_change_versions() {
+        let mut world = World::new();
+        let positions: Vec<ChunkPos> = (-1..=1).map(|x| ChunkPos(Vector3::new(x, 0, 0))).collect();
+        for pos in &positions {
+            world.insert_chunk(*pos, Chunk::filled(AIR));
+        }
+        let versions = |world: &World| -> Vec<u64> {
+            positions
+                .iter()
+                .map(|pos| world.get_chunk(pos).unwrap().version())
+                .collect()
+        };
+        assert_eq!(versions(&world), vec![0, 0, 0]);
+
+        // Inside the middle chunk
+        world.set_block(&BlockPos::new(5, 5, 5), STONE);
+        assert_eq!(versions(&world), vec![0, 1, 0]);
+        // Setting the same block again is not a change
+        world.set_block(&BlockPos::new(5, 5, 5), STONE);
+        assert_eq!(versions(&world), vec![0, 1, 0]);
+        // On the borders, next to the chunks on both sides
+        world.set_block(&BlockPos::new(0, 5, 5), STONE);
+        assert_eq!(versions(&world), vec![1, 2, 0]);
+        world.set_block(&BlockPos::new(SIZE - 1, 5, 5), STONE);
+        assert_eq!(versions(&world), vec![1, 3, 1]);
+        // On a corner, next to chunks that are not loaded
+        world.set_block(&BlockPos::new(-1, 0, 0), STONE);
+        assert_eq!(versions(&world), vec![2, 4, 1]);
+        // A fill marks every chunk showing its blocks once
+        let region = BlockRegion::new(BlockPos::new(-3, 1, 1), BlockPos::new(-1, 2, 2));
+        world.fill(&region, DIRT);
+        assert_eq!(versions(&world), vec![3, 5, 1]);
     }
 }
@@ -0,0 +1,426 @@
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::Arc};
+
+use super::{
+    features::{Feature, FeatureConfig},
+    GeneratorError,
+};
+use crate::{
+    noise::FractalNoise,
+    registry::{Id, Registry},
+    world::{Block, BlockId, BlockPos, ColumnPos, CHUNK_SIZE},
+};
+
+/// Settings of a biome of the `noise` generator
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BiomeConfig {
+    pub name: String,
+    /// Temperature where the biome is found, between -1 and 1. Most of the world is between
+    /// -0.5 and 0.5.
+    pub temperature: f64,
+    /// Humidity where the biome is found, with the same range as `temperature`
+    pub humidity: f64,
+    /// Block at the top of the terrain
+    pub top_block: String,
+    /// Block below the top one
+    pub filler_block: String,
+    /// Number of filler blocks below the top one
+    pub filler_depth: usize,
+    /// Multiplier of the terrain amplitude
+    pub height_scale: f64,
+    /// Features placed in the chunks of the biome, after the ones of every biome
+    #[serde(default)]
+    pub features: Vec<FeatureConfig>,
+    /// Color of the grass, as linear RGB multipliers of its texture
+    pub grass_tint: [f32; 3],
+}
+
+impl BiomeConfig {
+    /// The biomes of the default world
+    pub fn defaults() -> Vec<BiomeConfig> {
+        let biome = |name: &str, temperature, humidity, top: &str, filler: &str| BiomeConfig {
+            name: name.to_owned(),
+            temperature,
+            humidity,
+            top_block: top.to_owned(),
+            filler_block: filler.to_owned(),
+            filler_depth: 3,
+            height_scale: 1.0,
+            features: Vec::new(),
+            grass_tint: [0.55, 0.8, 0.35],
+        };
+        vec![
+            biome("default:plains", 0.0, 0.0, "default:grass", "default:dirt"),
+            BiomeConfig {
+                height_scale: 0.5,
+                grass_tint: [0.75, 0.7, 0.4],
+                ..biome(
+                    "default:desert",
+                    0.25,
+                    -0.15,
+                    "default:sand",
+                    "default:sand",
+                )
+            },
+            BiomeConfig {
+                height_scale: 2.0,
+                features: vec![FeatureConfig::Boulder {
+                    block: "default:stone".to_owned(),
+                    chance: 0.6,
+                    min_radius: 2.0,
+                    max_radius: 4.0,
+                }],
+                grass_tint: [0.4, 0.7, 0.35],
+                ..biome(
+                    "default:hills",
+                    -0.05,
+                    0.25,
+                    "default:grass",
+                    "default:dirt",
+                )
+            },
+            BiomeConfig {
+                filler_depth: 1,
+                height_scale: 0.8,
+                grass_tint: [0.6, 0.75, 0.6],
+                ..biome(
+                    "default:tundra",
+                    -0.25,
+                    -0.15,
+                    "default:snow",
+                    "default:dirt",
+                )
+            },
+        ]
+    }
+}
+
+/// Settings of the climate noise choosing the biomes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClimateConfig {
+    /// Horizontal size of the climate zones, in blocks
+    pub scale: f64,
+    /// Width of the transitions between biomes, as a distance between climates. Larger values
+    /// give smoother borders.
+    pub blend: f64,
+}
+
+impl Default for ClimateConfig {
+    fn default() -> Self {
+        Self {
+            scale: 512.0,
+            blend: 0.1,
+        }
+    }
+}
+
+/// A biome, with the blocks of its settings resolved
+pub struct Biome {
+    pub temperature: f64,
+    pub humidity: f64,
+    pub top_block: BlockId,
+    pub filler_block: BlockId,
+    pub filler_depth: usize,
+    pub height_scale: f64,
+    pub features: Vec<Box<dyn Feature>>,
+    pub grass_tint: [f32; 3],
+}
+
+/// The id of a biome in the `Registry<Biome>` of a `BiomeSource`
+pub type BiomeId = Id<Biome>;
+
+/// Seed offsets of the climate noises
+const TEMPERATURE_SEED: u64 = 0x7465_6d70;
+const HUMIDITY_SEED: u64 = 0x6875_6d69;
+
+/// Chooses the biomes of the world from a temperature and a humidity noise.
+///
+/// Every column of blocks gets the biome whose climate is the closest to its own. Values that
+/// differ between biomes, like the height scale, are blended near the borders so that the terrain
+/// stays continuous. Biomes only depend on the horizontal position.
+pub struct BiomeSource {
+    biomes: Registry<Biome>,
+    config: ClimateConfig,
+    temperature_noise: FractalNoise,
+    humidity_noise: FractalNoise,
+}
+
+impl BiomeSource {
+    /// Fails if there is no biome, or if a biome uses a block that is not registered
+    pub fn new(
+        seed: u64,
+        config: &ClimateConfig,
+        biome_configs: &[BiomeConfig],
+        block_registry: &Registry<Block>,
+    ) -> Result<Self, GeneratorError> {
+        if biome_configs.is_empty() {
+            return Err(GeneratorError::NoBiomes);
+        }
+        let mut biomes = Registry::new();
+        for biome in biome_configs {
+            let features = biome
+                .features
+                .iter()
+                .map(|feature| feature.create(block_registry))
+                .collect::<Result<_, _>>()?;
+            biomes.register(
+                biome.name.clone(),
+                Biome {
+                    temperature: biome.temperature,
+                    humidity: biome.humidity,
+                    top_block: block_registry.get_item_id(&biome.top_block)?,
+                    filler_block: block_registry.get_item_id(&biome.filler_block)?,
+                    filler_depth: biome.filler_depth,
+                    height_scale: biome.height_scale,
+                    features,
+                    grass_tint: biome.grass_tint,
+                },
+            )?;
+        }
+        Ok(Self {
+            biomes,
+            config: config.clone(),
+            temperature_noise: FractalNoise::new(seed ^ TEMPERATURE_SEED, 3, 2.0, 0.5),
+            humidity_noise: FractalNoise::new(seed ^ HUMIDITY_SEED, 3, 2.0, 0.5),
+        })
+    }
+
+    pub fn biomes(&self) -> &Registry<Biome> {
+        &self.biomes
+    }
+
+    /// Temperature and humidity of the column at `(x, z)`
+    pub fn climate(&self, x: isize, z: isize) -> (f64, f64) {
+        let (x, z) = (x as f64 / self.config.scale, z as f64 / self.config.scale);
+        (
+            self.temperature_noise.get2(x, z),
+            self.humidity_noise.get2(x, z),
+        )
+    }
+
+    /// The biome of the column containing `pos`
+    pub fn biome_at(&self, pos: &BlockPos) -> BiomeId {
+        self.weights(pos.0[0], pos.0[2])[0].0
+    }
+
+    /// Contribution of the biomes to the column at `(x, z)`, from the largest to the smallest.
+    /// The weights add up to 1, and the biome of the column comes first.
+    pub fn weights(&self, x: isize, z: isize) -> Vec<(BiomeId, f64)> {
+        let (temperature, humidity) = self.climate(x, z);
+        let mut distances: Vec<(BiomeId, f64)> = self
+            .biomes
+            .iter()
+            .map(|(id, biome)| {
+                let distance_squared =
+                    (biome.temperature - temperature).powi(2) + (biome.humidity - humidity).powi(2);
+                (id, distance_squared)
+            })
+            .collect();
+        distances.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+
+        // Biomes farther than the closest one fade out over `blend`
+        let closest = distances[0].1;
+        let mut weights: Vec<(BiomeId, f64)> = distances
+            .into_iter()
+            .map(|(id, distance)| {
+                (
+                    id,
+                    (-((distance - closest) / self.config.blend).powi(2)).exp(),
+                )
+            })
+            .filter(|(_, weight)| *weight > 1e-3)
+            .collect();
+        let total: f64 = weights.iter().map(|(_, weight)| weight).sum();
+        for (_, weight) in weights.iter_mut() {
+            *weight /= total;
+        }
+        weights
+    }
+
+    /// Height scale of the column at `(x, z)`, blended between the biomes around it
+    pub fn height_scale(&self, x: isize, z: isize) -> f64 {
+        self.blend(&self.weights(x, z), |biome| biome.height_scale)
+    }
+
+    /// Compute the biomes of a column of chunks
+    pub fn biome_map(&self, column: &ColumnPos) -> BiomeMap {
+        let origin = column.origin();
+        let mut biomes = Vec::with_capacity(CHUNK_SIZE * CHUNK_SIZE);
+        let mut height_scales = Vec::with_capacity(CHUNK_SIZE * CHUNK_SIZE);
+        let mut grass_tints = Vec::with_capacity(CHUNK_SIZE * CHUNK_SIZE);
+        for x in 0..CHUNK_SIZE as isize {
+            for z in 0..CHUNK_SIZE as isize {
+                let weights = self.weights(origin[0] + x, origin[1] + z);
+                let mut grass_tint = [0.0; 3];
+                for (i, tint) in grass_tint.iter_mut().enumerate() {
+                    *tint = self.blend(&weights, |biome| f64::from(biome.grass_tint[i])) as f32;
+                }
+                biomes.push(weights[0].0);
+                height_scales.push(self.blend(&weights, |biome| biome.height_scale));
+                grass_tints.push(grass_tint);
+            }
+        }
+        BiomeMap {
+            biomes,
+            height_scales,
+            grass_tints,
+        }
+    }
+
+    /// Weighted average of a value of the biomes
+    fn blend<F>(&self, weights: &[(BiomeId, f64)], value: F) -> f64
+    where
+        F: Fn(&Biome) -> f64,
+    {
+        weights
+            .iter()
+            .map(|(id, weight)| value(&self.biomes[*id]) * weight)
+            .sum()
+    }
+}
+
+/// The biomes of the columns of blocks of a column of chunks, with the values blended between
+/// biomes
+#[derive(Debug, Clone, PartialEq)]
+pub struct BiomeMap {
+    biomes: Vec<BiomeId>,
+    height_scales: Vec<f64>,
+    grass_tints: Vec<[f32; 3]>,
+}
+
+impl BiomeMap {
+    /// Biome of the column at `(x, z)`, relative to the origin of the chunk column
+    pub fn get(&self, x: usize, z: usize) -> BiomeId {
+        self.biomes[Self::index(x, z)]
+    }
+
+    pub fn height_scale(&self, x: usize, z: usize) -> f64 {
+        self.height_scales[Self::index(x, z)]
+    }
+
+    pub fn grass_tint(&self, x: usize, z: usize) -> [f32; 3] {
+        self.grass_tints[Self::index(x, z)]
+    }
+
+    fn index(x: usize, z: usize) -> usize {
+        x * CHUNK_SIZE + z
+    }
+}
+
+/// The biome maps of the loaded chunk columns. They are shared with the meshing jobs, which
+/// color the tinted blocks.
+#[derive(Default)]
+pub struct BiomeMaps {
+    maps: HashMap<ColumnPos, Arc<BiomeMap>>,
+}
+
+impl BiomeMaps {
+    pub fn get(&self, column: &ColumnPos) -> Option<&Arc<BiomeMap>> {
+        self.maps.get(column)
+    }
+
+    pub fn insert(&mut self, column: ColumnPos, biome_map: BiomeMap) {
+        self.maps.insert(column, Arc::new(biome_map));
+    }
+
+    /// Remove the biome maps of the columns matching `predicate`
+    pub fn remove_where<F>(&mut self, mut predicate: F)
+    where
+        F: FnMut(&ColumnPos) -> bool,
+    {
+        self.maps.retain(|column, _| !predicate(column));
+    }
+
+    /// Get the biome of the column containing `pos`, or `None` if its biome map is not loaded
+    pub fn get_biome(&self, pos: &BlockPos) -> Option<BiomeId> {
+        let local = pos.local_pos().0;
+        self.maps
+            .get(&pos.chunk_pos().column())
+            .map(|biome_map| biome_map.get(local[0], local[2]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn biome_source() -> BiomeSource {
+        let mut block_registry = Registry::new();
+        for name in &[
+            "default:air",
+            "default:dirt",
+            "default:grass",
+            "default:sand",
+            "default:snow",
+            "default:stone",
+        ] {
+            block_registry.register(*name, Block::default()).unwrap();
+        }
+        BiomeSource::new(
+            42,
+            &ClimateConfig::default(),
+            &BiomeConfig::defaults(),
+            &block_registry,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn every_biome_is_found() {
+        let source = biome_source();
+        let mut found = vec![false; source.biomes().len()];
+        for x in -64..64 {
+            for z in -64..64 {
+                let biome = source.biome_at(&BlockPos::new(x * 64, 0, z * 64));
+                found[biome.index()] = true;
+            }
+        }
+        assert!(found.iter().all(|found| *found), "{:?}", found);
+    }
+
+    #[test]
+    fn biomes_blend_at_borders() {
+        let source = biome_source();
+        let mut borders = Vec::new();
+        for x in -2048..2048 {
+            let pos = BlockPos::new(x, 0, 100);
+            let next = pos.offset(1, 0, 0);
+            if source.biome_at(&pos) != source.biome_at(&next) {
+                borders.push(pos);
+            }
+            // Neighbouring columns have close height scales, even across borders
+            let scale = source.height_scale(pos.0[0], pos.0[2]);
+            let next_scale = source.height_scale(next.0[0], next.0[2]);
+            assert!(
+                (scale - next_scale).abs() < 0.05,
+                "{} {}",
+                scale,
+                next_scale
+            );
+        }
+        assert!(!borders.is_empty());
+
+        // The biome map of a column crossed by a border matches the biomes of its blocks
+        let column = borders[0].chunk_pos().column();
+        let map = source.biome_map(&column);
+        let mut biome_maps = BiomeMaps::default();
+        biome_maps.insert(column, map.clone());
+        let origin = column.origin();
+        for x in 0..CHUNK_SIZE {
+            for z in 0..CHUNK_SIZE {
+                let pos = BlockPos::new(origin[0] + x as isize, 0, origin[1] + z as isize);
+                assert_eq!(map.get(x, z), source.biome_at(&pos));
+                assert_eq!(
+                    map.height_scale(x, z),
+                    source.height_scale(pos.0[0], pos.0[2])
+                );
+                // At any height
+                let pos = pos.offset(0, -100, 0);
+                assert_eq!(biome_maps.get_biome(&pos), Some(source.biome_at(&pos)));
+            }
+        }
+        let outside = BlockPos::new(origin[0] - 1, 0, origin[1]);
+        assert_eq!(biome_maps.get_biome(&outside), None);
+    }
+}
@@ -0,0 +1,310 @@
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+
+use crate::{
+    noise::{FractalNoise, SplitMix64},
+    world::{floor_div, floor_mod, BlockPos, BlockRegion, CHUNK_SIZE},
+};
+
+/// Settings of the caves carved by the `noise` generator
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CaveConfig {
+    /// Size of the cheese caves, in blocks
+    pub cheese_scale: f64,
+    /// Blocks where the cave noise is above this value are carved. The noise is between -1 and 1,
+    /// higher values give fewer caves.
+    pub cheese_threshold: f64,
+    /// Number of blocks between the surface and the top of the cheese caves
+    pub cheese_min_depth: isize,
+    /// Probability that a worm tunnel starts in a chunk
+    pub worm_chance: f64,
+    /// Length of the worm tunnels, in blocks
+    pub worm_length: usize,
+    /// Radius of the worm tunnels at their ends, they are 50% larger in their middle
+    pub worm_radius: f64,
+}
+
+impl Default for CaveConfig {
+    fn default() -> Self {
+        Self {
+            cheese_scale: 48.0,
+            cheese_threshold: 0.3,
+            cheese_min_depth: 8,
+            worm_chance: 0.25,
+            worm_length: 96,
+            worm_radius: 2.0,
+        }
+    }
+}
+
+/// Seed offset of the cheese noise, so that it differs from the height map noise
+const CHEESE_SEED: u64 = 0x6361_7665;
+/// Distance between two samples of the cheese noise, in blocks
+const CHEESE_STEP: isize = 4;
+
+/// Carves caves out of the terrain.
+///
+/// Whether a block is carved only depends on its position, so caves are seamless whatever the
+/// chunks being generated. Worm tunnels start in a chunk and can cross into its neighbours: every
+/// chunk generates the worms of the chunks around it and keeps the blocks that fall inside it.
+#[derive(Clone)]
+pub struct CaveCarver {
+    config: CaveConfig,
+    seed: u64,
+    cheese_noise: FractalNoise,
+}
+
+impl CaveCarver {
+    pub fn new(seed: u64, config: &CaveConfig) -> Self {
+        Self {
+            config: config.clone(),
+            seed,
+            cheese_noise: FractalNoise::new(seed ^ CHEESE_SEED, 2, 2.0, 0.5),
+        }
+    }
+
+    /// Call `carve` with the blocks of `region` that are part of a cave. `surface_height` gives
+    /// the height of the first air block of a column. A block can be carved more than once.
+    pub fn carve<H, F>(&self, region: &BlockRegion, surface_height: H, mut carve: F)
+    where
+        H: Fn(isize, isize) -> isize,
+        F: FnMut(BlockPos),
+    {
+        self.carve_cheese(region, &surface_height, &mut carve);
+        self.carve_worms(region, &surface_height, &mut carve);
+    }
+
+    /// Large caves where the 3D noise is high, squashed vertically. The noise is sampled every
+    /// `CHEESE_STEP` blocks and interpolated in between.
+    fn carve_cheese<H, F>(&self, region: &BlockRegion, surface_height: &H, carve: &mut F)
+    where
+        H: Fn(isize, isize) -> isize,
+        F: FnMut(BlockPos),
+    {
+        let (min, max) = (region.min.0, region.max.0);
+        let tops: Vec<isize> = (min[0]..=max[0])
+            .flat_map(|x| (min[2]..=max[2]).map(move |z| (x, z)))
+            .map(|(x, z)| surface_height(x, z) - self.config.cheese_min_depth)
+            .collect();
+        let highest_top = tops.iter().cloned().max().unwrap_or(min[1]);
+        if highest_top <= min[1] {
+            return;
+        }
+
+        // Noise samples at the corners of the lattice cells overlapping the region
+        let lattice_min: Vec<isize> = (0..3).map(|i| floor_div(min[i], CHEESE_STEP)).collect();
+        let size: Vec<usize> = (0..3)
+            .map(|i| (floor_div(max[i], CHEESE_STEP) + 2 - lattice_min[i]) as usize)
+            .collect();
+        let scale = self.config.cheese_scale;
+        let mut samples = Vec::with_capacity(size[0] * size[1] * size[2]);
+        for i in 0..size[0] {
+            for j in 0..size[1] {
+                for k in 0..size[2] {
+                    let corner = |axis: usize, index: usize| {
+                        ((lattice_min[axis] + index as isize) * CHEESE_STEP) as f64
+                    };
+                    samples.push(self.cheese_noise.get3(
+                        corner(0, i) / scale,
+                        corner(1, j) * 2.0 / scale,
+                        corner(2, k) / scale,
+                    ));
+                }
+            }
+        }
+        let sample = |i: usize, j: usize, k: usize| samples[(i * size[1] + j) * size[2] + k];
+        let lerp = |t: f64, a: f64, b: f64| a + t * (b - a);
+        // Lattice cell of a coordinate, and its position in the cell between 0 and 1
+        let cell = |axis: usize, c: isize| {
+            (
+                (floor_div(c, CHEESE_STEP) - lattice_min[axis]) as usize,
+                floor_mod(c, CHEESE_STEP) as f64 / CHEESE_STEP as f64,
+            )
+        };
+
+        let mut tops = tops.into_iter();
+        for x in min[0]..=max[0] {
+            let (i, tx) = cell(0, x);
+            for z in min[2]..=max[2] {
+                let (k, tz) = cell(2, z);
+                let top = tops.next().unwrap();
+                for y in min[1]..=max[1].min(top - 1) {
+                    let (j, ty) = cell(1, y);
+                    let noise = lerp(
+                        tx,
+                        lerp(
+                            ty,
+                            lerp(tz, sample(i, j, k), sample(i, j, k + 1)),
+                            lerp(tz, sample(i, j + 1, k), sample(i, j + 1, k + 1)),
+                        ),
+                        lerp(
+                            ty,
+                            lerp(tz, sample(i + 1, j, k), sample(i + 1, j, k + 1)),
+                            lerp(tz, sample(i + 1, j + 1, k), sample(i + 1, j + 1, k + 1)),
+                        ),
+                    );
+                    if noise > self.config.cheese_threshold {
+                        carve(BlockPos::new(x, y, z));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Winding tunnels, started by the chunks close enough for their worms to reach `region`
+    fn carve_worms<H, F>(&self, region: &BlockRegion, surface_height: &H, carve: &mut F)
+    where
+        H: Fn(isize, isize) -> isize,
+        F: FnMut(BlockPos),
+    {
+        let max_radius = self.config.worm_radius * 1.5;
+        let reach = (self.config.worm_length as f64 + max_radius).ceil() as isize;
+        let reachable = BlockRegion::new(
+            region.min.offset(-reach, -reach, -reach),
+            region.max.offset(reach, reach, reach),
+        );
+        for chunk_pos in reachable.chunks() {
+            let pos = chunk_pos.0;
+            let mut rng = SplitMix64::for_position(self.seed, pos[0], pos[1], pos[2]);
+            if rng.next_f64() >= self.config.worm_chance {
+                continue;
+            }
+            let mut random_coord =
+                |origin: isize| origin as f64 + rng.next_f64() * CHUNK_SIZE as f64;
+            let origin = chunk_pos.origin().0;
+            let start = [
+                random_coord(origin[0]),
+                random_coord(origin[1]),
+                random_coord(origin[2]),
+            ];
+            // Worms only start underground
+            if start[1]
+                >= surface_height(start[0].floor() as isize, start[2].floor() as isize) as f64
+            {
+                continue;
+            }
+            self.carve_worm(start, &mut rng, region, carve);
+        }
+    }
+
+    fn carve_worm<F>(
+        &self,
+        start: [f64; 3],
+        rng: &mut SplitMix64,
+        region: &BlockRegion,
+        carve: &mut F,
+    ) where
+        F: FnMut(BlockPos),
+    {
+        let length = self.config.worm_length;
+        let mut position = start;
+        let mut yaw = rng.next_f64() * 2.0 * PI;
+        let mut pitch = (rng.next_f64() - 0.5) * 0.5;
+        let (mut yaw_change, mut pitch_change) = (0.0, 0.0);
+        let max_radius = self.config.worm_radius * 1.5;
+        for step in 0..length {
+            // Stop once the rest of the worm can't reach the region anymore
+            let distance_squared: f64 = (0..3)
+                .map(|i| {
+                    let (min, max) = (region.min.0[i] as f64, region.max.0[i] as f64 + 1.0);
+                    (min - position[i]).max(position[i] - max).max(0.0)
+                })
+                .map(|d| d * d)
+                .sum();
+            let remaining = (length - step) as f64 + max_radius;
+            if distance_squared > remaining * remaining {
+                break;
+            }
+
+            let radius =
+                self.config.worm_radius * (1.0 + 0.5 * (PI * step as f64 / length as f64).sin());
+            position[0] += pitch.cos() * yaw.cos();
+            position[1] += pitch.sin();
+            position[2] += pitch.cos() * yaw.sin();
+
+            // Mostly horizontal tunnels that turn smoothly
+            pitch = pitch * 0.7 + pitch_change * 0.1;
+            yaw += yaw_change * 0.1;
+            pitch_change = pitch_change * 0.9 + (rng.next_f64() - rng.next_f64()) * 2.0;
+            yaw_change = yaw_change * 0.75 + (rng.next_f64() - rng.next_f64()) * 4.0;
+
+            carve_sphere(position, radius, region, carve);
+        }
+    }
+}
+
+/// Carve the blocks of `region` whose center is inside a sphere
+fn carve_sphere<F>(center: [f64; 3], radius: f64, region: &BlockRegion, carve: &mut F)
+where
+    F: FnMut(BlockPos),
+{
+    let corner = |offset: f64| {
+        BlockPos::new(
+            (center[0] + offset).floor() as isize,
+            (center[1] + offset).floor() as isize,
+            (center[2] + offset).floor() as isize,
+        )
+    };
+    let bounds = BlockRegion::new(corner(-radius), corner(radius));
+    if let Some(bounds) = bounds.intersection(region) {
+        for pos in bounds.iter() {
+            let distance_squared: f64 = (0..3)
+                .map(|i| pos.0[i] as f64 + 0.5 - center[i])
+                .map(|d| d * d)
+                .sum();
+            if distance_squared <= radius * radius {
+                carve(pos);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::ChunkPos;
+    use amethyst::core::nalgebra::Vector3;
+    use std::collections::HashSet;
+
+    fn carved(carver: &CaveCarver, region: &BlockRegion) -> HashSet<BlockPos> {
+        let mut carved = HashSet::new();
+        carver.carve(
+            region,
+            |_, _| 64,
+            |pos| {
+                carved.insert(pos);
+            },
+        );
+        carved
+    }
+
+    #[test]
+    fn neighbours_agree_on_shared_faces() {
+        let config = CaveConfig {
+            worm_chance: 1.0,
+            ..CaveConfig::default()
+        };
+        let carver = CaveCarver::new(7, &config);
+        let chunk = ChunkPos(Vector3::new(0, 0, -1));
+        for axis in 0..3 {
+            let mut offset = Vector3::zeros();
+            offset[axis] = 1;
+            let neighbour = ChunkPos(chunk.0 + offset);
+
+            // Carving both chunks at once gives the same caves as carving them separately
+            let both = BlockRegion::new(chunk.region().min, neighbour.region().max);
+            let together = carved(&carver, &both);
+            let mut separately = carved(&carver, &chunk.region());
+            separately.extend(carved(&carver, &neighbour.region()));
+            assert_eq!(together, separately);
+
+            // The caves do cross the shared face
+            let face = neighbour.origin().0[axis] - 1;
+            let crossing = together
+                .iter()
+                .filter(|pos| pos.0[axis] == face && together.contains(&BlockPos(pos.0 + offset)));
+            assert!(crossing.count() > 0);
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use super::WorldGenerator;
+use crate::{
+    registry::{Registry, RegistryError},
+    world::{Block, BlockId, BlockPos, Chunk, ChunkPos, CHUNK_SIZE},
+};
+
+/// Places every registered block on a grid at height 0, separated by air, starting at the origin
+#[derive(Clone)]
+pub struct CheckerboardGenerator {
+    air_block: BlockId,
+    blocks: Vec<BlockId>,
+    /// Number of blocks in a row of the grid
+    row_length: usize,
+}
+
+impl CheckerboardGenerator {
+    pub fn new(block_registry: &Registry<Block>) -> Result<Self, RegistryError> {
+        let air_block = block_registry.get_item_id("default:air")?;
+        let blocks: Vec<BlockId> = block_registry
+            .iter()
+            .map(|(id, _)| id)
+            .filter(|id| *id != air_block)
+            .collect();
+        let mut row_length = 1;
+        while row_length * row_length < blocks.len() {
+            row_length += 1;
+        }
+        Ok(Self {
+            air_block,
+            blocks,
+            row_length,
+        })
+    }
+
+    /// The block at a position, blocks are on even coordinates so that every face is visible
+    fn block_at(&self, pos: &BlockPos) -> BlockId {
+        let (x, y, z) = (pos.0[0], pos.0[1], pos.0[2]);
+        if y != 0 || x < 0 || z < 0 || x % 2 != 0 || z % 2 != 0 {
+            return self.air_block;
+        }
+        let (column, row) = (x as usize / 2, z as usize / 2);
+        if column >= self.row_length {
+            return self.air_block;
+        }
+        self.blocks
+            .get(row * self.row_length + column)
+            .cloned()
+            .unwrap_or(self.air_block)
+    }
+}
+
+impl WorldGenerator for CheckerboardGenerator {
+    fn generate_chunk(&self, pos: &ChunkPos) -> Chunk {
+        let mut chunk = Chunk::filled(self.air_block);
+        // Only the chunks at height 0 contain blocks
+        if pos.0[1] != 0 {
+            return chunk;
+        }
+        for i in 0..CHUNK_SIZE {
+            for k in 0..CHUNK_SIZE {
+                let block = self.block_at(&pos.origin().offset(i as isize, 0, k as isize));
+                if block != self.air_block {
+                    chunk.set(i, 0, k, block);
+                }
+            }
+        }
+        chunk
+    }
+}
@@ -0,0 +1,102 @@
+use serde::{Deserialize, Serialize};
+
+use super::pipeline::ChunkView;
+use crate::{
+    noise::SplitMix64,
+    registry::{Registry, RegistryError},
+    world::{Block, BlockId, BlockPos, BlockRegion, CHUNK_SIZE},
+};
+
+/// Settings of a feature placed by the `noise` generator
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum FeatureConfig {
+    /// Round rocks half buried in the surface
+    Boulder {
+        block: String,
+        /// Probability that a chunk has a boulder
+        chance: f64,
+        min_radius: f64,
+        max_radius: f64,
+    },
+}
+
+impl FeatureConfig {
+    /// Create the feature, fails if its blocks are not registered
+    pub fn create(
+        &self,
+        block_registry: &Registry<Block>,
+    ) -> Result<Box<dyn Feature>, RegistryError> {
+        Ok(match self {
+            FeatureConfig::Boulder {
+                block,
+                chance,
+                min_radius,
+                max_radius,
+            } => Box::new(Boulder {
+                block: block_registry.get_item_id(block)?,
+                air_block: block_registry.get_item_id("default:air")?,
+                chance: *chance,
+                min_radius: *min_radius,
+                max_radius: *max_radius,
+            }),
+        })
+    }
+}
+
+/// A structure placed once the terrain is carved, which can extend into the neighbouring chunks
+pub trait Feature: Send + Sync {
+    /// Place the feature from the carved terrain of the chunk of `view`, the only blocks it can
+    /// read. `rng` is seeded by the position of the chunk.
+    fn place(&self, view: &mut ChunkView, rng: &mut SplitMix64);
+}
+
+pub struct Boulder {
+    block: BlockId,
+    air_block: BlockId,
+    chance: f64,
+    min_radius: f64,
+    max_radius: f64,
+}
+
+impl Feature for Boulder {
+    fn place(&self, view: &mut ChunkView, rng: &mut SplitMix64) {
+        let origin = view.pos().origin().0;
+        // Always draw the same numbers, so that the following features don't depend on this one
+        let placed = rng.next_f64() < self.chance;
+        let x = origin[0] + (rng.next_f64() * CHUNK_SIZE as f64) as isize;
+        let z = origin[2] + (rng.next_f64() * CHUNK_SIZE as f64) as isize;
+        let radius = self.min_radius + rng.next_f64() * (self.max_radius - self.min_radius);
+        if !placed {
+            return;
+        }
+
+        // The ground must be inside the chunk, with air above it in the chunk as well
+        let ground = (origin[1]..origin[1] + CHUNK_SIZE as isize)
+            .rev()
+            .find(|y| {
+                let block = view.get_block(&BlockPos::new(x, *y, z));
+                let above = view.get_block(&BlockPos::new(x, y + 1, z));
+                block.is_some() && block != Some(self.air_block) && above == Some(self.air_block)
+            });
+        let ground = match ground {
+            Some(ground) => ground,
+            None => return,
+        };
+
+        let center = [x as f64 + 0.5, ground as f64 + 0.5, z as f64 + 0.5];
+        let extent = radius.ceil() as isize;
+        let bounds = BlockRegion::new(
+            BlockPos::new(x - extent, ground - extent, z - extent),
+            BlockPos::new(x + extent, ground + extent, z + extent),
+        );
+        for pos in bounds.iter() {
+            let distance_squared: f64 = (0..3)
+                .map(|i| pos.0[i] as f64 + 0.5 - center[i])
+                .map(|d| d * d)
+                .sum();
+            if distance_squared <= radius * radius {
+                view.set_block(&pos, self.block);
+            }
+        }
+    }
+}
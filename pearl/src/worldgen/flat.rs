@@ -0,0 +1,111 @@
+use serde::{Deserialize, Serialize};
+
+use super::WorldGenerator;
+use crate::{
+    registry::{Registry, RegistryError},
+    world::{Block, BlockId, Chunk, ChunkPos, CHUNK_SIZE},
+};
+
+/// A horizontal layer of a flat world
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FlatLayer {
+    pub block: String,
+    pub thickness: usize,
+}
+
+/// Settings of the `flat` generator
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FlatConfig {
+    /// Height of the top of the last layer
+    pub height: isize,
+    /// Layers from the bottom to the top. The first layer also fills everything below it.
+    pub layers: Vec<FlatLayer>,
+}
+
+impl FlatConfig {
+    /// A world without any layer
+    pub fn void() -> Self {
+        Self {
+            height: 0,
+            layers: Vec::new(),
+        }
+    }
+}
+
+impl Default for FlatConfig {
+    fn default() -> Self {
+        let layer = |block: &str, thickness| FlatLayer {
+            block: block.to_owned(),
+            thickness,
+        };
+        Self {
+            height: 0,
+            layers: vec![layer("default:dirt", 3), layer("default:grass", 1)],
+        }
+    }
+}
+
+/// Generates horizontal layers of blocks, with air above them
+#[derive(Clone)]
+pub struct FlatGenerator {
+    air_block: BlockId,
+    /// Height of the first air block
+    top: isize,
+    /// Height of the bottom of every layer, from the top to the bottom
+    layers: Vec<(isize, BlockId)>,
+}
+
+impl FlatGenerator {
+    /// Fails if one of the layer blocks is not registered
+    pub fn new(
+        block_registry: &Registry<Block>,
+        config: &FlatConfig,
+    ) -> Result<Self, RegistryError> {
+        let mut layers = Vec::new();
+        let mut top = config.height;
+        for layer in config.layers.iter().rev() {
+            let bottom = top - layer.thickness as isize;
+            layers.push((bottom, block_registry.get_item_id(&layer.block)?));
+            top = bottom;
+        }
+        Ok(Self {
+            air_block: block_registry.get_item_id("default:air")?,
+            top: config.height,
+            layers,
+        })
+    }
+
+    /// The block at height `y`
+    fn block_at(&self, y: isize) -> BlockId {
+        if y >= self.top {
+            return self.air_block;
+        }
+        self.layers
+            .iter()
+            .find(|(bottom, _)| y >= *bottom)
+            .or_else(|| self.layers.last())
+            .map_or(self.air_block, |(_, block)| *block)
+    }
+}
+
+impl WorldGenerator for FlatGenerator {
+    fn generate_chunk(&self, pos: &ChunkPos) -> Chunk {
+        let bottom = pos.origin().0[1];
+        let blocks: Vec<BlockId> = (0..CHUNK_SIZE)
+            .map(|j| self.block_at(bottom + j as isize))
+            .collect();
+        let mut chunk = Chunk::filled(blocks[0]);
+        if blocks.iter().all(|block| *block == blocks[0]) {
+            return chunk;
+        }
+        for (j, block) in blocks.into_iter().enumerate() {
+            for i in 0..CHUNK_SIZE {
+                for k in 0..CHUNK_SIZE {
+                    chunk.set(i, j, k, block);
+                }
+            }
+        }
+        chunk
+    }
+}
@@ -0,0 +1,144 @@
+//! World generation.
+//!
+//! Chunks are generated by a `WorldGenerator`, chosen by name in `resources/world_config.ron`:
+//!
+//! - `noise`: hills of stone in several biomes, with ores, caves and features, see
+//!   `TerrainConfig`, `BiomeConfig`, `OreConfig`, `CaveConfig` and `FeatureConfig`,
+//! - `flat`: horizontal layers of blocks, see `FlatConfig`,
+//! - `void`: nothing but air,
+//! - `checkerboard`: every registered block on a grid, to check how they look.
+//!
+//! Generation runs in stages, see `GenerationStage`. The first stage creates the chunk from its
+//! position alone, and the following ones can modify it. Features can also place blocks in the
+//! neighbouring chunks. The `GenerationPipeline` decides when a chunk can move to its next stage.
+use serde::{Deserialize, Serialize};
+use std::{error::Error, fmt, sync::Arc};
+
+use crate::{
+    registry::{Registry, RegistryError},
+    world::{Block, Chunk, ChunkPos},
+};
+
+mod biomes;
+mod caves;
+mod debug;
+mod features;
+mod flat;
+mod ores;
+mod pipeline;
+mod terrain;
+
+pub use self::{
+    biomes::{Biome, BiomeConfig, BiomeId, BiomeMap, BiomeMaps, BiomeSource, ClimateConfig},
+    caves::{CaveCarver, CaveConfig},
+    debug::CheckerboardGenerator,
+    features::{Boulder, Feature, FeatureConfig},
+    flat::{FlatConfig, FlatGenerator, FlatLayer},
+    ores::{Ore, OreConfig},
+    pipeline::{ChunkView, GenerationPipeline, GenerationStage, GenerationTask},
+    terrain::{NoiseGenerator, TerrainConfig},
+};
+
+/// Generates the chunks of the world.
+///
+/// Generators are shared by the worker threads, and must always generate the same chunk at a
+/// given position.
+pub trait WorldGenerator: Send + Sync {
+    /// Run the `Terrain` stage, which creates the chunk
+    fn generate_chunk(&self, pos: &ChunkPos) -> Chunk;
+
+    /// Run one of the stages between `Terrain` and `Lighting`. Generators that create their
+    /// chunks in one go don't need to implement it.
+    fn generate_stage(&self, _stage: GenerationStage, _view: &mut ChunkView) {}
+
+    /// The biomes of the generated world, if it has any
+    fn biome_source(&self) -> Option<&BiomeSource> {
+        None
+    }
+}
+
+/// World generation settings, loaded from `resources/world_config.ron`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WorldConfig {
+    /// Worlds generated with the same seed and settings are identical
+    pub seed: u64,
+    /// Name of the generator, one of `noise`, `flat`, `void` and `checkerboard`
+    pub generator: String,
+    /// Settings of the `noise` generator
+    pub terrain: TerrainConfig,
+    /// Climate noise choosing the biomes of the `noise` generator
+    pub climate: ClimateConfig,
+    /// Biomes of the `noise` generator
+    pub biomes: Vec<BiomeConfig>,
+    /// Caves of the `noise` generator
+    pub caves: CaveConfig,
+    /// Features of the `noise` generator placed in every biome, in this order
+    pub features: Vec<FeatureConfig>,
+    /// Settings of the `flat` generator
+    pub flat: FlatConfig,
+}
+
+impl Default for WorldConfig {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            generator: "noise".to_owned(),
+            terrain: TerrainConfig::default(),
+            climate: ClimateConfig::default(),
+            biomes: BiomeConfig::defaults(),
+            caves: CaveConfig::default(),
+            features: vec![FeatureConfig::Boulder {
+                block: "default:stone".to_owned(),
+                chance: 0.2,
+                min_radius: 1.5,
+                max_radius: 3.0,
+            }],
+            flat: FlatConfig::default(),
+        }
+    }
+}
+
+/// Error returned when the generator of a `WorldConfig` can't be created
+#[derive(Debug)]
+pub enum GeneratorError {
+    /// No generator has this name
+    UnknownGenerator(String),
+    /// A block used by the generator is not registered, or two biomes have the same name
+    Registry(RegistryError),
+    /// The `noise` generator needs at least one biome
+    NoBiomes,
+}
+
+impl fmt::Display for GeneratorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GeneratorError::UnknownGenerator(name) => write!(f, "Unknown generator {}", name),
+            GeneratorError::Registry(error) => write!(f, "{}", error),
+            GeneratorError::NoBiomes => write!(f, "No biome is configured"),
+        }
+    }
+}
+
+impl Error for GeneratorError {}
+
+impl From<RegistryError> for GeneratorError {
+    fn from(error: RegistryError) -> Self {
+        GeneratorError::Registry(error)
+    }
+}
+
+/// Create the generator selected by `config`. `ores` are the ore rules of the block files.
+pub fn create_generator(
+    config: &WorldConfig,
+    block_registry: &Registry<Block>,
+    ores: &[OreConfig],
+) -> Result<Arc<dyn WorldGenerator>, GeneratorError> {
+    Ok(match config.generator.as_str() {
+        "noise" => Arc::new(NoiseGenerator::new(block_registry, config, ores)?),
+        "flat" => Arc::new(FlatGenerator::new(block_registry, &config.flat)?),
+        "void" => Arc::new(FlatGenerator::new(block_registry, &FlatConfig::void())?),
+        "checkerboard" => Arc::new(CheckerboardGenerator::new(block_registry)?),
+        name => return Err(GeneratorError::UnknownGenerator(name.to_owned())),
+    })
+}
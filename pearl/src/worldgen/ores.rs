@@ -0,0 +1,247 @@
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use super::{features::Feature, pipeline::ChunkView};
+use crate::{
+    noise::SplitMix64,
+    registry::{Registry, RegistryError},
+    world::{Block, BlockId, BlockPos, CHUNK_SIZE},
+};
+
+/// Placement rule of an ore, declared in the block files next to the ore block
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OreConfig {
+    /// Name of the ore block, with its namespace
+    pub block: String,
+    /// Number of blocks of a vein, fewer if it runs into blocks it can't replace
+    pub vein_size: usize,
+    /// Number of veins tried in every chunk inside the height range
+    pub attempts: usize,
+    /// Lowest height where veins start
+    pub min_height: isize,
+    /// Highest height where veins start
+    pub max_height: isize,
+    /// Blocks that the ore can replace
+    pub replaceable: Vec<String>,
+}
+
+impl OreConfig {
+    /// Fails if the ore or a replaceable block is not registered
+    pub fn create(&self, block_registry: &Registry<Block>) -> Result<Ore, RegistryError> {
+        Ok(Ore {
+            block: block_registry.get_item_id(&self.block)?,
+            vein_size: self.vein_size,
+            attempts: self.attempts,
+            min_height: self.min_height,
+            max_height: self.max_height,
+            replaceable: self
+                .replaceable
+                .iter()
+                .map(|name| block_registry.get_item_id(name))
+                .collect::<Result<Vec<_>, _>>()?
+                .into(),
+        })
+    }
+}
+
+/// Veins of an ore block, started in random places of the chunks in its height range. A vein
+/// wanders from block to block and can cross into the neighbouring chunks.
+pub struct Ore {
+    block: BlockId,
+    vein_size: usize,
+    attempts: usize,
+    min_height: isize,
+    max_height: isize,
+    replaceable: Arc<[BlockId]>,
+}
+
+impl Feature for Ore {
+    fn place(&self, view: &mut ChunkView, rng: &mut SplitMix64) {
+        let origin = view.pos().origin();
+        let size = CHUNK_SIZE as isize;
+        if origin.0[1] > self.max_height || origin.0[1] + size <= self.min_height {
+            return;
+        }
+        let random_offset = |rng: &mut SplitMix64| (rng.next_f64() * size as f64) as isize;
+        for _ in 0..self.attempts {
+            let mut pos = origin.offset(random_offset(rng), random_offset(rng), random_offset(rng));
+            // Starting heights are drawn in the whole chunk, so that the chunks that are only
+            // partly in the height range don't get denser veins
+            if pos.0[1] < self.min_height || pos.0[1] > self.max_height {
+                continue;
+            }
+            for _ in 0..self.vein_size {
+                view.replace_block(&pos, self.block, &self.replaceable);
+                pos = random_step(&pos, rng);
+            }
+        }
+    }
+}
+
+/// Move to one of the 6 blocks sharing a face with `pos`
+fn random_step(pos: &BlockPos, rng: &mut SplitMix64) -> BlockPos {
+    let direction = (rng.next_f64() * 6.0) as usize;
+    let delta = [1, -1][direction % 2];
+    let mut next = *pos;
+    next.0[direction / 2] += delta;
+    next
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        world::{
+            test_blocks::{block_registry, DIRT, STONE},
+            Chunk, ChunkPos,
+        },
+        worldgen::{GenerationPipeline, GenerationStage, WorldGenerator},
+    };
+    use amethyst::core::nalgebra::Vector3;
+    use std::collections::HashMap;
+
+    /// Stone with a layer of dirt between the heights 0 and 7, and the ores
+    struct OreGenerator {
+        ores: Vec<Ore>,
+    }
+
+    impl WorldGenerator for OreGenerator {
+        fn generate_chunk(&self, pos: &ChunkPos) -> Chunk {
+            let block = if pos.0[1] == 0 { DIRT } else { STONE };
+            let mut chunk = Chunk::filled(block);
+            if pos.0[1] == 0 {
+                for pos in pos.region().iter().filter(|pos| pos.0[1] >= 8) {
+                    let local = pos.local_pos().0;
+                    chunk.set(local[0], local[1], local[2], STONE);
+                }
+            }
+            chunk
+        }
+
+        fn generate_stage(&self, stage: GenerationStage, view: &mut ChunkView) {
+            if stage == GenerationStage::Features {
+                let pos = view.pos().0;
+                for (index, ore) in self.ores.iter().enumerate() {
+                    let mut rng = SplitMix64::for_position(index as u64, pos[0], pos[1], pos[2]);
+                    ore.place(view, &mut rng);
+                }
+            }
+        }
+    }
+
+    /// The blocks of the chunks at `positions`, requested in this order
+    fn generate(
+        generator: &OreGenerator,
+        block_registry: &Registry<Block>,
+        positions: &[ChunkPos],
+    ) -> HashMap<ChunkPos, Vec<BlockId>> {
+        let mut pipeline = GenerationPipeline::new();
+        for pos in positions {
+            pipeline.request(*pos);
+        }
+        pipeline.run_blocking(generator, block_registry);
+        pipeline
+            .take_complete()
+            .into_iter()
+            .map(|(pos, chunk)| {
+                let blocks = pos
+                    .region()
+                    .iter()
+                    .map(|block_pos| {
+                        let local = block_pos.local_pos().0;
+                        chunk.get(local[0], local[1], local[2])
+                    })
+                    .collect();
+                (pos, blocks)
+            })
+            .collect()
+    }
+
+    /// Number of blocks of every kind at every height
+    fn count_blocks(chunks: &HashMap<ChunkPos, Vec<BlockId>>) -> HashMap<(BlockId, isize), usize> {
+        let mut counts = HashMap::new();
+        for (pos, blocks) in chunks {
+            for (block_pos, block) in pos.region().iter().zip(blocks) {
+                *counts.entry((*block, block_pos.0[1])).or_insert(0) += 1;
+            }
+        }
+        counts
+    }
+
+    #[test]
+    fn ore_distribution() {
+        let mut block_registry = block_registry();
+        for name in &["default:coal", "default:gold"] {
+            block_registry.register(*name, Block::default()).unwrap();
+        }
+        let ore = |block: &str, vein_size, attempts, min_height, max_height| OreConfig {
+            block: block.to_owned(),
+            vein_size,
+            attempts,
+            min_height,
+            max_height,
+            replaceable: vec!["default:stone".to_owned()],
+        };
+        let configs = [
+            ore("default:coal", 12, 20, -64, 63),
+            ore("default:gold", 6, 4, -96, -33),
+        ];
+        let generator = OreGenerator {
+            ores: configs
+                .iter()
+                .map(|config| config.create(&block_registry).unwrap())
+                .collect(),
+        };
+        let mut positions = Vec::new();
+        for x in 0..3 {
+            for y in -3..2 {
+                for z in 0..3 {
+                    positions.push(ChunkPos(Vector3::new(x, y, z)));
+                }
+            }
+        }
+        let chunks = generate(&generator, &block_registry, &positions);
+        assert_eq!(chunks, generate(&generator, &block_registry, &positions));
+        // Veins crossing into the neighbouring chunks don't depend on which chunk is generated
+        // first, even where they overlap
+        let reversed: Vec<ChunkPos> = positions.iter().rev().cloned().collect();
+        assert_eq!(chunks, generate(&generator, &block_registry, &reversed));
+        let counts = count_blocks(&chunks);
+
+        for (config, ore) in configs.iter().zip(generator.ores.iter()) {
+            let heights: Vec<(isize, usize)> = counts
+                .iter()
+                .filter(|((block, _), _)| *block == ore.block)
+                .map(|((_, height), count)| (*height, *count))
+                .collect();
+            let total: usize = heights.iter().map(|(_, count)| count).sum();
+            let lowest = heights.iter().map(|(height, _)| *height).min().unwrap();
+            let highest = heights.iter().map(|(height, _)| *height).max().unwrap();
+            let range = (config.max_height - config.min_height + 1) as usize;
+            let chunk_count = 9 * range / CHUNK_SIZE;
+            let per_chunk = total as f64 / chunk_count as f64;
+            let statistics = format!(
+                "{}: {} blocks between {} and {}, {:.1} per chunk",
+                config.block, total, lowest, highest, per_chunk,
+            );
+
+            // Veins wander at most `vein_size` blocks away from their start
+            let reach = config.vein_size as isize;
+            assert!(lowest >= config.min_height - reach, "{}", statistics);
+            assert!(highest <= config.max_height + reach, "{}", statistics);
+            // Veins overlap themselves and each other, but most of their blocks are placed
+            let expected = (config.vein_size * config.attempts) as f64;
+            assert!(
+                per_chunk > 0.3 * expected && per_chunk <= expected,
+                "{}",
+                statistics
+            );
+        }
+
+        // Only the stone was replaced
+        for height in 0..8 {
+            assert_eq!(counts[&(DIRT, height)], 9 * CHUNK_SIZE * CHUNK_SIZE);
+        }
+    }
+}
@@ -0,0 +1,488 @@
+use amethyst::core::nalgebra::Vector3;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::Arc,
+};
+
+use super::WorldGenerator;
+use crate::{
+    light::light_chunk,
+    registry::Registry,
+    world::{neighbour_offsets, Block, BlockId, BlockPos, BlockRegion, Chunk, ChunkPos, LocalPos},
+};
+
+/// The stages of the generation of a chunk, in order
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Clone, Copy)]
+pub enum GenerationStage {
+    /// The shape of the terrain, from the position of the chunk alone
+    Terrain,
+    /// The top blocks of the terrain
+    Surface,
+    /// Caves carved in the terrain
+    Carvers,
+    /// Structures that can place blocks in the neighbouring chunks. They are decided from the
+    /// carved terrain of their own chunk, and their blocks are only placed at the next stage.
+    Features,
+    /// The blocks placed by the features of the chunk and of its neighbours, then the light
+    /// levels of the chunk on its own. The light spreads into the neighbouring chunks after the
+    /// chunk is added to the world.
+    Lighting,
+}
+
+impl GenerationStage {
+    pub const ALL: [GenerationStage; 5] = [
+        GenerationStage::Terrain,
+        GenerationStage::Surface,
+        GenerationStage::Carvers,
+        GenerationStage::Features,
+        GenerationStage::Lighting,
+    ];
+
+    /// The last stage, after which the chunk is complete
+    pub const LAST: GenerationStage = GenerationStage::Lighting;
+
+    pub fn next(self) -> Option<Self> {
+        Self::ALL.get(self as usize + 1).cloned()
+    }
+
+    pub fn previous(self) -> Option<Self> {
+        (self as usize).checked_sub(1).map(|index| Self::ALL[index])
+    }
+
+    /// Stages that wait for the neighbours of the chunk to reach the previous stage.
+    ///
+    /// Lighting places the blocks of the features of the neighbours, which must be decided. The
+    /// other stages only read their own chunk and never wait.
+    pub fn waits_for_neighbours(self) -> bool {
+        self == GenerationStage::Lighting
+    }
+}
+
+/// A block placed by a feature
+#[derive(Clone)]
+struct Placement {
+    pos: BlockPos,
+    block_id: BlockId,
+    /// The blocks of the carved terrain that it replaces, every block if `None`
+    replaceable: Option<Arc<[BlockId]>>,
+}
+
+/// Access to the blocks of a chunk being generated.
+///
+/// Blocks can be placed in the chunk and in its neighbours, but they are only written at the
+/// `Lighting` stage, once the features of every neighbour are decided. They are written in the
+/// order of the positions of the chunks that placed them, and in the order they were placed for
+/// a given chunk, so the result doesn't depend on the order the chunks are generated in. Blocks
+/// that only replace some blocks check the carved terrain, not the blocks placed before them.
+pub struct ChunkView {
+    pos: ChunkPos,
+    chunk: Option<Chunk>,
+    /// The blocks placed by the features of the chunk, and during `Lighting` the blocks placed
+    /// in the chunk, in the order they are written
+    placements: Vec<Placement>,
+}
+
+impl ChunkView {
+    /// Position of the chunk being generated
+    pub fn pos(&self) -> &ChunkPos {
+        &self.pos
+    }
+
+    /// The chunk being generated
+    pub fn chunk(&self) -> &Chunk {
+        self.chunk
+            .as_ref()
+            .expect("The terrain of the chunk was not generated")
+    }
+
+    pub fn chunk_mut(&mut self) -> &mut Chunk {
+        self.chunk
+            .as_mut()
+            .expect("The terrain of the chunk was not generated")
+    }
+
+    /// The region where blocks can be placed, neighbours included
+    pub fn region(&self) -> BlockRegion {
+        let min = ChunkPos(self.pos.0.map(|c| c - 1)).region().min;
+        let max = ChunkPos(self.pos.0.map(|c| c + 1)).region().max;
+        BlockRegion::new(min, max)
+    }
+
+    /// Get a block of the chunk, or `None` if it is outside of it. The blocks placed by the
+    /// features are not visible.
+    pub fn get_block(&self, pos: &BlockPos) -> Option<BlockId> {
+        if pos.chunk_pos() != self.pos {
+            return None;
+        }
+        let local = pos.local_pos().0;
+        Some(self.chunk().get(local[0], local[1], local[2]))
+    }
+
+    /// Place a block, and return false if it is outside of the view
+    pub fn set_block(&mut self, pos: &BlockPos, block_id: BlockId) -> bool {
+        self.place(pos, block_id, None)
+    }
+
+    /// Place a block that is only written if the carved terrain has one of the `replaceable`
+    /// blocks there, and return false if it is outside of the view
+    pub fn replace_block(
+        &mut self,
+        pos: &BlockPos,
+        block_id: BlockId,
+        replaceable: &Arc<[BlockId]>,
+    ) -> bool {
+        self.place(pos, block_id, Some(replaceable.clone()))
+    }
+
+    fn place(
+        &mut self,
+        pos: &BlockPos,
+        block_id: BlockId,
+        replaceable: Option<Arc<[BlockId]>>,
+    ) -> bool {
+        let offset = pos.chunk_pos().0 - self.pos.0;
+        if offset.iter().any(|c| c.abs() > 1) {
+            return false;
+        }
+        self.placements.push(Placement {
+            pos: *pos,
+            block_id,
+            replaceable,
+        });
+        true
+    }
+
+    /// Write the blocks placed in the chunk
+    fn write_placements(&mut self) {
+        let chunk = self
+            .chunk
+            .as_mut()
+            .expect("The terrain of the chunk was not generated");
+        // Every block is checked before the first one is written
+        let written: Vec<(LocalPos, BlockId)> = self
+            .placements
+            .drain(..)
+            .filter(|placement| match &placement.replaceable {
+                Some(replaceable) => {
+                    let local = placement.pos.local_pos().0;
+                    replaceable.contains(&chunk.get(local[0], local[1], local[2]))
+                }
+                None => true,
+            })
+            .map(|placement| (placement.pos.local_pos(), placement.block_id))
+            .collect();
+        for (LocalPos(local), block_id) in written {
+            chunk.set(local[0], local[1], local[2], block_id);
+        }
+    }
+}
+
+/// A stage to run on a chunk, sent to the worker threads along with the chunk
+pub struct GenerationTask {
+    pub stage: GenerationStage,
+    pub view: ChunkView,
+}
+
+impl GenerationTask {
+    /// Run the stage with `generator`, except for the `Lighting` stage which is the same for
+    /// every generator
+    pub fn run(&mut self, generator: &dyn WorldGenerator, block_registry: &Registry<Block>) {
+        match self.stage {
+            GenerationStage::Terrain => {
+                self.view.chunk = Some(generator.generate_chunk(&self.view.pos));
+            }
+            GenerationStage::Lighting => {
+                self.view.write_placements();
+                light_chunk(self.view.chunk_mut(), block_registry);
+            }
+            stage => generator.generate_stage(stage, &mut self.view),
+        }
+    }
+}
+
+/// A chunk going through the generation stages
+#[derive(Default)]
+struct ProtoChunk {
+    /// `None` before the terrain is generated, while a task uses the chunk, and once the chunk
+    /// is complete and handed over
+    chunk: Option<Chunk>,
+    /// The last stage that was run
+    stage: Option<GenerationStage>,
+    /// A task uses the chunk
+    busy: bool,
+    /// The blocks placed by the features of the chunk, by the chunk they are in. They are kept
+    /// until the chunk is forgotten, as the neighbours that are requested later need them.
+    placements: HashMap<ChunkPos, Vec<Placement>>,
+}
+
+/// Decides which generation stages can run, and keeps the chunks between stages.
+///
+/// Chunks are brought to the last stage with `request`. A stage that waits for the neighbours
+/// of a chunk first brings them to the previous stage, so chunks around the requested ones are
+/// generated as well. Once complete, chunks are handed over by `take_complete`.
+#[derive(Default)]
+pub struct GenerationPipeline {
+    chunks: HashMap<ChunkPos, ProtoChunk>,
+    /// Requested chunks that are not complete yet, by priority
+    requested: VecDeque<ChunkPos>,
+    complete: Vec<(ChunkPos, Chunk)>,
+}
+
+impl GenerationPipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Generate the chunk at `pos` up to the last stage. Requests are processed in order.
+    pub fn request(&mut self, pos: ChunkPos) {
+        if self.requested.contains(&pos) {
+            return;
+        }
+        // A chunk that was handed over but is requested again is generated from scratch
+        if let Some(proto_chunk) = self.chunks.get(&pos) {
+            if proto_chunk.stage == Some(GenerationStage::LAST) {
+                self.chunks.remove(&pos);
+            }
+        }
+        self.requested.push_back(pos);
+    }
+
+    /// Return true if the chunk at `pos` was requested and is not complete yet
+    pub fn is_requested(&self, pos: &ChunkPos) -> bool {
+        self.requested.contains(pos)
+    }
+
+    /// Stop generating a chunk that doesn't need to be generated, e.g. because it was loaded
+    /// from a save. Its features are still decided when a neighbour needs them, but the chunk
+    /// is never handed over, and the features of its neighbours are not placed in it: it
+    /// already has them.
+    pub fn insert_complete(&mut self, pos: ChunkPos) {
+        self.requested.retain(|requested| *requested != pos);
+    }
+
+    /// Forget the chunks and requests matching `predicate`. Chunks used by a running task are
+    /// dropped when the task completes.
+    pub fn forget_where<F>(&mut self, mut predicate: F)
+    where
+        F: FnMut(&ChunkPos) -> bool,
+    {
+        self.chunks.retain(|pos, _| !predicate(pos));
+        self.requested.retain(|pos| !predicate(pos));
+    }
+
+    /// Find the next stage that can run, and lend it the chunk it needs until `complete` is
+    /// called
+    pub fn next_task(&mut self) -> Option<GenerationTask> {
+        let mut visited = HashSet::new();
+        let requested: Vec<ChunkPos> = self.requested.iter().cloned().collect();
+        requested
+            .into_iter()
+            .filter_map(|pos| self.find_stage(pos, GenerationStage::LAST, &mut visited))
+            .next()
+            .map(|(pos, stage)| self.start_task(pos, stage))
+    }
+
+    /// Give back the chunk of a finished task
+    pub fn complete(&mut self, task: GenerationTask) {
+        let GenerationTask { stage, view } = task;
+        let ChunkView {
+            pos,
+            chunk,
+            placements,
+        } = view;
+        let proto_chunk = match self.chunks.get_mut(&pos) {
+            // Skip the chunks forgotten while the task was running
+            Some(proto_chunk) if proto_chunk.busy => proto_chunk,
+            _ => return,
+        };
+        proto_chunk.busy = false;
+        proto_chunk.chunk = chunk;
+        proto_chunk.stage = Some(stage);
+        if stage == GenerationStage::Features {
+            for placement in placements {
+                proto_chunk
+                    .placements
+                    .entry(placement.pos.chunk_pos())
+                    .or_insert_with(Vec::new)
+                    .push(placement);
+            }
+        }
+
+        if stage == GenerationStage::LAST && self.requested.contains(&pos) {
+            self.requested.retain(|requested| *requested != pos);
+            if let Some(chunk) = self.chunks.get_mut(&pos).and_then(|c| c.chunk.take()) {
+                self.complete.push((pos, chunk));
+            }
+        }
+    }
+
+    /// Run every stage on the current thread until the requested chunks are complete
+    pub fn run_blocking(
+        &mut self,
+        generator: &dyn WorldGenerator,
+        block_registry: &Registry<Block>,
+    ) {
+        while let Some(mut task) = self.next_task() {
+            task.run(generator, block_registry);
+            self.complete(task);
+        }
+    }
+
+    /// The requested chunks that completed since the last call
+    pub fn take_complete(&mut self) -> Vec<(ChunkPos, Chunk)> {
+        self.complete.drain(..).collect()
+    }
+
+    /// Find a stage that can run now and that brings the chunk at `pos` closer to `target`
+    fn find_stage(
+        &mut self,
+        pos: ChunkPos,
+        target: GenerationStage,
+        visited: &mut HashSet<ChunkPos>,
+    ) -> Option<(ChunkPos, GenerationStage)> {
+        if !visited.insert(pos) {
+            return None;
+        }
+        let proto_chunk = self.chunks.entry(pos).or_default();
+        if proto_chunk.busy || proto_chunk.stage >= Some(target) {
+            return None;
+        }
+        let stage = proto_chunk
+            .stage
+            .map_or(Some(GenerationStage::Terrain), |stage| stage.next())?;
+
+        if stage.waits_for_neighbours() {
+            let required = stage.previous().unwrap();
+            let mut ready = true;
+            for offset in neighbour_offsets() {
+                let neighbour = ChunkPos(pos.0 + offset);
+                let neighbour_stage = self.chunks.get(&neighbour).and_then(|c| c.stage);
+                if neighbour_stage < Some(required) {
+                    if let Some(found) = self.find_stage(neighbour, required, visited) {
+                        return Some(found);
+                    }
+                    ready = false;
+                }
+            }
+            if !ready {
+                return None;
+            }
+        }
+        Some((pos, stage))
+    }
+
+    fn start_task(&mut self, pos: ChunkPos, stage: GenerationStage) -> GenerationTask {
+        // The blocks placed in the chunk, by the position of the chunk that placed them
+        let mut placements = Vec::new();
+        if stage == GenerationStage::Lighting {
+            let offsets = (-1..=1).flat_map(|x| {
+                (-1..=1).flat_map(move |y| (-1..=1).map(move |z| Vector3::new(x, y, z)))
+            });
+            for offset in offsets {
+                if let Some(source) = self.chunks.get(&ChunkPos(pos.0 + offset)) {
+                    if let Some(placed) = source.placements.get(&pos) {
+                        placements.extend_from_slice(placed);
+                    }
+                }
+            }
+        }
+        let proto_chunk = self.chunks.get_mut(&pos).unwrap();
+        proto_chunk.busy = true;
+        GenerationTask {
+            stage,
+            view: ChunkView {
+                pos,
+                chunk: proto_chunk.chunk.take(),
+                placements,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Records the stages it runs, and marks the first block of its +x neighbour
+    #[derive(Default)]
+    struct RecordingGenerator {
+        stages: Mutex<Vec<(ChunkPos, GenerationStage)>>,
+    }
+
+    impl WorldGenerator for RecordingGenerator {
+        fn generate_chunk(&self, pos: &ChunkPos) -> Chunk {
+            self.stages
+                .lock()
+                .unwrap()
+                .push((*pos, GenerationStage::Terrain));
+            Chunk::filled(BlockId::from_index(0))
+        }
+
+        fn generate_stage(&self, stage: GenerationStage, view: &mut ChunkView) {
+            self.stages.lock().unwrap().push((*view.pos(), stage));
+            if stage == GenerationStage::Features {
+                let neighbour = ChunkPos(view.pos().0 + Vector3::new(1, 0, 0));
+                assert!(view.set_block(&neighbour.origin(), BlockId::from_index(1)));
+            }
+        }
+    }
+
+    #[test]
+    fn stages_wait_for_neighbours() {
+        let generator = RecordingGenerator::default();
+        let mut block_registry = Registry::new();
+        for name in &["test:a", "test:b"] {
+            block_registry.register(*name, Block::default()).unwrap();
+        }
+        let mut pipeline = GenerationPipeline::new();
+        let requested = [
+            ChunkPos(Vector3::new(0, 0, 0)),
+            ChunkPos(Vector3::new(3, 0, 0)),
+        ];
+        for pos in requested.iter() {
+            pipeline.request(*pos);
+        }
+        // Run the tasks in batches, as they would on several threads
+        loop {
+            let mut tasks: Vec<GenerationTask> =
+                std::iter::from_fn(|| pipeline.next_task()).collect();
+            if tasks.is_empty() {
+                break;
+            }
+            for task in tasks.iter_mut() {
+                task.run(&generator, &block_registry);
+            }
+            for task in tasks.into_iter().rev() {
+                pipeline.complete(task);
+            }
+        }
+
+        let stages = generator.stages.into_inner().unwrap();
+        let reached = |pos: ChunkPos, stage: GenerationStage, before: usize| {
+            stages[..before].contains(&(pos, stage))
+        };
+        for (index, (pos, stage)) in stages.iter().enumerate() {
+            // Every stage runs once per chunk, after the previous one
+            assert_eq!(stages.iter().filter(|s| **s == (*pos, *stage)).count(), 1);
+            if let Some(previous) = stage.previous() {
+                assert!(reached(*pos, previous, index));
+                if stage.waits_for_neighbours() {
+                    for offset in neighbour_offsets() {
+                        assert!(reached(ChunkPos(pos.0 + offset), previous, index));
+                    }
+                }
+            }
+        }
+
+        // The features of the -x neighbours were placed in the complete chunks
+        let complete = pipeline.take_complete();
+        assert_eq!(complete.len(), 2);
+        for (pos, chunk) in complete {
+            assert!(requested.contains(&pos));
+            assert_eq!(chunk.get(0, 0, 0), BlockId::from_index(1));
+            assert_eq!(chunk.get(1, 0, 0), BlockId::from_index(0));
+        }
+        assert!(pipeline.next_task().is_none());
+    }
+}
@@ -0,0 +1,424 @@
+use serde::{Deserialize, Serialize};
+
+use super::{
+    biomes::{BiomeMap, BiomeSource},
+    caves::CaveCarver,
+    features::Feature,
+    ores::{Ore, OreConfig},
+    pipeline::ChunkView,
+    GenerationStage, GeneratorError, WorldConfig, WorldGenerator,
+};
+use crate::{
+    noise::{FractalNoise, SplitMix64},
+    registry::Registry,
+    world::{Block, BlockId, Chunk, ChunkPos, CHUNK_SIZE},
+};
+
+/// Settings of the `noise` generator, the shape of the terrain height map
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TerrainConfig {
+    /// Average height of the surface
+    pub base_height: isize,
+    /// Largest distance between the surface and `base_height`, multiplied by the height scale of
+    /// the biomes
+    pub amplitude: f64,
+    /// Horizontal size of the largest hills, in blocks
+    pub scale: f64,
+    /// Number of noise octaves added together
+    pub octaves: u32,
+    /// Frequency multiplier between two octaves
+    pub lacunarity: f64,
+    /// Amplitude multiplier between two octaves
+    pub persistence: f64,
+}
+
+impl Default for TerrainConfig {
+    fn default() -> Self {
+        Self {
+            base_height: 0,
+            amplitude: 24.0,
+            scale: 128.0,
+            octaves: 4,
+            lacunarity: 2.0,
+            persistence: 0.5,
+        }
+    }
+}
+
+/// Seed offset of the features, so that they don't use the same numbers as the caves
+const FEATURE_SEED: u64 = 0x6665_6174;
+/// Seed offset of the ores, every ore adds its index to it
+const ORE_SEED: u64 = 0x6f72_6500;
+
+/// Generates hills of stone from a fractal noise height map, covered with the surface blocks of
+/// their biome, with ores and caves below and features on top
+pub struct NoiseGenerator {
+    air_block: BlockId,
+    stone_block: BlockId,
+    terrain: TerrainConfig,
+    height_noise: FractalNoise,
+    biomes: BiomeSource,
+    caves: CaveCarver,
+    ores: Vec<Ore>,
+    features: Vec<Box<dyn Feature>>,
+    seed: u64,
+}
+
+impl NoiseGenerator {
+    /// Fails if one of the generated blocks is not registered, or if there is no biome
+    pub fn new(
+        block_registry: &Registry<Block>,
+        config: &WorldConfig,
+        ores: &[OreConfig],
+    ) -> Result<Self, GeneratorError> {
+        let terrain = config.terrain.clone();
+        let height_noise = FractalNoise::new(
+            config.seed,
+            terrain.octaves,
+            terrain.lacunarity,
+            terrain.persistence,
+        );
+        let features = config
+            .features
+            .iter()
+            .map(|feature| feature.create(block_registry))
+            .collect::<Result<_, _>>()?;
+        let ores = ores
+            .iter()
+            .map(|ore| ore.create(block_registry))
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            air_block: block_registry.get_item_id("default:air")?,
+            stone_block: block_registry.get_item_id("default:stone")?,
+            terrain,
+            height_noise,
+            biomes: BiomeSource::new(config.seed, &config.climate, &config.biomes, block_registry)?,
+            caves: CaveCarver::new(config.seed, &config.caves),
+            ores,
+            features,
+            seed: config.seed,
+        })
+    }
+
+    /// Height of the first air block above the surface of the column at `(x, z)`
+    pub fn surface_height(&self, x: isize, z: isize) -> isize {
+        self.scaled_height(x, z, self.biomes.height_scale(x, z))
+    }
+
+    fn scaled_height(&self, x: isize, z: isize, height_scale: f64) -> isize {
+        let noise = self
+            .height_noise
+            .get2(x as f64 / self.terrain.scale, z as f64 / self.terrain.scale);
+        self.terrain.base_height + (noise * self.terrain.amplitude * height_scale).floor() as isize
+    }
+
+    /// Surface heights of the columns of a chunk, using the height scales of its biome map
+    fn surface_heights(
+        &self,
+        pos: &ChunkPos,
+        biome_map: &BiomeMap,
+    ) -> [[isize; CHUNK_SIZE]; CHUNK_SIZE] {
+        let origin = pos.origin().0;
+        let mut heights = [[0; CHUNK_SIZE]; CHUNK_SIZE];
+        for (i, column) in heights.iter_mut().enumerate() {
+            for (k, height) in column.iter_mut().enumerate() {
+                *height = self.scaled_height(
+                    origin[0] + i as isize,
+                    origin[2] + k as isize,
+                    biome_map.height_scale(i, k),
+                );
+            }
+        }
+        heights
+    }
+}
+
+impl WorldGenerator for NoiseGenerator {
+    fn generate_chunk(&self, pos: &ChunkPos) -> Chunk {
+        let size = CHUNK_SIZE as isize;
+        let origin = pos.origin().0;
+        let heights = self.surface_heights(pos, &self.biomes.biome_map(&pos.column()));
+
+        // Chunks entirely above or below the surface are uniform
+        let min_height = heights.iter().flatten().min().cloned().unwrap();
+        let max_height = heights.iter().flatten().max().cloned().unwrap();
+        if origin[1] >= max_height {
+            return Chunk::filled(self.air_block);
+        }
+        if origin[1] + size <= min_height {
+            return Chunk::filled(self.stone_block);
+        }
+
+        let mut chunk = Chunk::filled(self.air_block);
+        for (i, column) in heights.iter().enumerate() {
+            for (k, &height) in column.iter().enumerate() {
+                for j in 0..CHUNK_SIZE.min((height - origin[1]).max(0) as usize) {
+                    chunk.set(i, j, k, self.stone_block);
+                }
+            }
+        }
+        chunk
+    }
+
+    fn generate_stage(&self, stage: GenerationStage, view: &mut ChunkView) {
+        let pos = *view.pos();
+        match stage {
+            GenerationStage::Surface => {
+                let origin = pos.origin().0;
+                let biome_map = self.biomes.biome_map(&pos.column());
+                let heights = self.surface_heights(&pos, &biome_map);
+                let chunk = view.chunk_mut();
+                for (i, column) in heights.iter().enumerate() {
+                    for (k, &height) in column.iter().enumerate() {
+                        let biome = &self.biomes.biomes()[biome_map.get(i, k)];
+                        // The top block, then the filler blocks below it
+                        let top = height - 1 - origin[1];
+                        for depth in 0..=biome.filler_depth as isize {
+                            let j = top - depth;
+                            if j >= 0 && j < CHUNK_SIZE as isize {
+                                let block = if depth == 0 {
+                                    biome.top_block
+                                } else {
+                                    biome.filler_block
+                                };
+                                chunk.set(i, j as usize, k, block);
+                            }
+                        }
+                    }
+                }
+            }
+            GenerationStage::Carvers => {
+                let chunk = view.chunk_mut();
+                if chunk.is_uniform() && chunk.get(0, 0, 0) == self.air_block {
+                    return;
+                }
+                self.caves.carve(
+                    &pos.region(),
+                    |x, z| self.surface_height(x, z),
+                    |block_pos| {
+                        let local = block_pos.local_pos().0;
+                        chunk.set(local[0], local[1], local[2], self.air_block);
+                    },
+                );
+            }
+            GenerationStage::Features => {
+                // Ores come before the other features, which can be made of replaceable blocks
+                for (index, ore) in self.ores.iter().enumerate() {
+                    let seed = self.seed ^ ORE_SEED.wrapping_add(index as u64);
+                    let mut rng = SplitMix64::for_position(seed, pos.0[0], pos.0[1], pos.0[2]);
+                    ore.place(view, &mut rng);
+                }
+                let mut rng = SplitMix64::for_position(
+                    self.seed ^ FEATURE_SEED,
+                    pos.0[0],
+                    pos.0[1],
+                    pos.0[2],
+                );
+                // Biome specific features come after the ones of every biome
+                let center =
+                    pos.origin()
+                        .offset(CHUNK_SIZE as isize / 2, 0, CHUNK_SIZE as isize / 2);
+                let biome = &self.biomes.biomes()[self.biomes.biome_at(&center)];
+                for feature in self.features.iter().chain(biome.features.iter()) {
+                    feature.place(view, &mut rng);
+                }
+            }
+            GenerationStage::Terrain | GenerationStage::Lighting => (),
+        }
+    }
+
+    fn biome_source(&self) -> Option<&BiomeSource> {
+        Some(&self.biomes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::worldgen::{FeatureConfig, GenerationPipeline};
+    use amethyst::core::nalgebra::Vector3;
+    use std::collections::HashMap;
+
+    fn block_registry() -> Registry<Block> {
+        let mut block_registry = Registry::new();
+        for name in &[
+            "default:air",
+            "default:coal_ore",
+            "default:dirt",
+            "default:grass",
+            "default:sand",
+            "default:snow",
+            "default:stone",
+        ] {
+            block_registry.register(*name, Block::default()).unwrap();
+        }
+        block_registry
+    }
+
+    fn generator(seed: u64, block_registry: &Registry<Block>) -> NoiseGenerator {
+        let config = WorldConfig {
+            seed,
+            ..WorldConfig::default()
+        };
+        generator_with_config(&config, block_registry)
+    }
+
+    fn generator_with_config(
+        config: &WorldConfig,
+        block_registry: &Registry<Block>,
+    ) -> NoiseGenerator {
+        let ores = [OreConfig {
+            block: "default:coal_ore".to_owned(),
+            vein_size: 12,
+            attempts: 20,
+            min_height: -128,
+            max_height: 64,
+            replaceable: vec!["default:stone".to_owned()],
+        }];
+        NoiseGenerator::new(block_registry, config, &ores).unwrap()
+    }
+
+    /// FNV-1a hash of the block ids, stable across runs and platforms
+    fn chunk_hash(chunk: &Chunk) -> u64 {
+        let mut hash = 0xcbf2_9ce4_8422_2325u64;
+        for x in 0..CHUNK_SIZE {
+            for y in 0..CHUNK_SIZE {
+                for z in 0..CHUNK_SIZE {
+                    hash ^= chunk.get(x, y, z).index() as u64;
+                    hash = hash.wrapping_mul(0x0100_0000_01b3);
+                }
+            }
+        }
+        hash
+    }
+
+    /// Hashes of the chunks generated with the seed 42
+    const EXPECTED_HASHES: [u64; 4] = [
+        12260992982817805264,
+        9844846556592375512,
+        4753263599038424882,
+        8748893993937537036,
+    ];
+
+    #[test]
+    fn same_seed_same_chunks() {
+        let positions = [
+            Vector3::new(0, 0, 0),
+            Vector3::new(0, -1, 0),
+            Vector3::new(-3, 0, 5),
+            Vector3::new(12, -1, -7),
+        ];
+        let block_registry = block_registry();
+        let hashes = |seed| -> Vec<u64> {
+            let generator = generator(seed, &block_registry);
+            let mut pipeline = GenerationPipeline::new();
+            for pos in positions.iter() {
+                pipeline.request(ChunkPos(*pos));
+            }
+            pipeline.run_blocking(&generator, &block_registry);
+            let chunks: HashMap<ChunkPos, Chunk> = pipeline.take_complete().into_iter().collect();
+            positions
+                .iter()
+                .map(|pos| chunk_hash(&chunks[&ChunkPos(*pos)]))
+                .collect()
+        };
+
+        let seed_42 = hashes(42);
+        assert_eq!(seed_42, hashes(42));
+        assert_ne!(seed_42, hashes(43));
+        // Changing these values means that existing worlds change when they are extended
+        assert_eq!(seed_42, EXPECTED_HASHES);
+    }
+
+    /// Generate the chunks of `requested` in this order, after marking the `saved` ones as
+    /// complete. The tasks run in batches, completed in reverse order, as on several threads.
+    fn generate(
+        generator: &NoiseGenerator,
+        block_registry: &Registry<Block>,
+        requested: &[ChunkPos],
+        saved: &[ChunkPos],
+    ) -> HashMap<ChunkPos, Chunk> {
+        let mut pipeline = GenerationPipeline::new();
+        for pos in saved {
+            pipeline.insert_complete(*pos);
+        }
+        for pos in requested {
+            pipeline.request(*pos);
+        }
+        loop {
+            let mut tasks: Vec<_> = std::iter::from_fn(|| pipeline.next_task()).collect();
+            if tasks.is_empty() {
+                break;
+            }
+            for task in tasks.iter_mut() {
+                task.run(generator, block_registry);
+            }
+            for task in tasks.into_iter().rev() {
+                pipeline.complete(task);
+            }
+        }
+        pipeline.take_complete().into_iter().collect()
+    }
+
+    #[test]
+    fn features_do_not_depend_on_the_generation_order() {
+        let block_registry = block_registry();
+        // Large boulders in every chunk, which often cross the chunk borders
+        let config = WorldConfig {
+            seed: 7,
+            features: vec![FeatureConfig::Boulder {
+                block: "default:sand".to_owned(),
+                chance: 1.0,
+                min_radius: 3.0,
+                max_radius: 5.0,
+            }],
+            ..WorldConfig::default()
+        };
+        let generator = generator_with_config(&config, &block_registry);
+        let mut positions = Vec::new();
+        for x in 0..3 {
+            for y in -1..1 {
+                for z in 0..3 {
+                    positions.push(ChunkPos(Vector3::new(x, y, z)));
+                }
+            }
+        }
+        let hashes = |chunks: &HashMap<ChunkPos, Chunk>| -> Vec<(ChunkPos, u64)> {
+            let mut hashes: Vec<_> = chunks
+                .iter()
+                .map(|(pos, chunk)| (*pos, chunk_hash(chunk)))
+                .collect();
+            hashes.sort_by_key(|(pos, _)| (pos.0[0], pos.0[1], pos.0[2]));
+            hashes
+        };
+
+        let in_order = generate(&generator, &block_registry, &positions, &[]);
+        assert_eq!(in_order.len(), positions.len());
+        let reversed: Vec<ChunkPos> = positions.iter().rev().cloned().collect();
+        let in_reverse = generate(&generator, &block_registry, &reversed, &[]);
+        assert_eq!(hashes(&in_order), hashes(&in_reverse));
+
+        // One chunk at a time, each in a new pipeline
+        let one_by_one: HashMap<ChunkPos, Chunk> = positions
+            .iter()
+            .rev()
+            .flat_map(|pos| generate(&generator, &block_registry, &[*pos], &[]))
+            .collect();
+        assert_eq!(hashes(&in_order), hashes(&one_by_one));
+
+        // The chunks loaded from a save still have the features of the chunks around them, and
+        // theirs still reach the chunks generated next to them
+        let (saved, generated): (Vec<ChunkPos>, Vec<ChunkPos>) = positions
+            .iter()
+            .cloned()
+            .partition(|pos| (pos.0[0] + pos.0[2]) % 2 == 0);
+        let next_to_saved = generate(&generator, &block_registry, &generated, &saved);
+        let expected: HashMap<ChunkPos, Chunk> = in_order
+            .into_iter()
+            .filter(|(pos, _)| generated.contains(pos))
+            .collect();
+        assert_eq!(hashes(&expected), hashes(&next_to_saved));
+    }
+}